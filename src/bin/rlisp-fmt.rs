@@ -0,0 +1,193 @@
+extern crate rlisp;
+
+use std::io::{self, Read, Write};
+use std::env;
+use std::fs;
+use std::process;
+use rlisp::format::format_source;
+use rlisp::parser::Parser;
+use rlisp::pretty::DEFAULT_WIDTH;
+
+const USAGE: &str = "Usage: rlisp-fmt [options] [files...]
+
+Formats the files in place, or stdin to stdout when there are none.
+
+Options:
+  --check     don't write anything, show a diff and fail if a file isn't formatted
+  -w <cols>   the line width to fit in (default 80)
+  -h          show this help";
+
+// the lines of `old` and `new` as a diff, from their longest common subsequence
+fn diff(old: &str, new: &str) -> String
+{
+    let (a, b): (Vec<&str>, Vec<&str>) = (old.lines().collect(), new.lines().collect());
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev()
+    {
+        for j in (0..b.len()).rev()
+        {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len()
+    {
+        if i < a.len() && j < b.len() && a[i] == b[j]
+        {
+            i += 1;
+            j += 1;
+        }
+        else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j])
+        {
+            out.push_str(&format!("{:>5} +{}\n", j + 1, b[j]));
+            j += 1;
+        }
+        else
+        {
+            out.push_str(&format!("{:>5} -{}\n", i + 1, a[i]));
+            i += 1;
+        }
+    }
+    out
+}
+
+// prints every syntax error of a source that couldn't be formatted
+fn report_errors(name: &str, text: &str)
+{
+    for diag in Parser::new(text).parse_recover().1
+    {
+        eprintln!("rlisp-fmt: {}: {}", name, diag);
+    }
+}
+
+// formats a file in place, or with `check` only shows what would change. Returns the exit status for it: 1 if
+// `check` found it unformatted, 2 if it couldn't be read, parsed or written, leaving it as it was
+fn format_file(path: &str, width: usize, check: bool) -> i32
+{
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("rlisp-fmt: {}: {}", path, e);
+            return 2
+        },
+    };
+    match format_source(&text, width) {
+        Ok(ref out) if *out == text => 0,
+        Ok(ref out) if check => {
+            println!("{}:\n{}", path, diff(&text, out));
+            1
+        },
+        Ok(out) => match fs::write(path, out) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("rlisp-fmt: {}: {}", path, e);
+                2
+            },
+        },
+        Err(_) => {
+            report_errors(path, &text);
+            2
+        },
+    }
+}
+
+fn main()
+{
+    let mut check = false;
+    let mut width = DEFAULT_WIDTH;
+    let mut files = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next()
+    {
+        match &arg[..] {
+            "--check" => check = true,
+            "-w" => match args.next().and_then(|w| w.parse().ok()) {
+                Some(w) if w > 0 => width = w,
+                _ => { eprintln!("rlisp-fmt: option -w requires a positive number\n{}", USAGE); process::exit(2) },
+            },
+            "-h" | "--help" => { println!("{}", USAGE); return },
+            opt if opt.starts_with('-') && opt.len() > 1 => {
+                eprintln!("rlisp-fmt: unknown option {}\n{}", opt, USAGE);
+                process::exit(2)
+            },
+            _ => files.push(arg),
+        }
+    }
+
+    if files.is_empty()
+    {
+        let mut text = String::new();
+        if let Err(e) = io::stdin().read_to_string(&mut text)
+        {
+            eprintln!("rlisp-fmt: can't read stdin: {}", e);
+            process::exit(2)
+        }
+        match format_source(&text, width) {
+            Ok(ref out) if check && *out != text => { print!("{}", diff(&text, out)); process::exit(1) },
+            Ok(_) if check => {},
+            Ok(out) => { let _ = io::stdout().write_all(out.as_bytes()); },
+            Err(_) => { report_errors("<stdin>", &text); process::exit(2) },
+        }
+        return
+    }
+
+    let status = files.iter().map(|path| format_file(path, width, check)).max().unwrap_or(0);
+    process::exit(status)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str, text: &str) -> PathBuf
+    {
+        let path = env::temp_dir().join(format!("rlisp-fmt-{}-{}.lisp", process::id(), name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    // formats a file with the given source, returning the exit status and what the file holds afterwards
+    fn format_temp(name: &str, text: &str, check: bool) -> (i32, String)
+    {
+        let path = temp_file(name, text);
+        let status = format_file(path.to_str().unwrap(), DEFAULT_WIDTH, check);
+        let after = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        (status, after)
+    }
+
+    #[test]
+    fn check_fails_only_on_unformatted_files()
+    {
+        assert_eq!(format_temp("formatted", "(f x)\n", true), (0, "(f x)\n".to_string()));
+        assert_eq!(format_temp("unformatted", "(f   x)", true), (1, "(f   x)".to_string()));
+    }
+
+    #[test]
+    fn rewrites_unformatted_files()
+    {
+        assert_eq!(format_temp("rewrite", "; note\n(f   x)\n\n\n(g)", false), (0, "; note\n(f x)\n\n(g)\n".to_string()));
+    }
+
+    #[test]
+    fn leaves_files_with_errors_alone()
+    {
+        for &check in &[true, false]
+        {
+            assert_eq!(format_temp("broken", "(f   \"x)", check), (2, "(f   \"x)".to_string()));
+        }
+        assert_eq!(format_file("/nonexistent/rlisp-fmt.lisp", DEFAULT_WIDTH, false), 2);
+    }
+
+    #[test]
+    fn diffs_lines()
+    {
+        assert_eq!(diff("a\nb\nc\n", "a\nc\nd\n"), "    2 -b\n    3 +d\n");
+        assert_eq!(diff("same\n", "same\n"), "");
+    }
+}
@@ -1,16 +1,20 @@
 use std::fmt;
+use std::borrow::Cow;
 use std::rc::Rc;
+use std::cell::RefCell;
 use std::collections::VecDeque;
-use data::{Value, List, Function, RuntimeError};
+use data::{Value, List, Cons, Function, RuntimeError, Displayed};
 use data::RuntimeError::*;
 use scope::{Scope, RcScope};
 use lambda::Lambda;
+use port::{output_port, current_output, current_error};
+use record::type_label;
 
 pub struct BuiltinFn
 {
-    pub name: &'static str,
+    pub name: Cow<'static, str>,
     pub do_eval: bool,
-    pub func: Box<Fn(VecDeque<Value>, RcScope) -> Result<Value, RuntimeError>>,
+    pub func: Box<dyn Fn(VecDeque<Value>, RcScope) -> Result<Value, RuntimeError>>,
 }
 
 impl Function for BuiltinFn
@@ -19,7 +23,7 @@ impl Function for BuiltinFn
     {
         if do_ev && self.do_eval
         {
-            let ev_args = try!(args.eval(env.clone()));
+            let ev_args = args.eval(env.clone())?;
             (self.func)(ev_args, env)
         }
         else
@@ -33,7 +37,7 @@ impl PartialEq for BuiltinFn
 {
     fn eq(&self, other: &Self) -> bool
     {
-        self as *const _ == other as *const _
+        std::ptr::eq(self, other)
     }
 }
 
@@ -45,6 +49,40 @@ impl fmt::Debug for BuiltinFn
     }
 }
 
+// a builtin function that isn't bound to a fixed name
+pub fn builtin_value<N, F>(name: N, func: F) -> Value
+    where N: Into<Cow<'static, str>>, F: Fn(VecDeque<Value>, RcScope) -> Result<Value, RuntimeError> + 'static
+{
+    Value::Builtin(Rc::new(BuiltinFn{ name: name.into(), do_eval: true, func: Box::new(func) }))
+}
+
+// the largest vector `make-vector` creates
+const MAX_VECTOR_LEN: usize = 1 << 24;
+
+// converts a number into an index below `len`
+pub fn check_index(n: f64, len: usize) -> Result<usize, RuntimeError>
+{
+    if n < 0.0 || n.fract() != 0.0 { return Err(InvalidIndex(n)) }
+    if n as usize >= len { return Err(IndexOutOfRange(n as usize, len)) }
+    Ok(n as usize)
+}
+
+// converts a number into a position in `0..=len`, for the bounds of a range or an append
+pub fn check_bound(n: f64, len: usize) -> Result<usize, RuntimeError>
+{
+    if n < 0.0 || n.fract() != 0.0 { return Err(InvalidIndex(n)) }
+    if n as usize > len { return Err(IndexOutOfRange(n as usize, len)) }
+    Ok(n as usize)
+}
+
+// the bounds of a range of a sequence of length `len`
+pub fn check_range(start: f64, end: f64, len: usize) -> Result<(usize, usize), RuntimeError>
+{
+    let (start, end) = (check_bound(start, len)?, check_bound(end, len)?);
+    if start > end { return Err(InvalidRange(start, end)) }
+    Ok((start, end))
+}
+
 macro_rules! check_arg
 {
     ($dq:expr, $num:expr, $cur:expr) => (match $dq.pop_front() {
@@ -61,8 +99,9 @@ macro_rules! check_arg
 macro_rules! check_function
 {
     ($dq:expr, $num:expr, $cur:expr) => (match $dq.pop_front() {
-        Some(Value::Builtin(func)) => func as Rc<Function>,
-        Some(Value::Lambda(func)) => func as Rc<Function>,
+        Some(Value::Builtin(func)) => func as Rc<dyn Function>,
+        Some(Value::Lambda(func)) => func as Rc<dyn Function>,
+        Some(Value::Generic(func)) => func as Rc<dyn Function>,
         Some(other) => return Err(InvalidArgType("Function", other.type_name())),
         None => return Err(InvalidArgNum($num, $cur)),
     })
@@ -81,7 +120,7 @@ fn fold_result<I, T, F>(iter: I, mut acc: T, mut f: F) -> Result<T, RuntimeError
 {
     for val in iter
     {
-        acc = try!(f(acc, val))
+        acc = f(acc, val)?
     }
     Ok(acc)
 }
@@ -97,10 +136,10 @@ pub fn load_builtins(env: &mut Scope)
         where F: Fn(RcScope, &str, Value)
     {
         let key = check_arg!(args, Symbol, 2, 0);
-        let val = try!(check_arg!(args, 2, 1).eval(env.clone()));
+        let val = check_arg!(args, 2, 1).eval(env.clone())?;
         f(env, &key, val.clone());
         Ok(val)
-    };
+    }
 
     env.set_builtin("let", false, |args, env| assign_impl(args, env, |e, k, v| e.borrow_mut().decl(k, v)));
     env.set_builtin("set", false, |args, env| assign_impl(args, env, |e, k, v| e.borrow_mut().set(k, v)));
@@ -118,6 +157,7 @@ pub fn load_builtins(env: &mut Scope)
     env.set_builtin("map", true, |mut args, env| {
         let func = check_function!(args, 2, 0);
         let lst = check_arg!(args, List, 2, 1);
+        if !lst.is_proper() { return Err(ImproperList) }
         lst.iter().map(|val| func.call(&val.wrap(), env.clone(), false)).collect::<Result<_, _>>()
             .map(Value::List)
     });
@@ -126,13 +166,14 @@ pub fn load_builtins(env: &mut Scope)
         let func = check_function!(args, 3, 0);
         let init = check_arg!(args, 3, 1);
         let lst = check_arg!(args, List, 3, 2);
+        if !lst.is_proper() { return Err(ImproperList) }
         fold_result(lst.iter(), init, |acc, val| func.call(&List::cons(acc, val.wrap()), env.clone(), false))
     });
 
     env.set_builtin("car", true, |mut args, _| {
         let lst = check_arg!(args, List, 1, 0);
         Ok(match lst {
-            List::Node(cons) => cons.car.clone(),
+            List::Node(cons) => cons.car.borrow().clone(),
             _ => Value::Nil,
         })
     });
@@ -140,34 +181,126 @@ pub fn load_builtins(env: &mut Scope)
     env.set_builtin("cdr", true, |mut args, _| {
         let lst = check_arg!(args, List, 1, 0);
         Ok(match lst {
-            List::Node(cons) => Value::List(cons.cdr.clone()),
+            List::Node(cons) => cons.cdr.borrow().clone(),
             _ => Value::Nil,
         })
     });
 
     env.set_builtin("cons", true, |mut args, _| {
         let car = check_arg!(args, 2, 0);
-        let cdr = check_arg!(args, List, 2, 1);
-        Ok(Value::List(List::cons(car, cdr)))
+        let cdr = check_arg!(args, 2, 1);
+        Ok(Value::List(List::pair(car, cdr)))
     });
 
+    #[inline]
+    fn set_cell<F>(mut args: VecDeque<Value>, f: F) -> Result<Value, RuntimeError>
+        where F: Fn(&Cons) -> &RefCell<Value>
+    {
+        let lst = check_arg!(args, List, 2, 0);
+        let val = check_arg!(args, 2, 1);
+        match lst {
+            List::Node(cons) => { *f(&cons).borrow_mut() = val; Ok(Value::Nil) },
+            List::End => Err(InvalidArgType("Pair", "List")),
+        }
+    }
+
+    env.set_builtin("set-car!", true, |args, _| set_cell(args, |cons| &cons.car));
+    env.set_builtin("set-cdr!", true, |args, _| set_cell(args, |cons| &cons.cdr));
+
     env.set_builtin("list", true, |args, _| {
         Ok(Value::List(List::from_de_iter(args.into_iter())))
     });
 
+    #[inline]
+    fn new_vector(vec: Vec<Value>) -> Value
+    {
+        Value::Vector(Rc::new(RefCell::new(vec)))
+    }
+
+    env.set_builtin("make-vector", true, |mut args, _| {
+        let len = check_arg!(args, Number, 1, 0);
+        let fill = args.pop_front().unwrap_or(Value::Nil);
+        if len < 0.0 || len.fract() != 0.0 { return Err(InvalidIndex(len)) }
+        if len > MAX_VECTOR_LEN as f64 { return Err(TooLarge(len, MAX_VECTOR_LEN)) }
+        Ok(new_vector(vec![fill; len as usize]))
+    });
+
+    env.set_builtin("vector", true, |args, _| {
+        Ok(new_vector(args.into_iter().collect()))
+    });
+
+    env.set_builtin("vector-ref", true, |mut args, _| {
+        let vec = check_arg!(args, Vector, 2, 0);
+        let idx = check_arg!(args, Number, 2, 1);
+        let vec = vec.borrow();
+        check_index(idx, vec.len()).map(|i| vec[i].clone())
+    });
+
+    env.set_builtin("vector-set!", true, |mut args, _| {
+        let vec = check_arg!(args, Vector, 3, 0);
+        let idx = check_arg!(args, Number, 3, 1);
+        let val = check_arg!(args, 3, 2);
+        let mut vec = vec.borrow_mut();
+        let i = check_index(idx, vec.len())?;
+        vec[i] = val;
+        Ok(Value::Nil)
+    });
+
+    env.set_builtin("vector-length", true, |mut args, _| {
+        let vec = check_arg!(args, Vector, 1, 0);
+        let len = vec.borrow().len();
+        Ok(Value::Number(len as f64))
+    });
+
+    env.set_builtin("vector->list", true, |mut args, _| {
+        let vec = check_arg!(args, Vector, 1, 0);
+        let lst = List::from_de_iter(vec.borrow().iter().cloned());
+        Ok(Value::List(lst))
+    });
+
+    env.set_builtin("list->vector", true, |mut args, _| {
+        let lst = check_arg!(args, List, 1, 0);
+        if !lst.is_proper() { return Err(ImproperList) }
+        Ok(new_vector(lst.iter().collect()))
+    });
+
+    env.set_builtin("vector-map", true, |mut args, env| {
+        let func = check_function!(args, 2, 0);
+        let vec = check_arg!(args, Vector, 2, 1);
+        let elems = vec.borrow().clone();   // the function may modify the vector
+        elems.into_iter().map(|val| func.call(&val.wrap(), env.clone(), false)).collect::<Result<_, _>>()
+            .map(new_vector)
+    });
+
+    env.set_builtin("vector-fill!", true, |mut args, _| {
+        let vec = check_arg!(args, Vector, 2, 0);
+        let val = check_arg!(args, 2, 1);
+        for elem in vec.borrow_mut().iter_mut()
+        {
+            *elem = val.clone();
+        }
+        Ok(Value::Nil)
+    });
+
+    env.set_builtin("subvector", true, |mut args, _| {
+        let vec = check_arg!(args, Vector, 3, 0);
+        let start = check_arg!(args, Number, 3, 1);
+        let end = check_arg!(args, Number, 3, 2);
+        let vec = vec.borrow();
+        let (start, end) = check_range(start, end, vec.len())?;
+        Ok(new_vector(vec[start..end].to_vec()))
+    });
+
     env.set_builtin("not", true, |mut args, _| {
         let val = check_arg!(args, 1, 0);
-        Ok(Value::Bool(match val {
-            Value::Nil | Value::Bool(false) => true,
-            _ => false,
-        }))
+        Ok(Value::Bool(matches!(val, Value::Nil | Value::Bool(false))))
     });
 
     env.set_builtin("and", false, |args, env| {
         let mut last = Value::Bool(true);
         for val in args
         {
-            match try!(val.eval(env.clone())) {
+            match val.eval(env.clone())? {
                 v @ Value::Nil | v @ Value::Bool(false) => return Ok(v),
                 other => last = other,
             }
@@ -179,7 +312,7 @@ pub fn load_builtins(env: &mut Scope)
         let mut last = Value::Bool(false);
         for val in args
         {
-            match try!(val.eval(env.clone())) {
+            match val.eval(env.clone())? {
                 v @ Value::Nil | v @ Value::Bool(false) => last = v,
                 other => return Ok(other),
             }
@@ -190,7 +323,7 @@ pub fn load_builtins(env: &mut Scope)
     env.set_builtin("if", false, |mut args, env| {
         let cond = check_arg!(args, 2, 0);
         let then = check_arg!(args, 2, 1);
-        match try!(cond.eval(env.clone())) {
+        match cond.eval(env.clone())? {
             Value::Nil | Value::Bool(false) => args.pop_front().unwrap_or(Value::Nil).eval(env),
             _ => then.eval(env),
         }
@@ -201,7 +334,7 @@ pub fn load_builtins(env: &mut Scope)
         let mut last = Value::Nil;
         for val in args
         {
-            last = try!(val.eval(local.clone()));
+            last = val.eval(local.clone())?;
         }
         Ok(last)
     });
@@ -213,6 +346,7 @@ pub fn load_builtins(env: &mut Scope)
 
     env.set_builtin("lambda", false, |mut args, env| {
         let arg_lst = check_arg!(args, List, 1, 0);
+        if !arg_lst.is_proper() { return Err(ImproperList) }
         arg_lst.iter().map(|val| map_value!(val, Symbol, |n: Rc<String>| (*n).clone()))
             .collect::<Result<_, _>>()
             .map(|names| Value::Lambda(Rc::new(Lambda::new(names, args, env))))
@@ -245,12 +379,6 @@ pub fn load_builtins(env: &mut Scope)
     env.set_builtin("-", true, |args, _| numeric_op(args, 0.0, |lhs| move |rhs| lhs - rhs));
     env.set_builtin("/", true, |args, _| numeric_op(args, 1.0, |lhs| move |rhs| lhs / rhs));
 
-    env.set_builtin("equal", true, |mut args, _| {
-        let va = check_arg!(args, 2, 0);
-        let vb = check_arg!(args, 2, 1);
-        Ok(Value::Bool(va == vb))
-    });
-
     #[inline]
     fn comp_op<F, G>(mut args: VecDeque<Value>, op_num: F, op_str: G) -> Result<Value, RuntimeError>
         where F: Fn(f64, f64) -> bool, G: Fn(&str, &str) -> bool
@@ -259,6 +387,7 @@ pub fn load_builtins(env: &mut Scope)
         let vb = check_arg!(args, 2, 1);
         match (va, vb) {
             (Value::Number(a), Value::Number(b)) => Ok(op_num(a, b)),
+            (Value::Char(a), Value::Char(b)) => Ok(op_num(a as u32 as f64, b as u32 as f64)),
             (Value::String(ref a), Value::String(ref b)) => Ok(op_str(a, b)),
             (a, b) => Err(InvalidComp(a.type_name(), b.type_name())),
         }.map(Value::Bool)
@@ -271,26 +400,133 @@ pub fn load_builtins(env: &mut Scope)
 
     env.set_builtin("atom", true, |mut args, _| {
         let val = check_arg!(args, 1, 0);
-        Ok(Value::Bool(match val {
-            Value::List(List::Node(_)) => false,
-            _ => true,
-        }))
+        Ok(Value::Bool(!matches!(val, Value::List(List::Node(_)))))
     });
 
     env.set_builtin("typeof", true, |mut args, _| {
         let val = check_arg!(args, 1, 0);
-        Ok(Value::String(Rc::new(val.type_name().to_string())))
+        Ok(Value::String(Rc::new(type_label(&val))))
     });
 
-    env.set_builtin("display", true, |mut args, _| {
+    // `display` and `print` are for people, `write` output can be read back.
+    // They write to the port given after the value, or the current output port
+    env.set_builtin("display", true, |mut args, env| {
         let val = check_arg!(args, 1, 0);
-        println!("{}", val);
+        output_port(&mut args, &env)?.write_str(&Displayed(&val).to_string())?;
         Ok(val)
     });
 
-    env.set_builtin("debug", true, |mut args, _| {
+    env.set_builtin("write", true, |mut args, env| {
         let val = check_arg!(args, 1, 0);
-        println!("{:?}", val);
+        output_port(&mut args, &env)?.write_str(&val.to_string())?;
         Ok(val)
     });
+
+    env.set_builtin("write-string", true, |mut args, env| {
+        let s = check_arg!(args, String, 1, 0);
+        output_port(&mut args, &env)?.write_str(&s)?;
+        Ok(Value::String(s))
+    });
+
+    env.set_builtin("newline", true, |mut args, env| {
+        output_port(&mut args, &env)?.write_str("\n")?;
+        Ok(Value::Nil)
+    });
+
+    // displays the arguments separated by spaces, then a newline
+    env.set_builtin("print", true, |args, env| {
+        let line: Vec<_> = args.iter().map(|val| Displayed(val).to_string()).collect();
+        current_output(&env).write_str(&(line.join(" ") + "\n"))?;
+        Ok(Value::Nil)
+    });
+
+    // writes the internal form of a value to the error port
+    env.set_builtin("debug", true, |mut args, env| {
+        let val = check_arg!(args, 1, 0);
+        current_error(&env).write_str(&format!("{:?}\n", val))?;
+        Ok(val)
+    });
+
+    // closes the open ports and stops the evaluation with an Exit error, it's up to the embedder to end the
+    // process with its code
+    env.set_builtin("exit", true, |mut args, env| {
+        let code = match args.pop_front() {
+            None | Some(Value::Bool(true)) => 0,
+            Some(Value::Bool(false)) => 1,
+            Some(Value::Number(n)) => n as i32,
+            Some(other) => return Err(InvalidArgType("Number", other.type_name())),
+        };
+        env.borrow().ports().close_all()?;
+        Err(Exit(code))
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{env, fs, process};
+    use scope::{run, run_in};
+    use vfs::RootedFs;
+
+    #[test]
+    fn limits_vector_size()
+    {
+        assert!(matches!(run("(make-vector 1e18)"), Err(TooLarge(..))));
+        assert!(matches!(run("(make-vector -1)"), Err(InvalidIndex(_))));
+        assert_eq!(run("(vector-length (make-vector 3 0))").unwrap(), Value::Number(3.0));
+    }
+
+    #[test]
+    fn checks_subvector_ranges()
+    {
+        assert_eq!(run("(subvector #(1 2 3) 1 3)").unwrap(), run("#(2 3)").unwrap());
+        assert_eq!(run("(subvector #(1 2 3) 3 3)").unwrap(), run("#()").unwrap());
+        assert!(matches!(run("(subvector #(1 2 3) 2 1)"), Err(InvalidRange(2, 1))));
+        assert!(matches!(run("(subvector #(1 2 3) 0 4)"), Err(IndexOutOfRange(4, 3))));
+        assert!(matches!(run("(vector-ref #(1 2 3) 3)"), Err(IndexOutOfRange(3, 3))));
+    }
+
+    #[test]
+    fn exit_flushes_ports_and_returns()
+    {
+        let root = env::temp_dir().join(format!("rlisp-exit-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("b.lisp"), "(exit 4)").unwrap();
+        let env = Scope::global().wrap();
+        env.borrow_mut().load_stdlib();
+        env.borrow_mut().set_vfs(Rc::new(RootedFs::new(&root)));
+        let eval = |code: &str| run_in(code, &env);
+
+        eval("(let p (open-output-file \"out.txt\"))").unwrap();
+        eval("(write-string \"kept\" p)").unwrap();
+        // the port is still open and buffered, the exit writes it out
+        assert!(matches!(eval("(exit 3)"), Err(Exit(3))));
+        assert_eq!(fs::read_to_string(root.join("out.txt")).unwrap(), "kept");
+        assert!(matches!(eval("(write-string \"lost\" p)"), Err(PortClosed(_))));
+        // an exit in a loaded file isn't reported as an error of that file
+        assert!(matches!(eval("(load \"b.lisp\")"), Err(Exit(4))));
+        assert!(matches!(eval("(exit #f)"), Err(Exit(1))));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn takes_improper_lists_apart()
+    {
+        assert_eq!(run("(car '(1 . 2))").unwrap(), Value::Number(1.0));
+        assert_eq!(run("(cdr '(1 . 2))").unwrap(), Value::Number(2.0));
+        assert_eq!(run("(cdr (cdr '(1 2 . 3)))").unwrap(), Value::Number(3.0));
+        assert_eq!(run("(cons 1 2)").unwrap().to_string(), "(1 . 2)");
+        assert_eq!(run("(cons 1 '(2))").unwrap().to_string(), "(1 2)");
+    }
+
+    #[test]
+    fn rejects_improper_lists()
+    {
+        for code in &["(map car '((1) . 2))", "(fold + 0 '(1 . 2))", "(+ 1 . 2)", "(apply + '(1 . 2))",
+                      "(let l (list 1 2)) (set-cdr! (cdr l) l) (map car l)"]
+        {
+            assert!(matches!(run(code), Err(ImproperList)), "{}", code);
+        }
+    }
 }
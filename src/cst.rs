@@ -0,0 +1,312 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use data::{Token, Value, List, ParseError};
+use lexer::{Tokenizer, Span};
+use parser::{atom_value, hashtable_value, pmap_value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriviaKind
+{
+    Whitespace,
+    Newline,
+    Comment,
+}
+
+// text between tokens that doesn't change the meaning of the source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trivia
+{
+    pub kind: TriviaKind,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct CstToken
+{
+    pub token: Token,
+    pub span: Span,
+    pub leading: Vec<Trivia>,   // the trivia between the previous token and this one
+}
+
+#[derive(Debug)]
+pub enum CstNode
+{
+    Atom(CstToken),                         // a number, char, symbol or string, or the dot of a pair
+    Quote(CstToken, Box<CstNode>),
+    Seq(CstToken, Vec<CstNode>, CstToken),  // the opening token, the elements and the closing token
+}
+
+impl CstNode
+{
+    pub fn first_token(&self) -> &CstToken
+    {
+        match *self {
+            CstNode::Atom(ref tok) | CstNode::Quote(ref tok, _) | CstNode::Seq(ref tok, _, _) => tok,
+        }
+    }
+
+    // the text of the node, without the trivia in front of it
+    pub fn span(&self) -> Span
+    {
+        let end = match *self {
+            CstNode::Atom(ref tok) => tok.span.end,
+            CstNode::Quote(_, ref node) => node.span().end,
+            CstNode::Seq(_, _, ref close) => close.span.end,
+        };
+        Span{ start: self.first_token().span.start, end }
+    }
+
+    // calls `f` on the tokens of the node in source order
+    pub fn for_each_token<F: FnMut(&CstToken)>(&self, f: &mut F)
+    {
+        match *self {
+            CstNode::Atom(ref tok) => f(tok),
+            CstNode::Quote(ref tok, ref node) => { f(tok); node.for_each_token(f) },
+            CstNode::Seq(ref open, ref elems, ref close) => {
+                f(open);
+                for elem in elems
+                {
+                    elem.for_each_token(f);
+                }
+                f(close);
+            },
+        }
+    }
+
+    // the AST of the node, as `Parser` would read it
+    pub fn to_value(&self) -> Result<Value, ParseError>
+    {
+        match *self {
+            CstNode::Atom(CstToken{ token: Token::Dot, .. }) => Err(ParseError::UnexpectedDot),
+            CstNode::Atom(ref tok) => Ok(atom_value(clone_atom(&tok.token))),
+            CstNode::Quote(_, ref node) => node.to_value().map(|val| val.quote()),
+            CstNode::Seq(ref open, ref elems, _) => match open.token {
+                Token::Lparen => list_value(elems),
+                Token::VectorStart => elem_values(elems).map(|vals| Value::Vector(Rc::new(RefCell::new(vals)))),
+                Token::HashTableStart(kind) => elem_values(elems).and_then(|vals| hashtable_value(kind, vals)),
+                Token::Lbracket => elem_values(elems).map(|vals| Value::PVector(vals.into_iter().collect())),
+                Token::Lbrace => elem_values(elems).and_then(pmap_value),
+                _ => unreachable!(),
+            },
+        }
+    }
+}
+
+fn clone_atom(tok: &Token) -> Token
+{
+    match *tok {
+        Token::Number(n) => Token::Number(n),
+        Token::Char(c) => Token::Char(c),
+        Token::Ident(ref s) => Token::Ident(s.clone()),
+        Token::String(ref s) => Token::String(s.clone()),
+        _ => unreachable!(),
+    }
+}
+
+fn elem_values(elems: &[CstNode]) -> Result<Vec<Value>, ParseError>
+{
+    elems.iter().map(CstNode::to_value).collect()
+}
+
+fn is_dot(node: &CstNode) -> bool
+{
+    matches!(*node, CstNode::Atom(CstToken{ token: Token::Dot, .. }))
+}
+
+// a list, where a dot before the last element makes it the tail
+fn list_value(elems: &[CstNode]) -> Result<Value, ParseError>
+{
+    let (elems, tail) = match elems.iter().position(is_dot) {
+        Some(i) if i > 0 && i + 2 == elems.len() => (&elems[..i], elems[i + 1].to_value()?),
+        Some(_) => return Err(ParseError::UnexpectedDot),
+        None => (elems, Value::List(List::End)),
+    };
+    let vals = elem_values(elems)?;
+    Ok(vals.into_iter().rev().fold(tail, |cdr, car| Value::List(List::pair(car, cdr))))
+}
+
+// a lossless syntax tree, that keeps every char of the source in its tokens and trivia
+#[derive(Debug)]
+pub struct Cst
+{
+    pub source: String,
+    pub nodes: Vec<CstNode>,
+    pub end: CstToken,  // holds the trivia after the last node
+}
+
+struct Builder<'a>
+{
+    tokens: Tokenizer<'a>,
+    last_end: usize,
+    cur: CstToken,
+}
+
+impl<'a> Builder<'a>
+{
+    // reads the next token, with the trivia before it
+    fn read(&mut self) -> CstToken
+    {
+        let mut leading = Vec::new();
+        loop
+        {
+            let (token, span) = self.tokens.next_spanned();
+            if span.start > self.last_end
+            {
+                leading.push(Trivia{ kind: TriviaKind::Whitespace, span: Span{ start: self.last_end, end: span.start } });
+            }
+            self.last_end = span.end;
+            let kind = match token {
+                Token::Newline => TriviaKind::Newline,
+                Token::Comment(_) => TriviaKind::Comment,
+                token => return CstToken{ token, span, leading },
+            };
+            leading.push(Trivia{ kind, span });
+        }
+    }
+
+    fn advance(&mut self) -> CstToken
+    {
+        let next = self.read();
+        ::std::mem::replace(&mut self.cur, next)
+    }
+
+    fn node(&mut self) -> Result<CstNode, ParseError>
+    {
+        let close = match self.cur.token {
+            Token::Lparen | Token::VectorStart | Token::HashTableStart(_) => Token::Rparen,
+            Token::Lbracket => Token::Rbracket,
+            Token::Lbrace => Token::Rbrace,
+            Token::Rparen => return Err(ParseError::UnexpectedRparen),
+            Token::Rbracket => return Err(ParseError::UnexpectedDelim(']')),
+            Token::Rbrace => return Err(ParseError::UnexpectedDelim('}')),
+            Token::Quote => {
+                let quote = self.advance();
+                return match self.node() {
+                    Ok(node) => Ok(CstNode::Quote(quote, Box::new(node))),
+                    Err(ParseError::EndOfStream) => Err(ParseError::NoQuoteArg),
+                    Err(e) => Err(e),
+                }
+            },
+            Token::End => return Err(ParseError::EndOfStream),
+            _ => {
+                let tok = self.advance();
+                return match tok.token {
+                    Token::Error(e) => Err(e),
+                    _ => Ok(CstNode::Atom(tok)),
+                }
+            },
+        };
+
+        let open = self.advance();
+        let mut elems = Vec::new();
+        while self.cur.token != close
+        {
+            match self.node() {
+                Err(ParseError::EndOfStream) => return Err(ParseError::UnclosedList),
+                other => elems.push(other?),
+            }
+        }
+        Ok(CstNode::Seq(open, elems, self.advance()))
+    }
+}
+
+impl Cst
+{
+    pub fn parse(text: &str) -> Result<Cst, ParseError>
+    {
+        let start = CstToken{ token: Token::End, span: Span{ start: 0, end: 0 }, leading: Vec::new() };
+        let mut builder = Builder{ tokens: Tokenizer::with_trivia(text), last_end: 0, cur: start };
+        builder.advance();  // replace the placeholder with the first token
+        let mut nodes = Vec::new();
+        while builder.cur.token != Token::End
+        {
+            nodes.push(builder.node()?);
+        }
+        Ok(Cst{ source: text.to_string(), nodes, end: builder.cur })
+    }
+
+    pub fn text(&self, span: Span) -> &str
+    {
+        &self.source[span.start..span.end]
+    }
+
+    // the source rebuilt from the tokens and trivia, which is the same as the parsed text
+    pub fn to_source(&self) -> String
+    {
+        let mut out = String::with_capacity(self.source.len());
+        {
+            let mut push = |tok: &CstToken| {
+                for trivia in &tok.leading
+                {
+                    out.push_str(self.text(trivia.span));
+                }
+                out.push_str(self.text(tok.span));
+            };
+            for node in &self.nodes
+            {
+                node.for_each_token(&mut push);
+            }
+            push(&self.end);
+        }
+        out
+    }
+
+    pub fn to_values(&self) -> Result<Vec<Value>, ParseError>
+    {
+        self.nodes.iter().map(CstNode::to_value).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use parser::Parser;
+
+    const SOURCES: &[&str] = &[
+        "",
+        "   \n\n\t ",
+        "; only a comment",
+        "; a comment and no newline at the end\n(a b)   ; trailing",
+        "\n\n(define x 1)\n\n\n; between\n\n(define y 2)\n",
+        "(a\tb \t c)\r\n(d  .   e)  \r\n",
+        "  ( a\n\n    (b   c ) ;; inner\n  )\t\n",
+        "1 -2 3.5 -0.25 1e10 2.5E-3 +inf.0 -inf.0 +nan.0",
+        "#\\a #\\space #\\newline #\\x41 #\\λ #\\( #\\) #\\; #\\\"",
+        "\"plain\" \"esc \\\" \\\\ \\n \\t \\x41; \\u{3bb}\" \"line \\\n    continued\" #r\"raw \\ string\"",
+        "sym with-dash set! ->arrow ... λ nil #t #f #true #false",
+        "'a '(1 2) ' ( 3 ) '#(4)",
+        "#(1 #(2) ()) [1 [2] 3] {a 1 b {c 2}} #hash((a . 1) (b . 2)) #hasheq((1 . 2))",
+        "(lambda (x) ; comment after a token\n  (if x\n      ; comment before a token\n      x\n      nil))",
+        "( )[ ]{ }#( )",
+    ];
+
+    #[test]
+    fn rebuilds_the_source()
+    {
+        for src in SOURCES
+        {
+            let cst = Cst::parse(src).unwrap();
+            assert_eq!(cst.to_source(), *src);
+            // hash tables are only equal to themselves, so the values are compared by how they print
+            let vals = format!("{:?}", cst.to_values().unwrap());
+            assert_eq!(vals, format!("{:?}", Parser::new(src).parse().unwrap()));
+        }
+    }
+
+    #[test]
+    fn rebuilds_joined_sources()
+    {
+        // every pair of sources joined by different separators, so the trivia between them is checked too.
+        // the separators end lines, or a comment at the end of the first source would swallow the second
+        for (i, a) in SOURCES.iter().enumerate()
+        {
+            for (j, b) in SOURCES.iter().enumerate()
+            {
+                let sep = ["\n", "  \n ", "\t\n  \n", " ;; x\n", "\r\n"][(i + j) % 5];
+                let src = format!("{}{}{}", a, sep, b);
+                assert_eq!(Cst::parse(&src).unwrap().to_source(), src);
+            }
+        }
+    }
+}
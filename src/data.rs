@@ -1,35 +1,66 @@
 use std::fmt;
 use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use builtins::BuiltinFn;
 use lambda::Lambda;
+use module::Module;
+use hashtable::{HashTable, HashKind};
+use persistent::{PVector, PMap};
+use port::Port;
+use record::{Record, RecordType};
+use object::Generic;
+use equality::is_equal;
 use scope::RcScope;
 
 #[derive(Debug, PartialEq)]
 pub enum Token
 {
     Lparen,
+    Lbracket,
+    Lbrace,
+    VectorStart,
+    HashTableStart(HashKind),
     Rparen,
+    Rbracket,
+    Rbrace,
     Quote,
+    Dot,
     Number(f64),
+    Char(char),
     Ident(String),
     String(String),
     Error(ParseError),
+    Comment(String),    // only returned with trivia
+    Newline,            // only returned with trivia
     End,    // end of string
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone)]
 pub enum Value
 {
     Nil,
     Bool(bool),
     Number(f64),
+    Char(char),
     Symbol(Rc<String>),
     String(Rc<String>),
     Builtin(Rc<BuiltinFn>),
     Lambda(Rc<Lambda>),
+    Generic(Rc<Generic>),
+    Module(Rc<Module>),
     List(List),
+    Vector(Rc<RefCell<Vec<Value>>>),
+    HashTable(Rc<RefCell<HashTable>>),
+    PVector(PVector),
+    PMap(PMap),
+    Port(Rc<Port>),
+    Eof,
+    Record(Rc<Record>),
+    RecordType(Rc<RecordType>),
 }
 
+// the `write` form of a value, that reads back as an equal value
 impl fmt::Display for Value
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
@@ -37,22 +68,121 @@ impl fmt::Display for Value
         match *self {
             Value::Nil => write!(f, "nil"),
             Value::Bool(val) => write!(f, "{}", if val { "#t" } else { "#f" }),
+            Value::Number(val) if val.is_nan() => write!(f, "+nan.0"),
+            Value::Number(val) if val.is_infinite() => write!(f, "{}", if val > 0.0 { "+inf.0" } else { "-inf.0" }),
             Value::Number(ref val) => write!(f, "{}", val),
+            Value::Char(val) => match char_name(val) {
+                Some(name) => write!(f, "#\\{}", name),
+                None if val.is_control() => write!(f, "#\\x{:x}", val as u32),
+                None => write!(f, "#\\{}", val),
+            },
             Value::Symbol(ref val) => write!(f, "{}", val),
-            Value::String(ref val) => write!(f, "\"{}\"", val),
+            Value::String(ref val) => write_string(f, val),
             Value::Builtin(ref val) => write!(f, "#<builtin:{}>", val.name),
             Value::Lambda(_) => write!(f, "#<lambda>"),
+            Value::Generic(ref val) => write!(f, "#<generic:{}>", val.name),
+            Value::Module(ref val) => write!(f, "#<module:{}>", val.name),
+            Value::Port(ref val) => write!(f, "#<{}-port:{}>", val.kind(), val.name),
+            Value::Eof => write!(f, "#<eof>"),
+            Value::RecordType(ref val) => write!(f, "#<record-type:{}>", val.name),
             Value::List(ref val) => write!(f, "{}", val),
+            Value::Vector(_) | Value::HashTable(_) | Value::PVector(_) | Value::PMap(_) | Value::Record(_) =>
+                Labels::find(self, false).write_value(f, self),
         }
     }
 }
 
+// the `display` form of a value, with strings and chars written as their raw text
+pub struct Displayed<'a>(pub &'a Value);
+
+impl<'a> fmt::Display for Displayed<'a>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self.0 {
+            Value::String(ref val) => write!(f, "{}", val),
+            Value::Char(val) => write!(f, "{}", val),
+            Value::List(List::Node(ref cons)) => {
+                let mut labels = Labels::new(true);
+                labels.visit_cons(cons, &mut HashSet::new(), &mut HashSet::new());
+                labels.write_cons(f, cons)
+            },
+            Value::Vector(_) | Value::HashTable(_) | Value::PVector(_) | Value::PMap(_) | Value::Record(_) =>
+                Labels::find(self.0, true).write_value(f, self.0),
+            ref other => write!(f, "{}", other),
+        }
+    }
+}
+
+// compares like `equal?`, which terminates on cyclic values
+impl PartialEq for Value
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        is_equal(self, other)
+    }
+}
+
+// the atoms show their Rust form, the other values their `write` form, which labels cycles
+impl fmt::Debug for Value
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            Value::Nil => write!(f, "Nil"),
+            Value::Eof => write!(f, "Eof"),
+            Value::Bool(val) => write!(f, "Bool({:?})", val),
+            Value::Number(val) => write!(f, "Number({:?})", val),
+            Value::Char(val) => write!(f, "Char({:?})", val),
+            Value::Symbol(ref val) => write!(f, "Symbol({:?})", val),
+            Value::String(ref val) => write!(f, "String({:?})", val),
+            ref other => write!(f, "{}({})", other.type_name(), other),
+        }
+    }
+}
+
+// writes a string literal that reads back as the same string
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result
+{
+    write!(f, "\"")?;
+    for chr in s.chars()
+    {
+        match chr {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            '\0' => write!(f, "\\0")?,
+            c if c.is_control() => write!(f, "\\x{:x};", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+// the names of the characters that have one in `#\name` literals
+const CHAR_NAMES: &[(&str, char)] = &[
+    ("space", ' '), ("newline", '\n'), ("tab", '\t'), ("return", '\r'), ("nul", '\0'),
+    ("alarm", '\x07'), ("backspace", '\x08'), ("escape", '\x1b'), ("delete", '\x7f'),
+];
+
+pub fn char_name(chr: char) -> Option<&'static str>
+{
+    CHAR_NAMES.iter().find(|&&(_, c)| c == chr).map(|&(name, _)| name)
+}
+
+pub fn char_from_name(name: &str) -> Option<char>
+{
+    CHAR_NAMES.iter().find(|&&(n, _)| n == name).map(|&(_, c)| c)
+}
+
 pub trait Function
 {
     fn call(&self, args: &List, env: RcScope, do_ev: bool) -> Result<Value, RuntimeError>;
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone)]
 pub enum List
 {
     Node(Rc<Cons>),
@@ -64,30 +194,267 @@ impl fmt::Display for List
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
     {
         match *self {
-            List::Node(ref val) => write!(f, "({})", val),
+            List::Node(ref val) => write!(f, "{}", val),
             List::End => write!(f, "()"),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct Cons
+impl PartialEq for List
 {
-    pub car: Value,
-    pub cdr: List,
+    fn eq(&self, other: &Self) -> bool
+    {
+        match (self, other) {
+            (List::End, List::End) => true,
+            (List::Node(x), List::Node(y)) => is_equal(&Value::List(List::Node(x.clone())), &Value::List(List::Node(y.clone()))),
+            _ => false,
+        }
+    }
 }
 
-impl fmt::Display for Cons
+impl fmt::Debug for List
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
     {
         match *self {
-            Cons{ ref car, cdr: List::Node(ref next) } => write!(f, "{} {}", car, next),
-            Cons{ ref car, cdr: List::End } => write!(f, "{}", car),
+            List::Node(ref cons) => write!(f, "Node({})", cons),
+            List::End => write!(f, "End"),
         }
     }
 }
 
+#[derive(Clone)]
+pub struct Cons
+{
+    pub car: RefCell<Value>,
+    pub cdr: RefCell<Value>,
+}
+
+// the identity of a vector, hash table, persistent collection or record, and the values it holds, keys and
+// values in turn for the tables
+fn children(val: &Value) -> Option<(*const (), Vec<Value>)>
+{
+    let pairs = |entries: Vec<(&Value, &Value)>| entries.into_iter().flat_map(|(k, v)| vec![k.clone(), v.clone()]).collect();
+    Some(match *val {
+        Value::Vector(ref vec) => (Rc::as_ptr(vec) as *const (), vec.borrow().clone()),
+        Value::HashTable(ref table) => {
+            (Rc::as_ptr(table) as *const (), pairs(table.borrow().entries().iter().map(|(k, v)| (k, v)).collect()))
+        },
+        Value::PVector(ref vec) => (vec.root_ptr(), vec.iter().cloned().collect()),
+        Value::PMap(ref map) => (map.root_ptr(), pairs(map.entries())),
+        Value::Record(ref rec) => (Rc::as_ptr(rec) as *const (), rec.fields.borrow().clone()),
+        _ => return None,
+    })
+}
+
+// the cons cells and vectors that are part of a cycle, with the label number given when first printed
+struct Labels
+{
+    cells: HashMap<*const (), Option<usize>>,
+    next: usize,
+    display: bool,  // write the elements in `display` form
+}
+
+type Visited = HashSet<*const ()>;
+
+impl Labels
+{
+    fn new(display: bool) -> Labels
+    {
+        Labels{ cells: HashMap::new(), next: 0, display }
+    }
+
+    fn find(root: &Value, display: bool) -> Labels
+    {
+        let mut labels = Labels::new(display);
+        labels.visit(root, &mut HashSet::new(), &mut HashSet::new());
+        labels
+    }
+
+    fn visit(&mut self, val: &Value, path: &mut Visited, done: &mut Visited)
+    {
+        if let Value::List(List::Node(ref cons)) = *val
+        {
+            return self.visit_cons(cons, path, done)
+        }
+        let (ptr, elems) = match children(val) {
+            Some(children) => children,
+            None => return,
+        };
+        if path.contains(&ptr)
+        {
+            self.cells.insert(ptr, None);
+            return
+        }
+        if !done.insert(ptr) { return }
+        path.insert(ptr);
+        for elem in &elems
+        {
+            self.visit(elem, path, done);
+        }
+        path.remove(&ptr);
+    }
+
+    // walks the cdr chain iteratively, and recurses on the cars
+    fn visit_cons(&mut self, root: &Cons, path: &mut Visited, done: &mut Visited)
+    {
+        let mut chain = Vec::new();
+        let mut holder: Rc<Cons>;
+        let mut cell = root;
+        loop
+        {
+            let ptr = cell as *const Cons as *const ();
+            if path.contains(&ptr)
+            {
+                self.cells.insert(ptr, None);
+                break
+            }
+            if done.contains(&ptr) { break }
+            path.insert(ptr);
+            chain.push(ptr);
+
+            self.visit(&cell.car.borrow(), path, done);
+            let next = match *cell.cdr.borrow() {
+                Value::List(List::Node(ref next)) => next.clone(),
+                ref other => { self.visit(other, path, done); break },
+            };
+            holder = next;
+            cell = &holder;
+        }
+        for ptr in chain
+        {
+            path.remove(&ptr);
+            done.insert(ptr);
+        }
+    }
+
+    // writes the `#n=` prefix the first time a labeled object is seen. Returns false if it was already printed
+    fn write_label(&mut self, f: &mut fmt::Formatter, ptr: *const ()) -> Result<bool, fmt::Error>
+    {
+        match self.cells.get_mut(&ptr) {
+            Some(&mut Some(n)) => { write!(f, "#{}#", n)?; Ok(false) },
+            Some(slot) => {
+                *slot = Some(self.next);
+                write!(f, "#{}=", self.next)?;
+                self.next += 1;
+                Ok(true)
+            },
+            None => Ok(true),
+        }
+    }
+
+    fn write_value(&mut self, f: &mut fmt::Formatter, val: &Value) -> fmt::Result
+    {
+        match *val {
+            Value::List(List::Node(ref cons)) => self.write_cons(f, cons),
+            Value::Vector(ref vec) => {
+                if !self.write_label(f, Rc::as_ptr(vec) as *const ())? { return Ok(()) }
+                write!(f, "#(")?;
+                for (i, elem) in vec.borrow().iter().enumerate()
+                {
+                    if i > 0 { write!(f, " ")? }
+                    self.write_value(f, elem)?;
+                }
+                write!(f, ")")
+            },
+            Value::HashTable(ref table) => {
+                if !self.write_label(f, Rc::as_ptr(table) as *const ())? { return Ok(()) }
+                let table = table.borrow();
+                write!(f, "{}(", if table.kind() == HashKind::Eq { "#hasheq" } else { "#hash" })?;
+                for (i, (key, val)) in table.entries().iter().enumerate()
+                {
+                    if i > 0 { write!(f, " ")? }
+                    if let List::Node(ref cons) = List::pair(key.clone(), val.clone())
+                    {
+                        self.write_cons(f, cons)?;
+                    }
+                }
+                write!(f, ")")
+            },
+            Value::PVector(ref vec) => {
+                if !self.write_label(f, vec.root_ptr())? { return Ok(()) }
+                write!(f, "[")?;
+                for (i, elem) in vec.iter().enumerate()
+                {
+                    if i > 0 { write!(f, " ")? }
+                    self.write_value(f, elem)?;
+                }
+                write!(f, "]")
+            },
+            Value::PMap(ref map) => {
+                if !self.write_label(f, map.root_ptr())? { return Ok(()) }
+                write!(f, "{{")?;
+                for (i, (key, val)) in map.entries().into_iter().enumerate()
+                {
+                    if i > 0 { write!(f, " ")? }
+                    self.write_value(f, key)?;
+                    write!(f, " ")?;
+                    self.write_value(f, val)?;
+                }
+                write!(f, "}}")
+            },
+            Value::Record(ref rec) => {
+                if !self.write_label(f, Rc::as_ptr(rec) as *const ())? { return Ok(()) }
+                write!(f, "#<{}", rec.rtype.name)?;
+                for (name, val) in rec.rtype.fields.iter().zip(rec.fields.borrow().iter())
+                {
+                    write!(f, " {}: ", name)?;
+                    self.write_value(f, val)?;
+                }
+                write!(f, ">")
+            },
+            ref other if self.display => write!(f, "{}", Displayed(other)),
+            ref other => write!(f, "{}", other),
+        }
+    }
+
+    fn write_cons(&mut self, f: &mut fmt::Formatter, cons: &Cons) -> fmt::Result
+    {
+        if !self.write_label(f, cons as *const Cons as *const ())? { return Ok(()) }
+
+        write!(f, "(")?;
+        let mut holder: Rc<Cons>;
+        let mut cell = cons;
+        loop
+        {
+            self.write_value(f, &cell.car.borrow())?;
+            let next = match *cell.cdr.borrow() {
+                Value::List(List::End) => None,
+                Value::List(List::Node(ref next)) if !self.cells.contains_key(&(Rc::as_ptr(next) as *const ())) =>
+                    Some(next.clone()),
+                ref other => {
+                    write!(f, " . ")?;
+                    self.write_value(f, other)?;
+                    None
+                },
+            };
+            match next {
+                Some(next) => { write!(f, " ")?; holder = next; cell = &holder; },
+                None => break,
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+impl fmt::Display for Cons
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let mut labels = Labels::new(false);
+        labels.visit_cons(self, &mut HashSet::new(), &mut HashSet::new());
+        labels.write_cons(f, self)
+    }
+}
+
+impl fmt::Debug for Cons
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "Cons{}", self)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ParseError
 {
@@ -96,6 +463,13 @@ pub enum ParseError
     UnclosedList,
     UnexpectedRparen,
     NoQuoteArg,
+    UnexpectedDot,
+    InvalidHashEntry,
+    UnexpectedDelim(char),
+    OddMapLiteral,
+    InvalidChar(String),
+    InvalidEscape(String, usize, usize),
+    Io(String),
     EndOfStream,
 }
 
@@ -109,11 +483,27 @@ impl fmt::Display for ParseError
             ParseError::UnclosedList => write!(f, "Unclosed list"),
             ParseError::UnexpectedRparen => write!(f, "Unexpected ')'"),
             ParseError::NoQuoteArg => write!(f, "Missing quote argument"),
+            ParseError::UnexpectedDot => write!(f, "Unexpected '.'"),
+            ParseError::InvalidHashEntry => write!(f, "Hash table entries must be pairs"),
+            ParseError::UnexpectedDelim(c) => write!(f, "Unexpected '{}'", c),
+            ParseError::OddMapLiteral => write!(f, "Map literal must have an even number of forms"),
+            ParseError::InvalidChar(ref s) => write!(f, "Invalid character literal #\\{}", s),
+            ParseError::InvalidEscape(ref s, line, col) => write!(f, "Invalid escape {} at line {}, column {}", s, line, col),
+            ParseError::Io(ref e) => write!(f, "Read failed: {}", e),
             ParseError::EndOfStream => write!(f, "End of stream"),
         }
     }
 }
 
+impl ParseError
+{
+    // true if the error can be fixed by appending more input
+    pub fn is_incomplete(&self) -> bool
+    {
+        matches!(*self, ParseError::UnclosedString | ParseError::UnclosedList | ParseError::NoQuoteArg)
+    }
+}
+
 #[derive(Debug)]
 pub enum RuntimeError
 {
@@ -122,6 +512,33 @@ pub enum RuntimeError
     InvalidArgNum(u32, u32),
     InvalidArgType(&'static str, &'static str),
     InvalidComp(&'static str, &'static str),
+    Syntax(ParseError),
+    FileError(String, String),
+    ModuleNotFound(String),
+    LoadCycle(String),
+    InFile(String, Box<RuntimeError>),
+    NotExported(String, String),
+    InvalidImport(String),
+    ImproperList,
+    InvalidIndex(f64),
+    IndexOutOfRange(usize, usize),
+    InvalidHashKind(Rc<String>),
+    KeyNotFound(String),
+    InvalidCharCode(f64),
+    InvalidRadix(f64),
+    NotAnInteger(f64),
+    InvalidRange(usize, usize),
+    TooLarge(f64, usize),
+    IoError(String, String),
+    PortClosed(String),
+    WrongRecordType(String, String),
+    InvalidDefinition(&'static str, String),
+    NoApplicableMethod(String, Vec<String>),
+    NoNextMethod(String),
+    NoSuchSlot(String, String),
+    MissingSlotValue(String, String),
+    WrongPortKind(String, &'static str),
+    Exit(i32),      // raised by `exit`, the embedder decides whether to end the process
 }
 
 impl fmt::Display for RuntimeError
@@ -134,6 +551,166 @@ impl fmt::Display for RuntimeError
             RuntimeError::InvalidArgNum(n, a) => write!(f, "Expected {} arguments, but got {}", n, a),
             RuntimeError::InvalidArgType(a, b) => write!(f, "Invalid argument: expected {}, but found {}", a, b),
             RuntimeError::InvalidComp(a, b) => write!(f, "Can't compare {} and {}", a, b),
+            RuntimeError::Syntax(ref e) => write!(f, "Syntax error: {}", e),
+            RuntimeError::FileError(ref p, ref e) => write!(f, "Can't load {}: {}", p, e),
+            RuntimeError::ModuleNotFound(ref n) => write!(f, "Module not found: {}", n),
+            RuntimeError::LoadCycle(ref p) => write!(f, "Circular load of {}", p),
+            RuntimeError::InFile(ref p, ref e) => write!(f, "{}: {}", p, e),
+            RuntimeError::NotExported(ref m, ref n) => write!(f, "Module {} doesn't export {}", m, n),
+            RuntimeError::InvalidImport(ref k) => write!(f, "Invalid import set: {}", k),
+            RuntimeError::InvalidIndex(i) => write!(f, "Invalid index {}: expected a non-negative integer", i),
+            RuntimeError::IndexOutOfRange(i, n) => write!(f, "Index {} out of range for length {}", i, n),
+            RuntimeError::InvalidHashKind(ref k) => write!(f, "Invalid hash table kind {}: expected equal or eq", k),
+            RuntimeError::KeyNotFound(ref k) => write!(f, "Key not found: {}", k),
+            RuntimeError::InvalidCharCode(n) => write!(f, "Invalid character code {}", n),
+            RuntimeError::InvalidRadix(n) => write!(f, "Invalid radix {}: expected an integer from 2 to 36", n),
+            RuntimeError::NotAnInteger(n) => write!(f, "Expected an integer, but found {}", n),
+            RuntimeError::InvalidRange(a, b) => write!(f, "Invalid range: start {} is past end {}", a, b),
+            RuntimeError::TooLarge(n, max) => write!(f, "Size {} is over the limit of {}", n, max),
+            RuntimeError::IoError(ref p, ref e) => write!(f, "I/O error on {}: {}", p, e),
+            RuntimeError::PortClosed(ref p) => write!(f, "Port {} is closed", p),
+            RuntimeError::WrongPortKind(ref p, k) => write!(f, "Port {} is not {}", p, k),
+            RuntimeError::WrongRecordType(ref a, ref b) => write!(f, "Invalid argument: expected a {} record, but found {}", a, b),
+            RuntimeError::InvalidDefinition(form, ref e) => write!(f, "Invalid {}: {}", form, e),
+            RuntimeError::NoApplicableMethod(ref g, ref t) => write!(f, "No method of {} applies to ({})", g, t.join(" ")),
+            RuntimeError::NoNextMethod(ref g) => write!(f, "No next method of {}", g),
+            RuntimeError::NoSuchSlot(ref c, ref s) => write!(f, "Class {} has no slot {}", c, s),
+            RuntimeError::MissingSlotValue(ref c, ref s) => write!(f, "Slot {} of {} is missing its value", s, c),
+            RuntimeError::ImproperList => write!(f, "Expected a proper list, but found an improper or circular one"),
+            RuntimeError::Exit(code) => write!(f, "Exit with status {}", code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use parser::Parser;
+    use scope::run;
+
+    fn eval(code: &str) -> Value
+    {
+        run(code).unwrap()
+    }
+
+    #[test]
+    fn debug_terminates_on_cycles()
+    {
+        let lst = eval("(let a (list 1 2)) (set-cdr! (cdr a) a) a");
+        assert_eq!(format!("{:?}", lst), "List(#0=(1 2 . #0#))");
+        let vec = eval("(let v (vector 1 2)) (vector-set! v 0 v) v");
+        assert_eq!(format!("{:?}", vec), "Vector(#0=#(#0# 2))");
+        let rec = eval("(define-record-type point (make-point x y) point? (x px set-px!) (y py)) (let p (make-point 1 2)) (set-px! p p) p");
+        assert_eq!(format!("{:?}", rec), "Record(#0=#<point x: #0# y: 2>)");
+        if let Value::Record(ref rec) = rec
+        {
+            assert_eq!(format!("{:?}", rec), "Record(#<point x: #0=#<point x: #0# y: 2> y: 2>)");
+        }
+    }
+
+    #[test]
+    fn debug_shows_atoms()
+    {
+        assert_eq!(format!("{:?}", eval("\"a\\nb\"")), "String(\"a\\nb\")");
+        assert_eq!(format!("{:?}", eval("'x")), "Symbol(\"x\")");
+        assert_eq!(format!("{:?}", eval("2")), "Number(2.0)");
+        assert_eq!(format!("{:?}", eval("#\\a")), "Char('a')");
+        assert_eq!(format!("{:?}", eval("'(1 \"s\")")), "List((1 \"s\"))");
+    }
+
+    #[test]
+    fn eq_terminates_on_cycles()
+    {
+        let a = eval("(let a (list 1 2)) (set-cdr! (cdr a) a) a");
+        let b = eval("(let b (list 1 2)) (set-cdr! (cdr b) b) b");
+        let c = eval("(let c (list 1 3)) (set-cdr! (cdr c) c) c");
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        let p = eval("(define-record-type point (make-point x y) point? (x px set-px!) (y py)) (let p (make-point 1 2)) (set-px! p p) p");
+        let q = eval("(define-record-type point (make-point x y) point? (x px set-px!) (y py)) (let p (make-point 1 2)) (set-px! p p) p");
+        assert_eq!(p.clone(), p);
+        // records of two different definitions are never equal
+        assert_ne!(p, q);
+        assert_eq!(eval("(list (/ 0 0))"), eval("(list (/ 0 0))"));
+    }
+
+    // a xorshift generator, so every run checks the same values
+    struct Gen(u64);
+
+    impl Gen
+    {
+        fn next(&mut self) -> u64
+        {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize
+        {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T
+        {
+            items[self.below(items.len())]
+        }
+
+        fn char(&mut self) -> char
+        {
+            self.pick(&['a', 'Z', '0', ' ', '\n', '\t', '\r', '\0', '\x07', '\x1b', '\x7f', '\u{85}', '"', '\\',
+                '(', ')', '[', '}', ';', '#', '\'', '.', 'λ', 'é', '\u{301}', '\u{1f600}', 'x'])
+        }
+
+        fn number(&mut self) -> f64
+        {
+            match self.below(4) {
+                0 => self.below(2001) as f64 - 1000.0,
+                1 => (self.below(2001) as f64 - 1000.0) / 64.0,
+                2 => self.pick(&[f64::INFINITY, f64::NEG_INFINITY, f64::NAN, 1e300, -5e-324, 0.1, 1e21]),
+                _ => f64::from_bits(self.next()),
+            }
+        }
+
+        fn value(&mut self, depth: usize) -> Value
+        {
+            match self.below(if depth == 0 { 7 } else { 10 }) {
+                0 => Value::Number(self.number()),
+                1 => Value::Char(self.char()),
+                2 => Value::String(Rc::new((0..self.below(8)).map(|_| self.char()).collect())),
+                3 => {
+                    let name = self.pick(&["x", "foo-bar", "set!", "x?", "->y", "+", "-", "...", "λ", "a.b", "*p*"]);
+                    Value::Symbol(Rc::new(name.to_string()))
+                },
+                4 => Value::Bool(self.below(2) == 0),
+                5 => Value::Nil,
+                6 => Value::List(List::End),
+                7 => {
+                    let elems: Vec<_> = (0..self.below(5) + 1).map(|_| self.value(depth - 1)).collect();
+                    let tail = if self.below(4) == 0 { self.value(depth - 1) } else { Value::List(List::End) };
+                    elems.into_iter().rev().fold(tail, |cdr, car| Value::List(List::pair(car, cdr)))
+                },
+                8 => Value::Vector(Rc::new(RefCell::new((0..self.below(5)).map(|_| self.value(depth - 1)).collect()))),
+                _ => Value::PVector((0..self.below(5)).map(|_| self.value(depth - 1)).collect()),
+            }
+        }
+    }
+
+    #[test]
+    fn write_reads_back()
+    {
+        let mut gen = Gen(0x2545_f491_4f6c_dd1d);
+        for _ in 0..5000
+        {
+            let val = gen.value(3);
+            let text = val.to_string();
+            match Parser::new(&text).parse() {
+                Ok(ref vals) if vals.len() == 1 => assert_eq!(vals[0], val, "{}", text),
+                other => panic!("{} read back as {:?}", text, other),
+            }
         }
     }
 }
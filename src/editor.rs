@@ -0,0 +1,659 @@
+use std::io::{self, Read, Write, BufRead, BufReader};
+use std::fs::File;
+use std::path::Path;
+use scope::Scope;
+
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key
+{
+    Char(char),
+    Ctrl(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Esc,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+}
+
+// the device the editor talks to, a fake one can be plugged in to drive the editor from a key list
+pub trait Terminal
+{
+    fn read_key(&mut self) -> io::Result<Key>;
+    fn write(&mut self, text: &str) -> io::Result<()>;
+    fn set_raw(&mut self, raw: bool) -> io::Result<()>;
+}
+
+pub trait Completer
+{
+    fn complete(&self, word: &str) -> Vec<String>;
+}
+
+impl Completer for Scope
+{
+    fn complete(&self, word: &str) -> Vec<String>
+    {
+        self.names().into_iter().filter(|name| name.starts_with(word)).collect()
+    }
+}
+
+// decodes a single keypress from a raw byte stream
+pub fn decode_key<R: Read>(input: &mut R) -> io::Result<Key>
+{
+    fn byte<R: Read>(input: &mut R) -> io::Result<u8>
+    {
+        let mut buf = [0; 1];
+        match input.read(&mut buf)? {
+            0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of input")),
+            _ => Ok(buf[0]),
+        }
+    }
+
+    Ok(match byte(input)? {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => match byte(input)? {
+            b'[' => match byte(input)? {
+                b'A' => Key::Up,
+                b'B' => Key::Down,
+                b'C' => Key::Right,
+                b'D' => Key::Left,
+                b'H' => Key::Home,
+                b'F' => Key::End,
+                num @ b'0'..=b'9' => {
+                    while byte(input)? != b'~' {}
+                    match num {
+                        b'1' | b'7' => Key::Home,
+                        b'4' | b'8' => Key::End,
+                        b'3' => Key::Delete,
+                        _ => Key::Esc,
+                    }
+                },
+                _ => Key::Esc,
+            },
+            b'O' => match byte(input)? {
+                b'H' => Key::Home,
+                b'F' => Key::End,
+                _ => Key::Esc,
+            },
+            _ => Key::Esc,
+        },
+        ctl @ 1..=26 => Key::Ctrl((b'a' + ctl - 1) as char),
+        first => {
+            let len = match first {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let mut buf = vec![first];
+            for _ in 1..len
+            {
+                buf.push(byte(input)?);
+            }
+            Key::Char(String::from_utf8_lossy(&buf).chars().next().unwrap_or('\u{fffd}'))
+        },
+    })
+}
+
+// the number of columns of the terminal on stdout, if it is one
+#[cfg(unix)]
+pub fn terminal_width() -> Option<usize>
+{
+    unsafe {
+        let mut size: ::libc::winsize = ::std::mem::zeroed();
+        match ::libc::ioctl(1, ::libc::TIOCGWINSZ, &mut size) {
+            0 if size.ws_col > 0 => Some(size.ws_col as usize),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn terminal_width() -> Option<usize>
+{
+    None
+}
+
+#[cfg(unix)]
+pub struct RawTerminal
+{
+    orig: ::libc::termios,
+    input: io::Stdin,
+    output: io::Stdout,
+}
+
+#[cfg(unix)]
+impl RawTerminal
+{
+    // fails if stdin is not an interactive terminal
+    pub fn new() -> io::Result<RawTerminal>
+    {
+        unsafe {
+            if ::libc::isatty(0) == 0
+            {
+                return Err(io::Error::other("stdin is not a terminal"))
+            }
+            let mut orig = ::std::mem::zeroed();
+            if ::libc::tcgetattr(0, &mut orig) != 0
+            {
+                return Err(io::Error::last_os_error())
+            }
+            Ok(RawTerminal{ orig, input: io::stdin(), output: io::stdout() })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Terminal for RawTerminal
+{
+    fn read_key(&mut self) -> io::Result<Key>
+    {
+        decode_key(&mut self.input)
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()>
+    {
+        self.output.write_all(text.as_bytes())?;
+        self.output.flush()
+    }
+
+    fn set_raw(&mut self, raw: bool) -> io::Result<()>
+    {
+        use libc::*;
+        let mut attr = self.orig;
+        if raw
+        {
+            attr.c_iflag &= !(BRKINT | ICRNL | INPCK | ISTRIP | IXON);
+            attr.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
+            attr.c_cc[VMIN] = 1;
+            attr.c_cc[VTIME] = 0;
+        }
+        match unsafe { tcsetattr(0, TCSADRAIN, &attr) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawTerminal
+{
+    fn drop(&mut self)
+    {
+        let _ = self.set_raw(false);
+    }
+}
+
+// index of the start of the word that ends at `pos`
+fn word_start(line: &[char], pos: usize) -> usize
+{
+    line[..pos].iter().rposition(|c| " \t()'\"".contains(*c)).map_or(0, |i| i + 1)
+}
+
+fn common_prefix(words: &[String]) -> String
+{
+    let mut prefix = words[0].clone();
+    for word in &words[1..]
+    {
+        let len = prefix.chars().zip(word.chars()).take_while(|&(a, b)| a == b).map(|(c, _)| c.len_utf8()).sum();
+        prefix.truncate(len);
+    }
+    prefix
+}
+
+// finds the paren matching the one before the cursor, or the one under it
+fn matching_paren(line: &[char], pos: usize) -> Option<usize>
+{
+    // parens inside string literals don't count
+    let mut code = Vec::with_capacity(line.len());
+    let (mut in_str, mut escape) = (false, false);
+    for &chr in line
+    {
+        code.push(!in_str && chr != '"');
+        if escape { escape = false }
+        else if chr == '\\' && in_str { escape = true }
+        else if chr == '"' { in_str = !in_str }
+    }
+
+    if pos > 0 && line[pos - 1] == ')' && code[pos - 1]
+    {
+        let mut depth = 0;
+        for i in (0..pos).rev().filter(|&i| code[i])
+        {
+            match line[i] {
+                ')' => depth += 1,
+                '(' => { depth -= 1; if depth == 0 { return Some(i) } },
+                _ => {},
+            }
+        }
+    }
+    else if pos < line.len() && line[pos] == '(' && code[pos]
+    {
+        let mut depth = 0;
+        for i in (pos..line.len()).filter(|&i| code[i])
+        {
+            match line[i] {
+                '(' => depth += 1,
+                ')' => { depth -= 1; if depth == 0 { return Some(i) } },
+                _ => {},
+            }
+        }
+    }
+    None
+}
+
+pub struct Editor<T: Terminal>
+{
+    term: T,
+    history: Vec<String>,
+}
+
+impl<T: Terminal> Editor<T>
+{
+    pub fn new(term: T) -> Editor<T>
+    {
+        Editor{ term, history: Vec::new() }
+    }
+
+    pub fn history(&self) -> &[String]
+    {
+        &self.history
+    }
+
+    pub fn add_history(&mut self, line: &str)
+    {
+        if line.trim().is_empty() || self.history.last().map(|s| &s[..]) == Some(line) { return }
+        self.history.push(line.to_string());
+        if self.history.len() > MAX_HISTORY
+        {
+            self.history.remove(0);
+        }
+    }
+
+    pub fn load_history<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()>
+    {
+        for line in BufReader::new(File::open(path)?).lines()
+        {
+            self.add_history(&line?);
+        }
+        Ok(())
+    }
+
+    pub fn save_history<P: AsRef<Path>>(&self, path: P) -> io::Result<()>
+    {
+        let mut file = File::create(path)?;
+        for line in &self.history
+        {
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    // reads a line in raw mode. Returns None on end of input, and an `Interrupted` error on Ctrl-C
+    pub fn read_line(&mut self, prompt: &str, comp: &dyn Completer) -> io::Result<Option<String>>
+    {
+        self.term.set_raw(true)?;
+        let res = self.edit(prompt, comp);
+        self.term.set_raw(false)?;
+        res
+    }
+
+    fn edit(&mut self, prompt: &str, comp: &dyn Completer) -> io::Result<Option<String>>
+    {
+        let mut line: Vec<char> = Vec::new();
+        let mut pos = 0;
+        let mut hist_idx = self.history.len();
+        let mut stash = Vec::new();   // the line being edited while browsing the history
+
+        self.refresh(prompt, &line, pos)?;
+        loop
+        {
+            let mut key = self.term.read_key()?;
+            if key == Key::Ctrl('r')
+            {
+                match self.search(&mut line)? {
+                    Some(k) => { key = k; pos = line.len(); },
+                    None => { self.refresh(prompt, &line, pos)?; continue },
+                }
+            }
+
+            match key {
+                Key::Enter => {
+                    self.term.write("\r\n")?;
+                    return Ok(Some(line.into_iter().collect()))
+                },
+                Key::Ctrl('c') => {
+                    self.term.write("^C\r\n")?;
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+                },
+                Key::Ctrl('d') if line.is_empty() => {
+                    self.term.write("\r\n")?;
+                    return Ok(None)
+                },
+                Key::Ctrl('d') | Key::Delete if pos < line.len() => { line.remove(pos); },
+                Key::Backspace | Key::Ctrl('h') if pos > 0 => { pos -= 1; line.remove(pos); },
+                Key::Left | Key::Ctrl('b') => pos = pos.saturating_sub(1),
+                Key::Right | Key::Ctrl('f') if pos < line.len() => pos += 1,
+                Key::Home | Key::Ctrl('a') => pos = 0,
+                Key::End | Key::Ctrl('e') => pos = line.len(),
+                Key::Ctrl('k') => line.truncate(pos),
+                Key::Ctrl('u') => { line.drain(..pos); pos = 0; },
+                Key::Ctrl('w') => {
+                    let start = line[..pos].iter().rposition(|c| !c.is_whitespace())
+                        .map_or(0, |i| word_start(&line, i + 1));
+                    line.drain(start..pos);
+                    pos = start;
+                },
+                Key::Up | Key::Ctrl('p') if hist_idx > 0 => {
+                    if hist_idx == self.history.len() { stash = line.clone() }
+                    hist_idx -= 1;
+                    line = self.history[hist_idx].chars().collect();
+                    pos = line.len();
+                },
+                Key::Down | Key::Ctrl('n') if hist_idx < self.history.len() => {
+                    hist_idx += 1;
+                    line = match self.history.get(hist_idx) {
+                        Some(entry) => entry.chars().collect(),
+                        None => stash.clone(),
+                    };
+                    pos = line.len();
+                },
+                Key::Tab => self.complete(prompt, &mut line, &mut pos, comp)?,
+                Key::Char(chr) => { line.insert(pos, chr); pos += 1; },
+                _ => {},
+            }
+            self.refresh(prompt, &line, pos)?;
+        }
+    }
+
+    fn refresh(&mut self, prompt: &str, line: &[char], pos: usize) -> io::Result<()>
+    {
+        let hl = matching_paren(line, pos);
+        let mut out = format!("\r{}", prompt);
+        for (i, &chr) in line.iter().enumerate()
+        {
+            if Some(i) == hl
+            {
+                out.push_str("\x1b[1;7m");
+                out.push(chr);
+                out.push_str("\x1b[0m");
+            }
+            else
+            {
+                out.push(chr);
+            }
+        }
+        out.push_str("\x1b[K\r");
+        let col = prompt.chars().count() + pos;
+        if col > 0
+        {
+            out.push_str(&format!("\x1b[{}C", col));
+        }
+        self.term.write(&out)
+    }
+
+    fn complete(&mut self, prompt: &str, line: &mut Vec<char>, pos: &mut usize, comp: &dyn Completer) -> io::Result<()>
+    {
+        let start = word_start(line, *pos);
+        let word: String = line[start..*pos].iter().collect();
+        let mut cands = comp.complete(&word);
+        cands.sort();
+        cands.dedup();
+        if cands.is_empty()
+        {
+            return self.term.write("\x07")
+        }
+
+        let prefix = common_prefix(&cands);
+        if prefix.len() > word.len()
+        {
+            for chr in prefix[word.len()..].chars()
+            {
+                line.insert(*pos, chr);
+                *pos += 1;
+            }
+        }
+        else if cands.len() > 1
+        {
+            self.term.write(&format!("\r\n{}\r\n", cands.join("  ")))?;
+            self.refresh(prompt, line, *pos)?;
+        }
+        Ok(())
+    }
+
+    // incremental reverse history search. Returns the key that ended the search, or None if cancelled
+    fn search(&mut self, line: &mut Vec<char>) -> io::Result<Option<Key>>
+    {
+        let mut query = String::new();
+        let mut idx = self.history.len();
+        let mut found: Option<usize> = None;
+        loop
+        {
+            let shown = found.map_or("", |i| &self.history[i][..]);
+            let text = format!("\r(reverse-i-search)`{}': {}\x1b[K", query, shown);
+            self.term.write(&text)?;
+
+            let from = match self.term.read_key()? {
+                Key::Char(chr) => { query.push(chr); found.map_or(idx, |i| i + 1) },
+                Key::Backspace => { query.pop(); self.history.len() },
+                Key::Ctrl('r') => found.unwrap_or(idx),
+                Key::Ctrl('g') | Key::Ctrl('c') | Key::Esc => return Ok(None),
+                key => {
+                    if let Some(i) = found
+                    {
+                        *line = self.history[i].chars().collect();
+                    }
+                    return Ok(Some(key))
+                },
+            };
+            idx = from;
+            found = self.history[..from].iter().rposition(|entry| entry.contains(&query[..]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::collections::VecDeque;
+
+    // a terminal that plays back a list of keys and records what the editor writes
+    struct ScriptedTerminal
+    {
+        keys: VecDeque<Key>,
+        output: String,
+        raw: bool,
+    }
+
+    impl ScriptedTerminal
+    {
+        fn new(keys: Vec<Key>) -> ScriptedTerminal
+        {
+            ScriptedTerminal{ keys: keys.into(), output: String::new(), raw: false }
+        }
+    }
+
+    impl Terminal for ScriptedTerminal
+    {
+        fn read_key(&mut self) -> io::Result<Key>
+        {
+            self.keys.pop_front().ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "end of script"))
+        }
+
+        fn write(&mut self, text: &str) -> io::Result<()>
+        {
+            self.output.push_str(text);
+            Ok(())
+        }
+
+        fn set_raw(&mut self, raw: bool) -> io::Result<()>
+        {
+            self.raw = raw;
+            Ok(())
+        }
+    }
+
+    struct Names(Vec<&'static str>);
+
+    impl Completer for Names
+    {
+        fn complete(&self, word: &str) -> Vec<String>
+        {
+            self.0.iter().filter(|n| n.starts_with(word)).map(|n| n.to_string()).collect()
+        }
+    }
+
+    fn typed(text: &str) -> Vec<Key>
+    {
+        text.chars().map(Key::Char).collect()
+    }
+
+    fn editor(keys: Vec<Key>, history: &[&str]) -> Editor<ScriptedTerminal>
+    {
+        let mut ed = Editor::new(ScriptedTerminal::new(keys));
+        for line in history
+        {
+            ed.add_history(line);
+        }
+        ed
+    }
+
+    fn read(ed: &mut Editor<ScriptedTerminal>) -> io::Result<Option<String>>
+    {
+        ed.read_line("> ", &Names(vec![]))
+    }
+
+    #[test]
+    fn edits_a_line()
+    {
+        let mut keys = typed("(+ 1 3");
+        keys.extend(vec![Key::Backspace, Key::Char('2'), Key::Home, Key::Delete, Key::Char('('), Key::End, Key::Char(')'), Key::Enter]);
+        let mut ed = editor(keys, &[]);
+        assert_eq!(read(&mut ed).unwrap(), Some("(+ 1 2)".to_string()));
+        assert!(!ed.term.raw);
+    }
+
+    #[test]
+    fn kills_words_and_lines()
+    {
+        let mut keys = typed("(foo bar baz");
+        keys.extend(vec![Key::Ctrl('w'), Key::Ctrl('a'), Key::Right, Key::Ctrl('k'), Key::Enter]);
+        assert_eq!(read(&mut editor(keys, &[])).unwrap(), Some("(".to_string()));
+    }
+
+    #[test]
+    fn browses_history()
+    {
+        let mut keys = typed("new");
+        keys.extend(vec![Key::Up, Key::Up, Key::Up, Key::Down, Key::Enter]);
+        assert_eq!(read(&mut editor(keys, &["one", "two"])).unwrap(), Some("two".to_string()));
+
+        // going past the newest entry brings back the line being typed
+        let mut keys = typed("new");
+        keys.extend(vec![Key::Up, Key::Down, Key::Enter]);
+        assert_eq!(read(&mut editor(keys, &["one", "two"])).unwrap(), Some("new".to_string()));
+    }
+
+    #[test]
+    fn skips_blank_and_repeated_history()
+    {
+        let ed = editor(vec![], &["a", "a", "  ", "b", "a"]);
+        assert_eq!(ed.history(), &["a", "b", "a"]);
+    }
+
+    #[test]
+    fn searches_history_backwards()
+    {
+        let history = ["(car x)", "(cdr y)", "(cadr z)"];
+        let mut keys = vec![Key::Ctrl('r')];
+        keys.extend(typed("ca"));
+        keys.push(Key::Enter);
+        assert_eq!(read(&mut editor(keys, &history)).unwrap(), Some("(cadr z)".to_string()));
+
+        // another Ctrl-R goes to the previous match
+        let mut keys = vec![Key::Ctrl('r')];
+        keys.extend(typed("ca"));
+        keys.extend(vec![Key::Ctrl('r'), Key::Enter]);
+        assert_eq!(read(&mut editor(keys, &history)).unwrap(), Some("(car x)".to_string()));
+
+        // cancelling keeps the line as it was
+        let mut keys = typed("x");
+        keys.extend(vec![Key::Ctrl('r'), Key::Char('c'), Key::Ctrl('g'), Key::Enter]);
+        assert_eq!(read(&mut editor(keys, &history)).unwrap(), Some("x".to_string()));
+    }
+
+    #[test]
+    fn completes_symbols()
+    {
+        let names = Names(vec!["car", "cdr", "string-length", "string-ref"]);
+        let mut keys = typed("(str");
+        keys.extend(vec![Key::Tab, Key::Char('l'), Key::Tab, Key::Enter]);
+        let mut ed = editor(keys, &[]);
+        assert_eq!(ed.read_line("> ", &names).unwrap(), Some("(string-length".to_string()));
+
+        // an ambiguous word lists the candidates
+        let mut keys = typed("(c");
+        keys.extend(vec![Key::Tab, Key::Enter]);
+        let mut ed = editor(keys, &[]);
+        assert_eq!(ed.read_line("> ", &names).unwrap(), Some("(c".to_string()));
+        assert!(ed.term.output.contains("car  cdr"));
+
+        // and no match rings the bell
+        let mut keys = typed("(zz");
+        keys.extend(vec![Key::Tab, Key::Enter]);
+        let mut ed = editor(keys, &[]);
+        assert_eq!(ed.read_line("> ", &names).unwrap(), Some("(zz".to_string()));
+        assert!(ed.term.output.contains('\x07'));
+    }
+
+    #[test]
+    fn matches_parens()
+    {
+        let line: Vec<char> = "(a (b \")\") c)".chars().collect();
+        assert_eq!(matching_paren(&line, line.len()), Some(0));
+        assert_eq!(matching_paren(&line, 3), Some(9));
+        assert_eq!(matching_paren(&line, 10), Some(3));
+        assert_eq!(matching_paren(&line, 1), None);
+        // the paren in the string doesn't count
+        assert_eq!(matching_paren(&line, 8), None);
+
+        let mut keys = typed("(a)");
+        keys.push(Key::Enter);
+        let mut ed = editor(keys, &[]);
+        read(&mut ed).unwrap();
+        assert!(ed.term.output.contains("\x1b[1;7m(\x1b[0ma)"));
+    }
+
+    #[test]
+    fn ends_input()
+    {
+        let mut ed = editor(vec![Key::Ctrl('d')], &[]);
+        assert_eq!(read(&mut ed).unwrap(), None);
+
+        let mut ed = editor(vec![Key::Char('x'), Key::Ctrl('c')], &[]);
+        assert_eq!(read(&mut ed).unwrap_err().kind(), io::ErrorKind::Interrupted);
+
+        // a terminal that runs out of input is an error, and leaves raw mode
+        let mut ed = editor(vec![Key::Char('x')], &[]);
+        assert_eq!(read(&mut ed).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!ed.term.raw);
+    }
+
+    #[test]
+    fn decodes_keys()
+    {
+        let mut input: &[u8] = b"a\x1b[A\x1b[3~\x01\r\xc3\xa9";
+        let keys: Vec<Key> = (0..6).map(|_| decode_key(&mut input).unwrap()).collect();
+        assert_eq!(keys, vec![Key::Char('a'), Key::Up, Key::Delete, Key::Ctrl('a'), Key::Enter, Key::Char('é')]);
+    }
+}
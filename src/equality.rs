@@ -0,0 +1,266 @@
+use std::rc::Rc;
+use std::collections::HashSet;
+use data::{Value, List, RuntimeError};
+use record::Record;
+use data::RuntimeError::*;
+use scope::Scope;
+
+// pairs of compound values being compared, so cycles compare as equal instead of looping
+type Seen = HashSet<(*const (), *const ())>;
+
+// identity: the same object, or the same immediate value. Numbers are the same if their bits are
+pub fn is_eq(a: &Value, b: &Value) -> bool
+{
+    match (a, b) {
+        (&Value::Number(x), &Value::Number(y)) => x.to_bits() == y.to_bits(),
+        _ => same_object(a, b),
+    }
+}
+
+// like `eq?`, but numbers compare by value, with all the NaNs the same
+pub fn is_eqv(a: &Value, b: &Value) -> bool
+{
+    match (a, b) {
+        (&Value::Number(x), &Value::Number(y)) => x == y || (x.is_nan() && y.is_nan()),
+        _ => same_object(a, b),
+    }
+}
+
+// structural equality of lists, vectors, strings, records and persistent collections
+pub fn is_equal(a: &Value, b: &Value) -> bool
+{
+    equal_in(a, b, &mut Seen::new())
+}
+
+// two records of the same type with `equal?` fields
+pub fn records_equal(x: &Record, y: &Record) -> bool
+{
+    records_in(x, y, &mut Seen::new())
+}
+
+fn same_object(a: &Value, b: &Value) -> bool
+{
+    match (a, b) {
+        (Value::Nil, Value::Nil) | (Value::Eof, Value::Eof) => true,
+        (Value::List(List::End), Value::List(List::End)) => true,
+        (&Value::Bool(x), &Value::Bool(y)) => x == y,
+        (&Value::Char(x), &Value::Char(y)) => x == y,
+        (Value::Symbol(x), Value::Symbol(y)) => x == y,
+        (Value::String(x), Value::String(y)) => Rc::ptr_eq(x, y),
+        (Value::List(List::Node(x)), Value::List(List::Node(y))) => Rc::ptr_eq(x, y),
+        (Value::Vector(x), Value::Vector(y)) => Rc::ptr_eq(x, y),
+        (Value::HashTable(x), Value::HashTable(y)) => Rc::ptr_eq(x, y),
+        (Value::PVector(x), Value::PVector(y)) => x.root_ptr() == y.root_ptr() && x.len() == y.len(),
+        (Value::PMap(x), Value::PMap(y)) => x.root_ptr() == y.root_ptr(),
+        (Value::Builtin(x), Value::Builtin(y)) => Rc::ptr_eq(x, y),
+        (Value::Lambda(x), Value::Lambda(y)) => Rc::ptr_eq(x, y),
+        (Value::Generic(x), Value::Generic(y)) => Rc::ptr_eq(x, y),
+        (Value::Module(x), Value::Module(y)) => Rc::ptr_eq(x, y),
+        (Value::Port(x), Value::Port(y)) => Rc::ptr_eq(x, y),
+        (Value::Record(x), Value::Record(y)) => Rc::ptr_eq(x, y),
+        (Value::RecordType(x), Value::RecordType(y)) => Rc::ptr_eq(x, y),
+        _ => false,
+    }
+}
+
+// true if the pair was already being compared, otherwise marks it
+fn visit<T: ?Sized, U: ?Sized>(x: *const T, y: *const U, seen: &mut Seen) -> bool
+{
+    !seen.insert((x as *const (), y as *const ()))
+}
+
+fn equal_in(a: &Value, b: &Value, seen: &mut Seen) -> bool
+{
+    if is_eqv(a, b) { return true }
+    match (a, b) {
+        (Value::String(x), Value::String(y)) => x == y,
+        (Value::List(List::Node(x)), Value::List(List::Node(y))) => {
+            let (mut x, mut y) = (x.clone(), y.clone());
+            // the spine is walked in a loop, so long lists don't use up the stack
+            loop
+            {
+                if visit(Rc::as_ptr(&x), Rc::as_ptr(&y), seen) { return true }
+                if !equal_in(&x.car.borrow(), &y.car.borrow(), seen) { return false }
+                let next = match (&*x.cdr.borrow(), &*y.cdr.borrow()) {
+                    (Value::List(List::Node(a)), Value::List(List::Node(b))) => (a.clone(), b.clone()),
+                    (a, b) => return equal_in(a, b, seen),
+                };
+                x = next.0;
+                y = next.1;
+            }
+        },
+        (Value::Vector(x), Value::Vector(y)) => {
+            if visit(Rc::as_ptr(x), Rc::as_ptr(y), seen) { return true }
+            let (x, y) = (x.borrow(), y.borrow());
+            x.len() == y.len() && x.iter().zip(y.iter()).all(|(x, y)| equal_in(x, y, seen))
+        },
+        (Value::Record(x), Value::Record(y)) => records_in(x, y, seen),
+        (Value::PVector(x), Value::PVector(y)) =>
+            x.len() == y.len() && x.iter().zip(y.iter()).all(|(x, y)| equal_in(x, y, seen)),
+        // keys are found with `equal?` already, the values are compared here
+        (Value::PMap(x), Value::PMap(y)) =>
+            x.len() == y.len() && x.entries().iter().all(|&(k, v)| y.get(k).is_some_and(|w| equal_in(v, w, seen))),
+        _ => false,
+    }
+}
+
+fn records_in(x: &Record, y: &Record, seen: &mut Seen) -> bool
+{
+    if !Rc::ptr_eq(&x.rtype, &y.rtype) { return false }
+    if visit(x, y, seen) { return true }
+    let (xf, yf) = (x.fields.borrow(), y.fields.borrow());
+    xf.iter().zip(yf.iter()).all(|(x, y)| equal_in(x, y, seen))
+}
+
+// the first pair of the list whose car matches `key`, or #f. A circular list without a match is an
+// ImproperList error, found like in `List::is_proper` by a second pointer that moves at half the speed
+fn find_tail<F>(key: &Value, lst: Value, matches: F) -> Result<Value, RuntimeError>
+    where F: Fn(&Value, &Value) -> bool
+{
+    let mut slow = lst.clone();
+    let mut cur = lst;
+    let mut moved = false;
+    loop
+    {
+        let cons = match cur {
+            Value::List(List::Node(cons)) => cons,
+            Value::List(List::End) | Value::Nil => return Ok(Value::Bool(false)),
+            _ => return Err(ImproperList),
+        };
+        if matches(key, &cons.car.borrow())
+        {
+            return Ok(Value::List(List::Node(cons)))
+        }
+        let next = cons.cdr.borrow().clone();
+        cur = next;
+
+        if moved
+        {
+            let next = match slow {
+                Value::List(List::Node(ref s)) => s.cdr.borrow().clone(),
+                ref other => other.clone(),
+            };
+            slow = next;
+        }
+        moved = !moved;
+        if let (Value::List(List::Node(a)), Value::List(List::Node(b))) = (&slow, &cur)
+        {
+            if Rc::ptr_eq(a, b) { return Err(ImproperList) }
+        }
+    }
+}
+
+// the first element of an association list whose car matches `key`, or #f
+fn find_entry<F>(key: &Value, alist: Value, matches: F) -> Result<Value, RuntimeError>
+    where F: Fn(&Value, &Value) -> bool
+{
+    find_tail(key, alist, |key, entry| match *entry {
+        Value::List(List::Node(ref pair)) => matches(key, &pair.car.borrow()),
+        _ => false,
+    }).map(|tail| match tail {
+        Value::List(List::Node(cons)) => cons.car.borrow().clone(),
+        other => other,
+    })
+}
+
+pub fn load_equality(env: &mut Scope)
+{
+    env.set_builtin("eq?", true, |mut args, _| {
+        let va = check_arg!(args, 2, 0);
+        let vb = check_arg!(args, 2, 1);
+        Ok(Value::Bool(is_eq(&va, &vb)))
+    });
+
+    env.set_builtin("eqv?", true, |mut args, _| {
+        let va = check_arg!(args, 2, 0);
+        let vb = check_arg!(args, 2, 1);
+        Ok(Value::Bool(is_eqv(&va, &vb)))
+    });
+
+    env.set_builtin("equal?", true, |mut args, _| {
+        let va = check_arg!(args, 2, 0);
+        let vb = check_arg!(args, 2, 1);
+        Ok(Value::Bool(is_equal(&va, &vb)))
+    });
+
+    // the older name of `equal?`
+    env.set_builtin("equal", true, |mut args, _| {
+        let va = check_arg!(args, 2, 0);
+        let vb = check_arg!(args, 2, 1);
+        Ok(Value::Bool(is_equal(&va, &vb)))
+    });
+
+    env.set_builtin("memq", true, |mut args, _| {
+        let key = check_arg!(args, 2, 0);
+        find_tail(&key, check_arg!(args, 2, 1), is_eq)
+    });
+
+    env.set_builtin("memv", true, |mut args, _| {
+        let key = check_arg!(args, 2, 0);
+        find_tail(&key, check_arg!(args, 2, 1), is_eqv)
+    });
+
+    env.set_builtin("member", true, |mut args, _| {
+        let key = check_arg!(args, 2, 0);
+        find_tail(&key, check_arg!(args, 2, 1), is_equal)
+    });
+
+    env.set_builtin("assq", true, |mut args, _| {
+        let key = check_arg!(args, 2, 0);
+        find_entry(&key, check_arg!(args, 2, 1), is_eq)
+    });
+
+    env.set_builtin("assv", true, |mut args, _| {
+        let key = check_arg!(args, 2, 0);
+        find_entry(&key, check_arg!(args, 2, 1), is_eqv)
+    });
+
+    env.set_builtin("assoc", true, |mut args, _| {
+        let key = check_arg!(args, 2, 0);
+        find_entry(&key, check_arg!(args, 2, 1), is_equal)
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use scope::run;
+
+    #[test]
+    fn searches_end_on_circular_lists()
+    {
+        // cycles of every length from 1 to 4, behind a prefix of 0 to 2 pairs
+        for prefix in 0..3
+        {
+            for len in 1..5
+            {
+                let elems: Vec<String> = (0..prefix + len).map(|i| format!("'(k{} . {})", i, i)).collect();
+                let tail = |n| (0..n).fold("l".to_string(), |expr, _| format!("(cdr {})", expr));
+                let (last, start) = (tail(prefix + len - 1), tail(prefix));
+                let setup = format!("(let l (list {})) (set-cdr! {} {})", elems.join(" "), last, start);
+                for search in &["memq", "memv", "member", "assq", "assv", "assoc"]
+                {
+                    let code = format!("{} ({} 'missing l)", setup, search);
+                    assert!(matches!(run(&code), Err(ImproperList)), "{}", code);
+                }
+                // a match is still found before going around
+                let last = format!("{} (car (assoc 'k{} l))", setup, prefix + len - 1);
+                assert_eq!(run(&last).unwrap().to_string(), format!("k{}", prefix + len - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn looks_up_alists_with_equal()
+    {
+        assert_eq!(run("(assoc \"b\" '((\"a\" . 1) (\"b\" . 2)))").unwrap().to_string(), "(\"b\" . 2)");
+        assert_eq!(run("(assoc '(1) '(((1) . x)))").unwrap().to_string(), "((1) . x)");
+        assert_eq!(run("(assv '(1) '(((1) . x)))").unwrap(), Value::Bool(false));
+        assert_eq!(run("(assoc 3 '())").unwrap(), Value::Bool(false));
+        assert!(matches!(run("(assoc 3 '((1 . 2) . 5))"), Err(ImproperList)));
+        assert_eq!(run("(assoc 'a '((a . 1)))").unwrap().to_string(), "(a . 1)");
+        // the persistent collections have their own `passoc`
+        assert_eq!(run("(get (passoc {} 'a 1) 'a)").unwrap(), Value::Number(1.0));
+    }
+}
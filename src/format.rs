@@ -0,0 +1,211 @@
+use data::ParseError;
+use cst::{Cst, CstNode, CstToken, Trivia, TriviaKind};
+use pretty::{Doc, text, seq, list_doc, layout};
+
+// a form of the source, atoms keep their original text
+enum Node
+{
+    Atom(String),
+    Quote(Box<Node>),
+    Seq(String, String, Vec<Item>),     // open and close delimiters, and the contents
+}
+
+enum Item
+{
+    Node(Node),
+    Comment(String, bool),  // true if the comment starts its own line
+    Blank,                  // one or more blank lines
+}
+
+// appends the comments in the trivia before a token to `items`. Returns true if there's a blank line
+// between the last of them and the token
+fn trivia_items(cst: &Cst, leading: &[Trivia], items: &mut Vec<Item>) -> bool
+{
+    let mut newlines = 0;
+    for trivia in leading
+    {
+        match trivia.kind {
+            TriviaKind::Newline => newlines += 1,
+            TriviaKind::Comment => {
+                if newlines > 1 && !items.is_empty() { items.push(Item::Blank) }
+                // a comment after something on the same line trails it
+                let own_line = newlines > 0 || !items.iter().any(|item| matches!(*item, Item::Node(_)));
+                items.push(Item::Comment(cst.text(trivia.span).to_string(), own_line));
+                newlines = 0;
+            },
+            TriviaKind::Whitespace => {},
+        }
+    }
+    newlines > 1 && !items.is_empty()
+}
+
+// the items of a sequence of nodes, followed by the token that ends it
+fn seq_items(cst: &Cst, nodes: &[CstNode], end: &CstToken) -> Vec<Item>
+{
+    let mut items = Vec::new();
+    for node in nodes
+    {
+        if trivia_items(cst, &node.first_token().leading, &mut items)
+        {
+            items.push(Item::Blank);
+        }
+        let node = convert(cst, node, &mut items);
+        items.push(Item::Node(node));
+    }
+    trivia_items(cst, &end.leading, &mut items);
+    items
+}
+
+// the node of the formatter for a syntax tree node, comments inside quotes are moved before them
+fn convert(cst: &Cst, node: &CstNode, items: &mut Vec<Item>) -> Node
+{
+    match *node {
+        CstNode::Atom(ref tok) => Node::Atom(cst.text(tok.span).to_string()),
+        CstNode::Quote(_, ref node) => {
+            trivia_items(cst, &node.first_token().leading, items);
+            Node::Quote(Box::new(convert(cst, node, items)))
+        },
+        CstNode::Seq(ref open, ref elems, ref close) =>
+            Node::Seq(cst.text(open.span).to_string(), cst.text(close.span).to_string(), seq_items(cst, elems, close)),
+    }
+}
+
+// the head of a list, if it's a symbol
+fn head_symbol(items: &[Item]) -> Option<&str>
+{
+    match items.first() {
+        Some(Item::Node(Node::Atom(s))) if s.parse::<f64>().is_err() && !s.starts_with(['"', '#']) => Some(s),
+        _ => None,
+    }
+}
+
+// the docs of the nodes, with the comments and blank lines attached to them
+fn item_docs(items: &[Item]) -> Vec<Doc>
+{
+    let mut docs: Vec<Doc> = Vec::new();
+    let mut pending = Vec::new();   // the comments and blank lines before the next node
+    let mut trailing = false;
+    for item in items
+    {
+        trailing = false;
+        match *item {
+            Item::Blank => pending.push(Doc::HardLine),
+            Item::Comment(ref c, false) if pending.is_empty() && !docs.is_empty() => {
+                let last = docs.pop().unwrap();
+                docs.push(Doc::Concat(vec![last, Doc::Suffix(c.clone()), Doc::BreakParent]));
+                trailing = true;
+            },
+            Item::Comment(ref c, _) => {
+                pending.push(text(c.clone()));
+                pending.push(Doc::HardLine);
+            },
+            Item::Node(ref node) => {
+                pending.push(node_doc(node));
+                docs.push(Doc::Concat(pending));
+                pending = Vec::new();
+            },
+        }
+    }
+    // comments at the end go in their own lines, before the closing delimiter
+    if trailing
+    {
+        pending.push(Doc::HardLine);
+    }
+    if !pending.is_empty()
+    {
+        match docs.pop() {
+            Some(last) => {
+                let mut tail = vec![last];
+                if !trailing { tail.push(Doc::HardLine) }
+                tail.extend(pending);
+                docs.push(Doc::Concat(tail));
+            },
+            None => docs.push(Doc::Concat(pending)),
+        }
+    }
+    docs
+}
+
+fn node_doc(node: &Node) -> Doc
+{
+    match *node {
+        Node::Atom(ref s) => text(s.clone()),
+        Node::Quote(ref node) => Doc::Concat(vec![text("'"), node_doc(node)]),
+        Node::Seq(ref open, _, ref items) if open == "(" => list_doc(head_symbol(items), item_docs(items)),
+        Node::Seq(ref open, ref close, ref items) => seq(open, item_docs(items), close),
+    }
+}
+
+// reformats source code with the canonical layout, keeping the comments and up to one blank line between forms
+pub fn format_source(text: &str, width: usize) -> Result<String, ParseError>
+{
+    let cst = Cst::parse(text)?;
+    let items = seq_items(&cst, &cst.nodes, &cst.end);
+    let mut docs = Vec::new();
+    for doc in item_docs(&items)
+    {
+        if !docs.is_empty() { docs.push(Doc::HardLine) }
+        docs.push(doc);
+    }
+    let mut out = layout(&Doc::Concat(docs), width);
+    let len = out.trim_end().len();
+    out.truncate(len);
+    if !out.is_empty() { out.push('\n') }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const SOURCES: &[&str] = &[
+        "; header\n\n(define   (f x)\n  ; inside\n  (+ x 1))   ; trailing\n\n\n(let y [1 2 3])\n",
+        "(let table #hash((a . 1) (b . 2))) (let m {'a 1 'b [1 2 3]}) '(a . b) `(1 ,x ,@xs)",
+        "(defclass circle (shape) (r (color 'red))) (defmethod area ((c circle)) (* 3.14 (slot-ref c 'r) (slot-ref c 'r)))",
+        "(if (> (length-of-a-long-name some-argument) another-long-argument) (display \"yes, it is\") (display \"no\"))",
+        "",
+        "   \n\n; only a comment",
+    ];
+
+    #[test]
+    fn formatting_twice_changes_nothing()
+    {
+        for &width in &[20, 40, 80]
+        {
+            for src in SOURCES
+            {
+                let once = format_source(src, width).unwrap();
+                assert_eq!(format_source(&once, width).unwrap(), once, "{:?} at width {}", src, width);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_comments_and_blank_lines()
+    {
+        let out = format_source(SOURCES[0], 80).unwrap();
+        assert_eq!(out, "; header\n\n(define (f x)\n        ; inside\n        (+ x 1)) ; trailing\n\n(let y [1 2 3])\n");
+        assert_eq!(format_source(SOURCES[5], 80).unwrap(), "; only a comment\n");
+        assert_eq!(format_source("(a)\n(b)\n", 80).unwrap(), "(a)\n(b)\n");
+        assert_eq!(format_source("(a)\n\n\n\n(b)", 80).unwrap(), "(a)\n\n(b)\n");
+    }
+
+    #[test]
+    fn fits_the_width()
+    {
+        let out = format_source(SOURCES[3], 50).unwrap();
+        assert!(out.lines().count() > 1);
+        assert!(out.lines().all(|line| line.len() <= 50), "{}", out);
+        assert_eq!(format_source(SOURCES[3], 200).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn rejects_syntax_errors()
+    {
+        for src in &["(f \"a)", "(a b", "(a))", "#\\nosuchchar"]
+        {
+            assert!(format_source(src, 80).is_err(), "{}", src);
+        }
+    }
+}
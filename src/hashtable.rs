@@ -0,0 +1,421 @@
+use std::fmt;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::hash::{Hash, Hasher};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use data::{Value, List, Function};
+use data::RuntimeError::*;
+use scope::Scope;
+use equality::{is_eq, is_equal};
+
+// only the first elements of a list, vector or record contribute to its hash, down to a few levels of
+// nesting, so cycles and huge keys stay cheap. Keys that differ past these limits collide, but still compare
+// apart
+const HASH_ELEMS: usize = 8;
+const HASH_DEPTH: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashKind
+{
+    Equal,  // keys compare by structure
+    Eq,     // keys compare with `eq?`: by identity, or the bits of a number
+}
+
+fn hash_num<H: Hasher>(n: f64, state: &mut H)
+{
+    // 0.0 and -0.0 are equal, and all the NaNs are the same key
+    let n = if n == 0.0 { 0.0 } else if n.is_nan() { f64::NAN } else { n };
+    n.to_bits().hash(state);
+}
+
+fn hash_ptr<T: ?Sized, H: Hasher>(ptr: *const T, state: &mut H)
+{
+    (ptr as *const () as usize).hash(state);
+}
+
+fn hash_value<H: Hasher>(val: &Value, kind: HashKind, depth: u32, state: &mut H)
+{
+    val.type_name().hash(state);
+    match *val {
+        Value::Nil | Value::Eof => {},
+        Value::Bool(b) => b.hash(state),
+        Value::Number(n) => hash_num(n, state),
+        Value::Char(c) => c.hash(state),
+        Value::Symbol(ref s) => s.hash(state),
+        Value::String(ref s) if kind == HashKind::Equal => s.hash(state),
+        Value::String(ref s) => hash_ptr(Rc::as_ptr(s), state),
+        Value::List(List::End) => {},
+        Value::List(ref lst) if kind == HashKind::Equal => if depth > 0 {
+            for elem in lst.iter().take(HASH_ELEMS)
+            {
+                hash_value(&elem, kind, depth - 1, state);
+            }
+        },
+        Value::List(List::Node(ref cons)) => hash_ptr(Rc::as_ptr(cons), state),
+        Value::Vector(ref vec) if kind == HashKind::Equal => if depth > 0 {
+            for elem in vec.borrow().iter().take(HASH_ELEMS)
+            {
+                hash_value(elem, kind, depth - 1, state);
+            }
+        },
+        Value::Vector(ref vec) => hash_ptr(Rc::as_ptr(vec), state),
+        Value::PVector(ref vec) if kind == HashKind::Equal => if depth > 0 {
+            for elem in vec.iter().take(HASH_ELEMS)
+            {
+                hash_value(elem, kind, depth - 1, state);
+            }
+        },
+        Value::PVector(ref vec) => hash_ptr(vec.root_ptr(), state),
+        // map entries come in hash order, so equal maps hash the same
+        Value::PMap(ref map) if kind == HashKind::Equal => map.len().hash(state),
+        Value::PMap(ref map) => hash_ptr(map.root_ptr(), state),
+        Value::Builtin(ref f) => hash_ptr(Rc::as_ptr(f), state),
+        Value::Lambda(ref f) => hash_ptr(Rc::as_ptr(f), state),
+        Value::Generic(ref f) => hash_ptr(Rc::as_ptr(f), state),
+        Value::Module(ref m) => hash_ptr(Rc::as_ptr(m), state),
+        Value::Port(ref p) => hash_ptr(Rc::as_ptr(p), state),
+        Value::Record(ref rec) if kind == HashKind::Equal => {
+            hash_ptr(Rc::as_ptr(&rec.rtype), state);
+            if depth > 0
+            {
+                for field in rec.fields.borrow().iter().take(HASH_ELEMS)
+                {
+                    hash_value(field, kind, depth - 1, state);
+                }
+            }
+        },
+        Value::Record(ref rec) => hash_ptr(Rc::as_ptr(rec), state),
+        Value::RecordType(ref t) => hash_ptr(Rc::as_ptr(t), state),
+        Value::HashTable(ref h) => hash_ptr(Rc::as_ptr(h), state),
+    }
+}
+
+// `#hasheq` tables compare keys with `eq?`, and `#hash` ones with `equal?`
+fn values_eq(a: &Value, b: &Value, kind: HashKind) -> bool
+{
+    match kind {
+        HashKind::Equal => is_equal(a, b),
+        HashKind::Eq => is_eq(a, b),
+    }
+}
+
+// hash of a value as a key of an `equal` table
+pub fn equal_hash(val: &Value) -> u64
+{
+    let mut state = DefaultHasher::new();
+    hash_value(val, HashKind::Equal, HASH_DEPTH, &mut state);
+    state.finish()
+}
+
+pub fn equal_keys(a: &Value, b: &Value) -> bool
+{
+    values_eq(a, b, HashKind::Equal)
+}
+
+// a value used as a key, hashed and compared according to the table kind
+struct HashKey(Value, HashKind);
+
+impl Hash for HashKey
+{
+    fn hash<H: Hasher>(&self, state: &mut H)
+    {
+        hash_value(&self.0, self.1, HASH_DEPTH, state);
+    }
+}
+
+impl PartialEq for HashKey
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        values_eq(&self.0, &other.0, self.1)
+    }
+}
+
+impl Eq for HashKey {}
+
+// a hash table that keeps its entries in a vector, so they are listed in a stable order
+pub struct HashTable
+{
+    kind: HashKind,
+    entries: Vec<(Value, Value)>,
+    index: HashMap<HashKey, usize>,
+}
+
+impl PartialEq for HashTable
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for HashTable
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:?} {:?}", self.kind, self.entries)
+    }
+}
+
+impl HashTable
+{
+    pub fn new(kind: HashKind) -> HashTable
+    {
+        HashTable{ kind, entries: Vec::new(), index: HashMap::new() }
+    }
+
+    pub fn kind(&self) -> HashKind
+    {
+        self.kind
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[(Value, Value)]
+    {
+        &self.entries
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value>
+    {
+        self.index.get(&HashKey(key.clone(), self.kind)).map(|&i| &self.entries[i].1)
+    }
+
+    pub fn insert(&mut self, key: Value, val: Value)
+    {
+        match self.index.get(&HashKey(key.clone(), self.kind)) {
+            Some(&i) => self.entries[i].1 = val,
+            None => {
+                self.index.insert(HashKey(key.clone(), self.kind), self.entries.len());
+                self.entries.push((key, val));
+            },
+        }
+    }
+
+    pub fn remove(&mut self, key: &Value) -> Option<Value>
+    {
+        let i = self.index.remove(&HashKey(key.clone(), self.kind))?;
+        let (_, val) = self.entries.swap_remove(i);
+        // the last entry took the place of the removed one
+        if i < self.entries.len()
+        {
+            self.index.insert(HashKey(self.entries[i].0.clone(), self.kind), i);
+        }
+        Some(val)
+    }
+
+    pub fn wrap(self) -> Value
+    {
+        Value::HashTable(Rc::new(RefCell::new(self)))
+    }
+}
+
+pub fn load_hashtable(env: &mut Scope)
+{
+    env.set_builtin("make-hash-table", true, |mut args, _| {
+        let kind = match args.pop_front() {
+            None => HashKind::Equal,
+            Some(Value::Symbol(ref s)) if **s == "equal" => HashKind::Equal,
+            Some(Value::Symbol(ref s)) if **s == "eq" => HashKind::Eq,
+            Some(Value::Symbol(s)) => return Err(InvalidHashKind(s)),
+            Some(other) => return Err(InvalidArgType("Symbol", other.type_name())),
+        };
+        Ok(HashTable::new(kind).wrap())
+    });
+
+    env.set_builtin("hash-ref", true, |mut args, _| {
+        let table = check_arg!(args, HashTable, 2, 0);
+        let key = check_arg!(args, 2, 1);
+        let found = table.borrow().get(&key).cloned();
+        found.or_else(|| args.pop_front()).ok_or_else(|| KeyNotFound(key.to_string()))
+    });
+
+    env.set_builtin("hash-set!", true, |mut args, _| {
+        let table = check_arg!(args, HashTable, 3, 0);
+        let key = check_arg!(args, 3, 1);
+        let val = check_arg!(args, 3, 2);
+        table.borrow_mut().insert(key, val);
+        Ok(Value::Nil)
+    });
+
+    env.set_builtin("hash-remove!", true, |mut args, _| {
+        let table = check_arg!(args, HashTable, 2, 0);
+        let key = check_arg!(args, 2, 1);
+        table.borrow_mut().remove(&key);
+        Ok(Value::Nil)
+    });
+
+    env.set_builtin("hash-contains?", true, |mut args, _| {
+        let table = check_arg!(args, HashTable, 2, 0);
+        let key = check_arg!(args, 2, 1);
+        let found = table.borrow().get(&key).is_some();
+        Ok(Value::Bool(found))
+    });
+
+    env.set_builtin("hash-count", true, |mut args, _| {
+        let table = check_arg!(args, HashTable, 1, 0);
+        let len = table.borrow().len();
+        Ok(Value::Number(len as f64))
+    });
+
+    env.set_builtin("hash-keys", true, |mut args, _| {
+        let table = check_arg!(args, HashTable, 1, 0);
+        let keys = table.borrow().entries.iter().map(|e| e.0.clone()).collect();
+        Ok(Value::List(keys))
+    });
+
+    env.set_builtin("hash-values", true, |mut args, _| {
+        let table = check_arg!(args, HashTable, 1, 0);
+        let vals = table.borrow().entries.iter().map(|e| e.1.clone()).collect();
+        Ok(Value::List(vals))
+    });
+
+    env.set_builtin("hash->alist", true, |mut args, _| {
+        let table = check_arg!(args, HashTable, 1, 0);
+        let pairs = table.borrow().entries.iter().map(|(k, v)| Value::List(List::pair(k.clone(), v.clone()))).collect();
+        Ok(Value::List(pairs))
+    });
+
+    env.set_builtin("hash-update!", true, |mut args, env| {
+        let table = check_arg!(args, HashTable, 3, 0);
+        let key = check_arg!(args, 3, 1);
+        let func = check_function!(args, 3, 2);
+        let found = table.borrow().get(&key).cloned();
+        let old = found.or_else(|| args.pop_front()).ok_or_else(|| KeyNotFound(key.to_string()))?;
+        let new = func.call(&old.wrap(), env, false)?;
+        table.borrow_mut().insert(key, new);
+        Ok(Value::Nil)
+    });
+
+    env.set_builtin("hash-for-each", true, |mut args, env| {
+        let table = check_arg!(args, HashTable, 2, 0);
+        let func = check_function!(args, 2, 1);
+        let entries = table.borrow().entries.clone();   // the function may modify the table
+        for (key, val) in entries
+        {
+            func.call(&List::cons(key, val.wrap()), env.clone(), false)?;
+        }
+        Ok(Value::Nil)
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use data::RuntimeError;
+    use scope::run;
+
+    fn check(code: &str, expected: &str)
+    {
+        match run(code) {
+            Ok(val) => assert_eq!(val.to_string(), expected, "{}", code),
+            Err(e) => panic!("{}: {}", code, e),
+        }
+    }
+
+    fn fails(code: &str) -> RuntimeError
+    {
+        run(code).err().unwrap_or_else(|| panic!("{} didn't fail", code))
+    }
+
+    #[test]
+    fn makes_tables_of_both_kinds()
+    {
+        check("(make-hash-table)", "#hash()");
+        check("(make-hash-table 'equal)", "#hash()");
+        check("(make-hash-table 'eq)", "#hasheq()");
+        assert!(matches!(fails("(make-hash-table 'eqv)"), InvalidHashKind(_)));
+        assert!(matches!(fails("(make-hash-table 1)"), InvalidArgType("Symbol", _)));
+    }
+
+    #[test]
+    fn reads_and_writes_entries()
+    {
+        let setup = "(let h (make-hash-table)) (hash-set! h 'a 1) (hash-set! h \"b\" 2) (hash-set! h '(c) 3) ";
+        check(&format!("{} (list (hash-ref h 'a) (hash-ref h \"b\") (hash-ref h (list 'c)))", setup), "(1 2 3)");
+        check(&format!("{} (hash-ref h 'z 0)", setup), "0");
+        check(&format!("{} (hash-ref h 'a 0)", setup), "1");
+        assert!(matches!(fails(&format!("{} (hash-ref h 'z)", setup)), KeyNotFound(_)));
+
+        // setting a key again replaces its value in place
+        check(&format!("{} (hash-set! h 'a 10) (list (hash-ref h 'a) (hash-count h) (hash-keys h))", setup),
+              "(10 3 (a \"b\" (c)))");
+        check(&format!("{} (list (hash-contains? h \"b\") (hash-contains? h 'b))", setup), "(#t #f)");
+    }
+
+    #[test]
+    fn removes_entries()
+    {
+        let setup = "(let h (make-hash-table)) (hash-set! h 'a 1) (hash-set! h 'b 2) (hash-set! h 'c 3) ";
+        // the entry moved into the place of the removed one can still be found
+        check(&format!("{} (hash-remove! h 'a) (list (hash->alist h) (hash-ref h 'c) (hash-count h))", setup),
+              "(((c . 3) (b . 2)) 3 2)");
+        check(&format!("{} (hash-remove! h 'c) (hash-remove! h 'z) (hash->alist h)", setup), "((a . 1) (b . 2))");
+        check(&format!("{} (hash-remove! h 'a) (hash-remove! h 'b) (hash-remove! h 'c) (hash-set! h 'd 4) h", setup),
+              "#hash((d . 4))");
+    }
+
+    #[test]
+    fn updates_entries()
+    {
+        let setup = "(let h (make-hash-table)) (hash-set! h 'a 1) ";
+        check(&format!("{} (hash-update! h 'a (lambda (x) (+ x 1))) (hash-ref h 'a)", setup), "2");
+        check(&format!("{} (hash-update! h 'b (lambda (x) (+ x 1)) 10) (hash-ref h 'b)", setup), "11");
+        assert!(matches!(fails(&format!("{} (hash-update! h 'b (lambda (x) x))", setup)), KeyNotFound(_)));
+    }
+
+    #[test]
+    fn iterates_in_insertion_order()
+    {
+        let setup = "(let h (make-hash-table)) (hash-set! h 'x 1) (hash-set! h 'y 2) (hash-set! h 'z 3) ";
+        check(&format!("{} (list (hash-keys h) (hash-values h) (hash->alist h) (hash-count h))", setup),
+              "((x y z) (1 2 3) ((x . 1) (y . 2) (z . 3)) 3)");
+        check(&format!("{} (let seen '()) (hash-for-each h (lambda (k v) (set seen (cons (list k v) seen)))) seen",
+                       setup), "((z 3) (y 2) (x 1))");
+        // the function can change the table while it runs
+        check(&format!("{} (hash-for-each h (lambda (k v) (hash-remove! h k))) (hash-count h)", setup), "0");
+        check("(hash-count (make-hash-table))", "0");
+    }
+
+    #[test]
+    fn reads_literals()
+    {
+        check("#hash((a . 1) (b . 2))", "#hash((a . 1) (b . 2))");
+        check("(hash-ref #hash((a . 1) (b . 2)) 'b)", "2");
+        check("(hash-ref #hash(((1 2) . x)) '(1 2))", "x");
+        check("(hash-ref #hasheq((1 . 2)) 1)", "2");
+        check("#hasheq((1 . 2))", "#hasheq((1 . 2))");
+    }
+
+    #[test]
+    fn eq_tables_compare_keys_with_eq()
+    {
+        let setup = "(let h (make-hash-table 'eq)) (let s \"k\") (hash-set! h s 1) (hash-set! h 'sym 2) (hash-set! h 0 3) ";
+        check(&format!("{} (list (hash-ref h s) (hash-ref h \"k\" #f))", setup), "(1 #f)");
+        check(&format!("{} (list (hash-ref h 'sym) (hash-ref h 0))", setup), "(2 3)");
+        // 0 and -0 are `eqv?` but not `eq?`
+        check(&format!("{} (hash-ref h -0 #f)", setup), "#f");
+    }
+
+    #[test]
+    fn long_keys_share_a_hash_but_stay_apart()
+    {
+        let (a, b) = ("'(0 1 2 3 4 5 6 7 8 9)", "'(0 1 2 3 4 5 6 7 8 10)");
+        assert_eq!(equal_hash(&run(a).unwrap()), equal_hash(&run(b).unwrap()));
+        check(&format!("(let h (make-hash-table)) (hash-set! h {} 'a) (hash-set! h {} 'b) \
+                        (list (hash-ref h {}) (hash-ref h {}) (hash-count h))", a, b, a, b), "(a b 2)");
+        let deep = |leaf| format!("'(((((({}))))))", leaf);
+        assert_eq!(equal_hash(&run(&deep(1)).unwrap()), equal_hash(&run(&deep(2)).unwrap()));
+        assert!(!equal_keys(&run(&deep(1)).unwrap(), &run(&deep(2)).unwrap()));
+    }
+}
@@ -14,7 +14,7 @@ impl PartialEq for Lambda
 {
     fn eq(&self, other: &Self) -> bool
     {
-        self as *const _ == other as *const _
+        std::ptr::eq(self, other)
     }
 }
 
@@ -31,7 +31,21 @@ impl Lambda
 {
     pub fn new(args: Vec<String>, code: VecDeque<Value>, env: RcScope) -> Lambda
     {
-        Lambda{ args: args, code: code, env: env }
+        Lambda{ args, code, env }
+    }
+
+    pub fn args(&self) -> &[String]
+    {
+        &self.args
+    }
+
+    // a string at the start of a body with more than one form is the docstring
+    pub fn doc(&self) -> Option<&str>
+    {
+        match self.code.front() {
+            Some(Value::String(s)) if self.code.len() > 1 => Some(s),
+            _ => None,
+        }
     }
 }
 
@@ -39,12 +53,12 @@ impl Function for Lambda
 {
     fn call(&self, args: &List, env: RcScope, do_eval: bool) -> Result<Value, RuntimeError>
     {
-        let vals = if do_eval { try!(args.eval(env)) } else { args.iter().collect() };
+        let vals = if do_eval { args.eval(env)? } else { args.iter().collect() };
         let (na, nv) = (self.args.len(), vals.len());
         if nv < na { return Err(RuntimeError::InvalidArgNum(na as u32, nv as u32)) }
 
         let mut local = Scope::local(self.env.clone());
-        for (name, val) in self.args.iter().zip(vals.into_iter())
+        for (name, val) in self.args.iter().zip(vals)
         {
             local.decl(name, val);
         }
@@ -53,7 +67,7 @@ impl Function for Lambda
         let mut last = Value::Nil;
         for val in &self.code
         {
-            last = try!(val.eval(wenv.clone()));
+            last = val.eval(wenv.clone())?;
         }
         Ok(last)
     }
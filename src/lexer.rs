@@ -1,29 +1,108 @@
 use std::str::Chars;
-use data::{Token, ParseError};
+use data::{Token, ParseError, char_from_name};
+use hashtable::HashKind;
 
-// take from the char after the \" to the next \"
-fn extract_string(input: &mut Chars) -> Result<String, ParseError>
+// the line and column, counting from 1, of a byte offset in `text`
+pub fn line_col(text: &str, offset: usize) -> (usize, usize)
 {
-    let mut buf = String::new();
-    let mut escape = false;
+    let done = &text[..offset];
+    let line_start = done.rfind('\n').map_or(0, |i| i + 1);
+    (done.matches('\n').count() + 1, done[line_start..].chars().count() + 1)
+}
+
+// an invalid escape that starts at byte `offset` of `text`, the line and column are only found here.
+// The rest of the string is skipped, so the tokens after it aren't read from inside the string
+fn invalid_escape(esc: &str, text: &str, offset: usize, input: &mut Chars) -> ParseError
+{
+    while let Some(chr) = input.next()
+    {
+        match chr {
+            '\\' => { input.next(); },
+            '"' => break,
+            _ => {},
+        }
+    }
+    let (line, col) = line_col(text, offset);
+    ParseError::InvalidEscape(esc.to_string(), line, col)
+}
+
+// reads the hex digits of a \xHH; or \u{XXXX} escape up to `end`
+fn extract_hex(input: &mut Chars, end: char) -> Option<char>
+{
+    let mut digits = String::new();
     for chr in input
     {
-        if escape
-        {
-            buf.push(match chr {
-                'n' => '\n',
-                't' => '\t',
-                other => other,
-            });
-            escape = false;
+        if chr == end { return u32::from_str_radix(&digits, 16).ok().and_then(::std::char::from_u32) }
+        if !chr.is_ascii_hexdigit() || digits.len() == 8 { return None }
+        digits.push(chr);
+    }
+    None
+}
+
+// skips the spaces and tabs at the start of a line
+fn skip_indent(input: &mut Chars)
+{
+    while let Some(' ') | Some('\t') = input.clone().next()
+    {
+        input.next();
+    }
+}
+
+// take from the char after the " to the next ", the position of a bad escape is found from `text`
+fn extract_string(input: &mut Chars, text: &str) -> Result<String, ParseError>
+{
+    let mut buf = String::new();
+    loop
+    {
+        let offset = text.len() - input.as_str().len();
+        match input.next() {
+            Some('\\') => {
+                let esc = match input.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('0') => '\0',
+                    Some('\\') => '\\',
+                    Some('"') => '"',
+                    Some('x') => match extract_hex(input, ';') {
+                        Some(chr) => chr,
+                        None => return Err(invalid_escape("\\x", text, offset, input)),
+                    },
+                    Some('u') => match input.next() {
+                        Some('{') => match extract_hex(input, '}') {
+                            Some(chr) => chr,
+                            None => return Err(invalid_escape("\\u", text, offset, input)),
+                        },
+                        _ => return Err(invalid_escape("\\u", text, offset, input)),
+                    },
+                    // line continuation, skips the newline and the indentation of the next line
+                    Some('\n') => { skip_indent(input); continue },
+                    Some('\r') if input.as_str().starts_with('\n') => {
+                        input.next();
+                        skip_indent(input);
+                        continue
+                    },
+                    Some(other) => return Err(invalid_escape(&format!("\\{}", other), text, offset, input)),
+                    None => return Err(ParseError::UnclosedString),
+                };
+                buf.push(esc);
+            },
+            Some('"') => return Ok(buf),
+            Some(other) => buf.push(other),
+            None => return Err(ParseError::UnclosedString),
         }
-        else
-        {
-            match chr {
-                '\\' => escape = true,
-                '"' => return Ok(buf),
-                other => buf.push(other),
-            }
+    }
+}
+
+// take a raw string after the #r", there are no escapes
+fn extract_raw_string(input: &mut Chars) -> Result<String, ParseError>
+{
+    let mut buf = String::new();
+    for chr in input
+    {
+        match chr {
+            '"' => return Ok(buf),
+            other => buf.push(other),
         }
     }
     Err(ParseError::UnclosedString)
@@ -36,54 +115,252 @@ fn extract_ident(input: &mut Chars, first: char) -> String
     for chr in input.clone()
     {
         match chr {
-            ' ' | '(' | ')' | '\n' | '\t' => break,
+            ' ' | '(' | ')' | '[' | ']' | '{' | '}' | ';' | '\n' | '\t' | '\r' => break,
             other => { buf.push(other); input.next(); },
         }
     }
     buf
 }
 
-// takes a single token from the input stream
-fn extract_token(input: &mut Chars) -> Token
+// parses the number syntax: an optional sign and digits with an optional fraction and exponent, or one
+// of +inf.0, -inf.0 and +nan.0. Unlike f64::from_str it doesn't take "inf", "nan" or "infinity"
+pub fn parse_number(s: &str) -> Option<f64>
+{
+    match s {
+        "+inf.0" => return Some(f64::INFINITY),
+        "-inf.0" => return Some(f64::NEG_INFINITY),
+        "+nan.0" => return Some(f64::NAN),
+        _ => {},
+    }
+    let digits = |s: &str| s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let body = s.strip_prefix(|c| c == '+' || c == '-').unwrap_or(s);
+    let int = digits(body);
+    let (frac, rest) = match body[int..].strip_prefix('.') {
+        Some(rest) => (digits(rest), &rest[digits(rest)..]),
+        None => (0, &body[int..]),
+    };
+    if int + frac == 0 { return None }
+    let valid = match rest.strip_prefix(|c| c == 'e' || c == 'E') {
+        Some(exp) => {
+            let exp = exp.strip_prefix(|c| c == '+' || c == '-').unwrap_or(exp);
+            !exp.is_empty() && digits(exp) == exp.len()
+        },
+        None => rest.is_empty(),
+    };
+    if valid { s.parse().ok() } else { None }
+}
+
+// take a character literal after the #\
+fn extract_char(input: &mut Chars) -> Result<char, ParseError>
 {
-    match input.skip_while(|c| *c == ' ' || *c == '\n' || *c == '\t').next() {
+    // the first char is always part of the literal, even if it's a separator
+    let first = input.next().ok_or_else(|| ParseError::InvalidChar(String::new()))?;
+    let name = extract_ident(input, first);
+    let mut chars = name.chars();
+    if let (Some(chr), None) = (chars.next(), chars.next())
+    {
+        return Ok(chr)
+    }
+    if let Some(chr) = char_from_name(&name)
+    {
+        return Ok(chr)
+    }
+    match name.strip_prefix('x') {
+        Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(::std::char::from_u32),
+        None => None,
+    }.ok_or(ParseError::InvalidChar(name))
+}
+
+// skips the whitespace, and also the newlines and comments when they aren't kept as trivia
+fn skip_space(input: &mut Chars, trivia: bool)
+{
+    loop
+    {
+        match input.clone().next() {
+            Some(' ') | Some('\t') | Some('\r') => {},
+            Some('\n') if !trivia => {},
+            Some(';') if !trivia => { extract_comment(input); continue },
+            _ => return,
+        }
+        input.next();
+    }
+}
+
+// take a comment from the ; to the end of the line
+fn extract_comment<'a>(input: &mut Chars<'a>) -> String
+{
+    let rest = input.as_str();
+    let len = rest.find('\n').unwrap_or(rest.len());
+    *input = rest[len..].chars();
+    rest[..len].trim_end().to_string()
+}
+
+// takes a single token from the input stream, which is the rest of `text`
+fn extract_token(input: &mut Chars, text: &str) -> Token
+{
+    skip_space(input, false);
+    match input.next() {
         Some(chr) => match chr {
             '(' => Token::Lparen,
             ')' => Token::Rparen,
+            '[' => Token::Lbracket,
+            ']' => Token::Rbracket,
+            '{' => Token::Lbrace,
+            '}' => Token::Rbrace,
             '\'' => Token::Quote,
-            '"' => match extract_string(input) {
+            '#' if input.clone().next() == Some('(') => { input.next(); Token::VectorStart },
+            '#' if input.clone().next() == Some('\\') => {
+                input.next();
+                match extract_char(input) {
+                    Ok(chr) => Token::Char(chr),
+                    Err(e) => Token::Error(e),
+                }
+            },
+            '#' if input.as_str().starts_with("r\"") => {
+                input.nth(1);
+                match extract_raw_string(input) {
+                    Ok(val) => Token::String(val),
+                    Err(e) => Token::Error(e),
+                }
+            },
+            '"' => match extract_string(input, text) {
                 Ok(val) => Token::String(val),
                 Err(e) => Token::Error(e),
             },
             '-' => {
                 let ident = extract_ident(input, '-');
-                match ident.parse() {
-                    Ok(val) => Token::Number(val),
-                    Err(_) => Token::Ident(ident),
+                match parse_number(&ident) {
+                    Some(val) => Token::Number(val),
+                    None => Token::Ident(ident),
                 }
             },
-            dig @ '0'...'9' => match extract_ident(input, dig).parse() {
-                Ok(val) => Token::Number(val),
-                Err(_) => Token::Error(ParseError::InvalidNumber),
+            dig @ '0'..='9' => match parse_number(&extract_ident(input, dig)) {
+                Some(val) => Token::Number(val),
+                None => Token::Error(ParseError::InvalidNumber),
+            },
+            other => match extract_ident(input, other) {
+                ref dot if dot == "." => Token::Dot,
+                ref inf if inf == "+inf.0" => Token::Number(f64::INFINITY),
+                ref nan if nan == "+nan.0" => Token::Number(f64::NAN),
+                ref hash if (hash == "#hash" || hash == "#hasheq") && input.clone().next() == Some('(') => {
+                    input.next();
+                    Token::HashTableStart(if hash == "#hash" { HashKind::Equal } else { HashKind::Eq })
+                },
+                ident => Token::Ident(ident),
             },
-            other => Token::Ident(extract_ident(input, other)),
         },
         None => Token::End,
     }
 }
 
+// a range of byte offsets in the source text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span
+{
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Clone)]
-pub struct Tokenizer<'a>(Chars<'a>);
+pub struct Tokenizer<'a>
+{
+    text: &'a str,
+    input: Chars<'a>,
+    trivia: bool,
+}
 
 impl<'a> Tokenizer<'a>
 {
-    pub fn new(text: &str) -> Tokenizer
+    pub fn new(text: &'a str) -> Tokenizer<'a>
+    {
+        Tokenizer{ text, input: text.chars(), trivia: false }
+    }
+
+    // a tokenizer that starts reading at a byte offset, positions are still counted from the start of `text`
+    pub fn at(text: &'a str, offset: usize) -> Tokenizer<'a>
+    {
+        Tokenizer{ text, input: text[offset..].chars(), trivia: false }
+    }
+
+    // a tokenizer that also returns the comments and newlines, for tools that rewrite the source
+    pub fn with_trivia(text: &'a str) -> Tokenizer<'a>
+    {
+        Tokenizer{ text, input: text.chars(), trivia: true }
+    }
+
+    // the byte offset of the next char
+    pub fn offset(&self) -> usize
     {
-        Tokenizer(text.chars())
+        self.text.len() - self.input.as_str().len()
     }
 
     pub fn next_token(&mut self) -> Token
     {
-        extract_token(&mut self.0)
+        self.next_spanned().0
+    }
+
+    // the next token and where it is in the text, leading whitespace excluded
+    pub fn next_spanned(&mut self) -> (Token, Span)
+    {
+        skip_space(&mut self.input, self.trivia);
+        let start = self.offset();
+        let tok = match self.input.clone().next() {
+            Some('\n') if self.trivia => { self.input.next(); Token::Newline },
+            Some(';') if self.trivia => Token::Comment(extract_comment(&mut self.input)),
+            _ => extract_token(&mut self.input, self.text),
+        };
+        (tok, Span{ start, end: self.offset() })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn escape_error(text: &str) -> ParseError
+    {
+        match Tokenizer::new(text).next_token() {
+            Token::Error(e) => e,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_escape_positions()
+    {
+        assert_eq!(escape_error("\"ab\\q\""), ParseError::InvalidEscape("\\q".to_string(), 1, 4));
+        assert_eq!(escape_error("\"a\nλ\\xZZ;\""), ParseError::InvalidEscape("\\x".to_string(), 2, 2));
+        assert_eq!(escape_error("\"\\\n  ok \\u{110000}\""), ParseError::InvalidEscape("\\u".to_string(), 2, 6));
+    }
+
+    #[test]
+    fn reads_long_strings()
+    {
+        // big enough to take minutes if every char looked its position up from the start
+        let body = "abc\\n".repeat(200_000);
+        let text = format!("\"{}\"", body);
+        match Tokenizer::new(&text).next_token() {
+            Token::String(s) => assert_eq!(s.len(), 800_000),
+            other => panic!("expected a string, got {:?}", other),
+        }
+    }
+
+    fn string(text: &str) -> String
+    {
+        match Tokenizer::new(text).next_token() {
+            Token::String(s) => s,
+            other => panic!("expected a string, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn continues_strings_on_the_next_line()
+    {
+        assert_eq!(string("\"ab\\\n    cd\""), "abcd");
+        assert_eq!(string("\"ab\\\r\n\t  cd\""), "abcd");
+        assert_eq!(string("\"ab\\\n\\\r\ncd\""), "abcd");
+        // a lone carriage return isn't a line end
+        assert_eq!(escape_error("\"ab\\\rcd\""), ParseError::InvalidEscape("\\\r".to_string(), 1, 4));
     }
 }
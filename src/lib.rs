@@ -1,8 +1,28 @@
+#[cfg(unix)]
+extern crate libc;
+extern crate unicode_segmentation;
+
 pub mod data;
 pub mod value;
 pub mod list;
 pub mod lexer;
 pub mod parser;
 pub mod scope;
+#[macro_use]
 pub mod builtins;
 pub mod lambda;
+pub mod equality;
+pub mod editor;
+pub mod loader;
+pub mod module;
+pub mod hashtable;
+pub mod persistent;
+pub mod strings;
+pub mod pretty;
+pub mod cst;
+pub mod format;
+pub mod reader;
+pub mod port;
+pub mod vfs;
+pub mod record;
+pub mod object;
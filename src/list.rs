@@ -1,4 +1,5 @@
 use std::rc::Rc;
+use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::iter::FromIterator;
 use data::{Value, List, Function, Cons, RuntimeError};
@@ -8,7 +9,13 @@ impl List
 {
     pub fn cons(car: Value, cdr: List) -> List
     {
-        List::Node(Rc::new(Cons{ car: car, cdr: cdr }))
+        List::pair(car, Value::List(cdr))
+    }
+
+    // a cons cell with any value in the cdr, can make an improper list
+    pub fn pair(car: Value, cdr: Value) -> List
+    {
+        List::Node(Rc::new(Cons{ car: RefCell::new(car), cdr: RefCell::new(cdr) }))
     }
 
     pub fn from_de_iter<I>(iter: I) -> List
@@ -17,23 +24,70 @@ impl List
         iter.rev().fold(List::End, |cdr, car| List::cons(car, cdr))
     }
 
+    // iterates the elements, an improper tail is skipped
     pub fn iter(&self) -> ListIter
     {
-        ListIter(self)
+        match *self {
+            List::Node(ref cons) => ListIter(Some(cons.clone())),
+            List::End => ListIter(None),
+        }
+    }
+
+    // the rest of the list, None at the end or on an improper tail
+    fn next_list(&self) -> Option<List>
+    {
+        match *self {
+            List::Node(ref cons) => match *cons.cdr.borrow() {
+                Value::List(ref next) => Some(next.clone()),
+                _ => None,
+            },
+            List::End => None,
+        }
+    }
+
+    // true if the list ends with (), false on improper or circular lists
+    pub fn is_proper(&self) -> bool
+    {
+        let (mut slow, mut fast) = (self.clone(), self.clone());
+        loop
+        {
+            for _ in 0..2
+            {
+                if let List::End = fast { return true }
+                fast = match fast.next_list() {
+                    Some(next) => next,
+                    None => return false,
+                };
+            }
+            slow = slow.next_list().unwrap_or(List::End);
+            if let (List::Node(a), List::Node(b)) = (&slow, &fast)
+            {
+                if Rc::ptr_eq(a, b) { return false }
+            }
+        }
     }
 
     pub fn eval(&self, env: RcScope) -> Result<VecDeque<Value>, RuntimeError>
     {
+        if !self.is_proper() { return Err(RuntimeError::ImproperList) }
         self.iter().map(|val| val.eval(env.clone())).collect()
     }
 
     pub fn call(&self, env: RcScope) -> Result<Value, RuntimeError>
     {
         match *self {
-            List::Node(ref cons) => match try!(cons.car.eval(env.clone())) {
-                Value::Builtin(ref func) => func.call(&cons.cdr, env, true),
-                Value::Lambda(ref func) => func.call(&cons.cdr, env, true),
-                other => Err(RuntimeError::InvalidCall(other.type_name())),
+            List::Node(ref cons) => {
+                let args = match *cons.cdr.borrow() {
+                    Value::List(ref args) if args.is_proper() => args.clone(),
+                    _ => return Err(RuntimeError::ImproperList),
+                };
+                let head = cons.car.borrow().clone();
+                match head.eval(env.clone())? {
+                    Value::Builtin(ref func) => func.call(&args, env, true),
+                    Value::Lambda(ref func) => func.call(&args, env, true),
+                    Value::Generic(ref func) => func.call(&args, env, true),
+                    other => Err(RuntimeError::InvalidCall(other.type_name())),
+                }
             },
             List::End => Ok(Value::Nil),
         }
@@ -50,20 +104,20 @@ impl FromIterator<Value> for List
 }
 
 #[derive(Clone)]
-pub struct ListIter<'a>(&'a List);
+pub struct ListIter(Option<Rc<Cons>>);
 
-impl<'a> Iterator for ListIter<'a>
+impl Iterator for ListIter
 {
     type Item = Value;
 
     fn next(&mut self) -> Option<Self::Item>
     {
-        match *self.0 {
-            List::Node(ref cons) => {
-                self.0 = &cons.cdr;
-                Some(cons.car.clone())
-            },
-            List::End => None,
-        }
+        let cons = self.0.take()?;
+        self.0 = match *cons.cdr.borrow() {
+            Value::List(List::Node(ref next)) => Some(next.clone()),
+            _ => None,
+        };
+        let car = cons.car.borrow().clone();
+        Some(car)
     }
 }
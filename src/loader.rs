@@ -0,0 +1,191 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use data::{Value, List, RuntimeError};
+use data::RuntimeError::*;
+use parser::Parser;
+use scope::{Scope, RcScope};
+use vfs::{Vfs, OsFs};
+
+// files that are loaded or in the process of loading
+#[derive(Default)]
+struct LoadState
+{
+    loaded: HashSet<PathBuf>,
+    loading: Vec<(PathBuf, PathBuf)>,   // the key of each file, and its path in the vfs
+}
+
+impl LoadState
+{
+    // the directory relative paths are resolved from
+    fn base_dir(&self) -> PathBuf
+    {
+        self.loading.last().and_then(|p| p.1.parent()).map_or_else(PathBuf::new, Path::to_path_buf)
+    }
+}
+
+fn with_ext(path: &Path) -> PathBuf
+{
+    let mut name = path.as_os_str().to_owned();
+    name.push(".lisp");
+    PathBuf::from(name)
+}
+
+// finds `name` inside one of `dirs`, trying with and without the .lisp extension
+fn resolve<I>(name: &str, dirs: I, vfs: &dyn Vfs) -> Option<PathBuf>
+    where I: IntoIterator<Item=PathBuf>
+{
+    dirs.into_iter()
+        .map(|dir| dir.join(name))
+        .flat_map(|path| vec![path.clone(), with_ext(&path)])
+        .find(|path| vfs.is_file(&path.display().to_string()).unwrap_or(false))
+}
+
+// the key of a file in the load state
+fn file_key(path: &Path, vfs: &dyn Vfs) -> PathBuf
+{
+    vfs.canonicalize(&path.display().to_string()).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn read_file(path: &str, vfs: &dyn Vfs) -> std::io::Result<String>
+{
+    let mut text = String::new();
+    vfs.open_read(path)?.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+fn search_path(env: &RcScope) -> Vec<PathBuf>
+{
+    match env.borrow().get("load-path") {
+        Some(Value::List(lst)) => lst.iter().filter_map(|val| match val {
+            Value::String(s) => Some(PathBuf::from(&*s)),
+            _ => None,
+        }).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn eval_file(path: &Path, env: RcScope, state: &Rc<RefCell<LoadState>>, vfs: &dyn Vfs) -> Result<Value, RuntimeError>
+{
+    let name = path.display().to_string();
+    let key = file_key(path, vfs);
+    if state.borrow().loading.iter().any(|p| p.0 == key)
+    {
+        return Err(LoadCycle(name))
+    }
+    let text = read_file(&name, vfs).map_err(|e| FileError(name.clone(), e.to_string()))?;
+    let vals = Parser::new(&text).parse().map_err(|e| InFile(name.clone(), Box::new(Syntax(e))))?;
+
+    state.borrow_mut().loading.push((key.clone(), path.to_path_buf()));
+    let mut res = Ok(Value::Nil);
+    for val in vals
+    {
+        res = val.eval(env.clone());
+        if res.is_err() { break }
+    }
+    let mut st = state.borrow_mut();
+    st.loading.pop();
+    if res.is_ok()
+    {
+        st.loaded.insert(key);
+    }
+    res.map_err(|e| match e {
+        Exit(code) => Exit(code),
+        e => InFile(name, Box::new(e)),
+    })
+}
+
+// loads a file through the `load` builtin of the environment
+pub fn load_file(path: &str, env: RcScope) -> Result<Value, RuntimeError>
+{
+    let form = vec![Value::Symbol(Rc::new("load".to_string())), Value::String(Rc::new(path.to_string()))];
+    List::from_de_iter(form.into_iter()).call(env)
+}
+
+pub fn load_loader(env: &mut Scope)
+{
+    let search: List = env::var_os("RLISP_PATH").map_or_else(Vec::new, |p| env::split_paths(&p).collect())
+        .into_iter()
+        .map(|dir| Value::String(Rc::new(dir.display().to_string())))
+        .collect();
+    env.set("load-path", Value::List(search));
+
+    load_sources(env, Rc::new(OsFs));
+}
+
+// the `load` and `require` builtins, that read the files through `vfs`
+pub fn load_sources(env: &mut Scope, vfs: Rc<dyn Vfs>)
+{
+    let state = Rc::new(RefCell::new(LoadState::default()));
+
+    let (st, fs) = (state.clone(), vfs.clone());
+    env.set_builtin("load", true, move |mut args, env| {
+        let name = check_arg!(args, String, 1, 0);
+        let path = st.borrow().base_dir().join(&*name);
+        eval_file(&path, env, &st, &*fs)
+    });
+
+    let st = state;
+    env.set_builtin("require", true, move |mut args, env| {
+        let name = check_arg!(args, String, 1, 0);
+        let dirs = Some(st.borrow().base_dir()).into_iter().chain(search_path(&env));
+        let path = resolve(&name, dirs, &*vfs).ok_or_else(|| ModuleNotFound(name.to_string()))?;
+        if st.borrow().loaded.contains(&file_key(&path, &*vfs))
+        {
+            return Ok(Value::Bool(false))
+        }
+        eval_file(&path, env, &st, &*vfs).map(|_| Value::Bool(true))
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::fs;
+    use vfs::{NoFs, RootedFs};
+
+    fn env_with(vfs: Rc<dyn Vfs>) -> RcScope
+    {
+        let env = Scope::global().wrap();
+        env.borrow_mut().load_stdlib();
+        env.borrow_mut().set_vfs(vfs);
+        env
+    }
+
+    #[test]
+    fn loads_through_the_vfs()
+    {
+        let root = env::temp_dir().join(format!("rlisp-loader-{}", std::process::id()));
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::write(root.join("lib/a.lisp"), "(load \"b.lisp\") (let a-val (+ b-val 1))").unwrap();
+        fs::write(root.join("lib/b.lisp"), "(let b-val 41)").unwrap();
+
+        let env = env_with(Rc::new(RootedFs::new(&root)));
+        assert_eq!(load_file("/lib/a.lisp", env.clone()).unwrap(), Value::Number(42.0));
+        env.borrow_mut().set("load-path", Value::List(Value::String(Rc::new("lib".to_string())).wrap()));
+        // b.lisp was already loaded by a.lisp
+        let req = Parser::new("(require \"b\")").parse_value().unwrap();
+        assert_eq!(req.eval(env.clone()).unwrap(), Value::Bool(false));
+        fs::write(root.join("lib/c.lisp"), "(let c-val 1)").unwrap();
+        let req = Parser::new("(require \"c\")").parse_value().unwrap();
+        assert_eq!(req.eval(env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(req.eval(env.clone()).unwrap(), Value::Bool(false));
+
+        // paths can't leave the root, even if the file exists
+        assert!(load_file("../outside.lisp", env.clone()).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn no_fs_disables_loading()
+    {
+        let env = env_with(Rc::new(NoFs));
+        assert!(matches!(load_file("Cargo.toml", env.clone()), Err(FileError(..))));
+        let req = Parser::new("(require \"Cargo.toml\")").parse_value().unwrap();
+        assert!(matches!(req.eval(env), Err(ModuleNotFound(_))));
+    }
+}
@@ -1,32 +1,731 @@
 extern crate rlisp;
 
+use std::io::{self, Write};
+use std::fmt::Display;
+use std::env;
+use std::process;
+use std::path::PathBuf;
+use std::time::Instant;
+use rlisp::data::{Value, ParseError, RuntimeError};
 use rlisp::parser::Parser;
-use rlisp::scope::Scope;
+use rlisp::loader::load_file;
+use rlisp::scope::{Scope, RcScope};
+use rlisp::value::eval_steps;
+use rlisp::pretty::{pretty, DEFAULT_WIDTH};
+use rlisp::editor::terminal_width;
+#[cfg(unix)]
+use rlisp::editor::{Editor, RawTerminal};
 
-fn main()
+const PROMPT: &str = "> ";
+const PROMPT_CONT: &str = ".. ";
+
+const HELP: &str = "Commands:
+  :load <file>    evaluate a file
+  :reload         evaluate the last loaded file again
+  :env            list the global bindings
+  :type <expr>    show the type of the result
+  :time <expr>    show the wall time and eval steps taken
+  :doc <name>     describe a binding
+  :reset          start over with a fresh global environment
+  :quit           exit the REPL
+  :help           show this list";
+
+const COMMANDS: &[&str] = &[
+    ":load", ":l", ":reload", ":r", ":env", ":type", ":t", ":time", ":doc", ":reset", ":quit", ":q", ":help", ":h", ":?",
+];
+
+// true if the input starts with a REPL command, and not with a symbol like the keyword `:before`
+fn is_command(text: &str) -> bool
+{
+    text.split_whitespace().next().is_some_and(|word| COMMANDS.contains(&word))
+}
+
+#[cfg(unix)]
+fn history_path() -> Option<PathBuf>
+{
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".rlisp_history"))
+}
+
+// why an evaluation stopped: an error to report, or a call to `exit`
+enum Stop
+{
+    Error(String),
+    Exit(i32),
+}
+
+impl From<RuntimeError> for Stop
+{
+    fn from(e: RuntimeError) -> Stop
+    {
+        match e {
+            RuntimeError::Exit(code) => Stop::Exit(code),
+            e => Stop::Error(e.to_string()),
+        }
+    }
+}
+
+impl From<ParseError> for Stop
+{
+    fn from(e: ParseError) -> Stop
+    {
+        Stop::Error(e.to_string())
+    }
+}
+
+// evaluates all the forms in `text`, stopping at the first error
+fn run_chunk(text: &str, env: &RcScope) -> Result<Value, Stop>
+{
+    let vals = Parser::new(text).parse()?;
+    let mut last = Value::Nil;
+    for val in vals
+    {
+        last = val.eval(env.clone())?;
+    }
+    Ok(last)
+}
+
+fn run_file(path: &str, env: &RcScope) -> Result<Value, Stop>
+{
+    Ok(load_file(path, env.clone())?)
+}
+
+// interpreter state kept across REPL inputs
+struct Session
+{
+    env: RcScope,
+    cmd_line: Vec<String>,
+    last_load: Option<String>,
+    out: Box<dyn Write>,
+}
+
+fn global_env(cmd_line: &[String]) -> RcScope
 {
-    let stdin = std::io::stdin();
     let env = Scope::global().wrap();
     env.borrow_mut().load_stdlib();
+    env.borrow_mut().set_command_line(cmd_line.to_vec());
+    env
+}
+
+impl Session
+{
+    // results, errors and command output go to `out`
+    fn new(cmd_line: Vec<String>, out: Box<dyn Write>) -> Session
+    {
+        Session{ env: global_env(&cmd_line), cmd_line, last_load: None, out }
+    }
+
+    fn print<T: Display>(&mut self, text: T)
+    {
+        let _ = writeln!(self.out, "{}", text);
+    }
+
+    // prints a REPL result, laid out in lines when it's wider than the terminal
+    fn print_result(&mut self, val: &Value)
+    {
+        let width = terminal_width().unwrap_or(DEFAULT_WIDTH);
+        let text = val.to_string();
+        if text.chars().count() > width
+        {
+            self.print(pretty(val, width));
+        }
+        else
+        {
+            self.print(text);
+        }
+    }
+
+    // closes the ports that scripts left open, so what they wrote to files isn't lost
+    fn close(&self) -> Result<(), Stop>
+    {
+        Ok(self.env.borrow().ports().close_all()?)
+    }
 
+    fn load(&mut self, path: &str) -> Result<Value, Stop>
+    {
+        self.last_load = Some(path.to_string());
+        run_file(path, &self.env)
+    }
+
+    // runs a REPL `:command`. Returns the exit status if the REPL should end
+    fn command(&mut self, line: &str) -> Option<i32>
+    {
+        let line = line.trim();
+        let (cmd, arg) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+
+        let res = match cmd {
+            ":load" | ":l" if !arg.is_empty() => self.load(arg).map(|_| ()),
+            ":reload" | ":r" => match self.last_load.clone() {
+                Some(path) => self.load(&path).map(|_| ()),
+                None => Err(Stop::Error("No file loaded yet".to_string())),
+            },
+            ":env" => {
+                let mut names = self.env.borrow().names();
+                names.sort();
+                names.dedup();
+                for name in names
+                {
+                    let val = self.env.borrow().get(&name).unwrap_or(Value::Nil);
+                    self.print(format!("{} = {}", name, val));
+                }
+                Ok(())
+            },
+            ":type" | ":t" if !arg.is_empty() => run_chunk(arg, &self.env).map(|v| self.print(v.type_name())),
+            ":time" if !arg.is_empty() => {
+                let (start, steps) = (Instant::now(), eval_steps());
+                let res = run_chunk(arg, &self.env);
+                let (elapsed, steps) = (start.elapsed(), eval_steps() - steps);
+                res.map(|v| {
+                    self.print_result(&v);
+                    self.print(format!("; {:.3} ms, {} eval steps", elapsed.as_secs_f64() * 1000.0, steps));
+                })
+            },
+            ":doc" if !arg.is_empty() => {
+                let val = self.env.borrow().get(arg);
+                match val {
+                    Some(Value::Builtin(f)) => {
+                        self.print(format!("{}: builtin {}", arg, if f.do_eval { "function" } else { "special form" }));
+                        Ok(())
+                    },
+                    Some(Value::Lambda(f)) => {
+                        self.print(format!("({}{})", arg, f.args().iter().fold(String::new(), |a, n| a + " " + n)));
+                        if let Some(doc) = f.doc()
+                        {
+                            self.print(format!("  {}", doc));
+                        }
+                        Ok(())
+                    },
+                    Some(Value::Generic(f)) => {
+                        self.print(format!("{}: generic function with {} methods", arg, f.method_count()));
+                        Ok(())
+                    },
+                    Some(other) => { self.print(format!("{}: {} value", arg, other.type_name())); Ok(()) },
+                    None => Err(Stop::Error(format!("Unbound variable: {}", arg))),
+                }
+            },
+            ":reset" => {
+                self.env = global_env(&self.cmd_line);
+                Ok(())
+            },
+            ":quit" | ":q" => return Some(0),
+            ":help" | ":h" | ":?" => { self.print(HELP); Ok(()) },
+            _ => Err(Stop::Error(format!("Unknown command {}, try :help", line))),
+        };
+
+        match res {
+            Ok(()) => None,
+            Err(Stop::Error(e)) => { self.print(format!("Error: {}", e)); None },
+            Err(Stop::Exit(code)) => Some(code),
+        }
+    }
+}
+
+// reads lines through `read_line` and evaluates each complete form, then closes the open ports. Returns the
+// exit status
+fn repl<F>(mut sess: Session, read_line: F) -> i32
+    where F: FnMut(&str, &RcScope) -> io::Result<Option<String>>
+{
+    let code = repl_loop(&mut sess, read_line);
+    match sess.close() {
+        Ok(()) => code,
+        Err(Stop::Exit(code)) => code,
+        Err(Stop::Error(e)) => {
+            eprintln!("Error: {}", e);
+            1
+        },
+    }
+}
+
+fn repl_loop<F>(sess: &mut Session, mut read_line: F) -> i32
+    where F: FnMut(&str, &RcScope) -> io::Result<Option<String>>
+{
+    let mut text = String::new();
     loop
     {
-        let mut text = String::new();
-        match stdin.read_line(&mut text) {
-            Ok(0) => break,
-            Ok(_) => {},
-            Err(e) => panic!("Error reading input: {}", e),
+        let prompt = if text.is_empty() { PROMPT } else { PROMPT_CONT };
+        match read_line(prompt, &sess.env) {
+            Ok(Some(line)) => text.push_str(&line),
+            Ok(None) => return 0,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => { text.clear(); continue },
+            Err(e) => {
+                eprintln!("Error reading input: {}", e);
+                return 1
+            },
+        }
+
+        if is_command(&text)
+        {
+            if let Some(code) = sess.command(&text) { return code }
+            text.clear();
+            continue
         }
 
         match Parser::new(&text).parse() {
             Ok(vs) => for val in vs
             {
-                match val.eval(env.clone()) {
-                    Ok(v) => println!("Result: {}", v),
-                    Err(e) => println!("Error: {}", e),
+                match val.eval(sess.env.clone()) {
+                    Ok(v) => sess.print_result(&v),
+                    Err(RuntimeError::Exit(code)) => return code,
+                    Err(e) => sess.print(format!("Error: {}", e)),
                 }
             },
-            Err(e) => println!("Error: {}", e),
+            Err(ref e) if e.is_incomplete() => continue,   // wait for the rest of the form
+            Err(e) => sess.print(format!("Error: {}", e)),
         }
+        text.clear();
+    }
+}
+
+fn repl_plain(sess: Session) -> i32
+{
+    let stdin = io::stdin();
+    repl(sess, |prompt, _| {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        Ok(match stdin.read_line(&mut line)? {
+            0 => { println!(); None },
+            _ => Some(line),
+        })
+    })
+}
+
+#[cfg(unix)]
+fn repl_editor(sess: Session) -> Result<i32, Session>
+{
+    let term = match RawTerminal::new() {
+        Ok(term) => term,
+        Err(_) => return Err(sess),
+    };
+    let mut editor = Editor::new(term);
+    let hist_path = history_path();
+    if let Some(ref path) = hist_path
+    {
+        let _ = editor.load_history(path);
+    }
+
+    let code = repl(sess, |prompt, env| {
+        let line = editor.read_line(prompt, &*env.borrow())?;
+        if let Some(ref line) = line
+        {
+            editor.add_history(line);
+            if let Some(ref path) = hist_path
+            {
+                let _ = editor.save_history(path);
+            }
+        }
+        Ok(line.map(|l| l + "\n"))
+    });
+    Ok(code)
+}
+
+#[cfg(not(unix))]
+fn repl_editor(sess: Session) -> Result<i32, Session>
+{
+    Err(sess)
+}
+
+fn repl_start(sess: Session) -> i32
+{
+    // fall back to plain line reading when stdin isn't a terminal
+    repl_editor(sess).unwrap_or_else(repl_plain)
+}
+
+// reports an error to stderr and returns the exit status for it, 1 for errors or the status given to `exit`
+fn status(stop: Stop) -> i32
+{
+    match stop {
+        Stop::Exit(code) => code,
+        Stop::Error(msg) => {
+            eprintln!("Error: {}", msg);
+            1
+        },
+    }
+}
+
+// exits with the status for `stop`, keeping what was already printed
+fn stop(stop: Stop) -> !
+{
+    let _ = io::stdout().flush();
+    process::exit(status(stop))
+}
+
+// evaluates the -e expressions, then the script
+fn run_args(sess: &mut Session, exprs: &[String], script: Option<&String>) -> Result<(), Stop>
+{
+    for expr in exprs
+    {
+        run_chunk(expr, &sess.env)?;
+    }
+    if let Some(path) = script
+    {
+        sess.load(path)?;
+    }
+    Ok(())
+}
+
+const USAGE: &str = "Usage: rlisp [options] [script [args...]]
+
+Options:
+  -e <expr>  evaluate expression (can be repeated)
+  -i         enter the REPL after running the script or expressions
+  -h         show this help";
+
+// what the command line asks for
+#[derive(Debug, PartialEq)]
+struct Options
+{
+    exprs: Vec<String>,
+    interactive: bool,
+    script: Option<String>,
+    cmd_line: Vec<String>,
+}
+
+impl Options
+{
+    // the REPL runs with -i, or when there's nothing else to run
+    fn wants_repl(&self) -> bool
+    {
+        self.interactive || (self.script.is_none() && self.exprs.is_empty())
+    }
+}
+
+// parses the program name and arguments. Returns None for -h, or the message for a usage error
+fn parse_args<I>(mut args: I) -> Result<Option<Options>, String>
+    where I: Iterator<Item = String>
+{
+    let prog = args.next().unwrap_or_else(|| "rlisp".to_string());
+    let mut exprs = Vec::new();
+    let mut interactive = false;
+    let mut script = None;
+
+    while let Some(arg) = args.next()
+    {
+        match &arg[..] {
+            "-e" => match args.next() {
+                Some(expr) => exprs.push(expr),
+                None => return Err("option -e requires an argument".to_string()),
+            },
+            "-i" => interactive = true,
+            "-h" | "--help" => return Ok(None),
+            "--" => { script = args.next(); break },
+            opt if opt.starts_with('-') && opt.len() > 1 => return Err(format!("unknown option {}", opt)),
+            _ => { script = Some(arg); break },
+        }
+    }
+
+    let cmd_line = match script {
+        Some(ref path) => Some(path.clone()).into_iter().chain(args).collect(),
+        None => vec![prog],
+    };
+    Ok(Some(Options{ exprs, interactive, script, cmd_line }))
+}
+
+fn main()
+{
+    let opts = match parse_args(env::args()) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => { println!("{}", USAGE); return },
+        Err(msg) => { eprintln!("rlisp: {}\n{}", msg, USAGE); process::exit(2) },
+    };
+    let mut sess = Session::new(opts.cmd_line.clone(), Box::new(io::stdout()));
+
+    if let Err(e) = run_args(&mut sess, &opts.exprs, opts.script.as_ref())
+    {
+        let _ = sess.close();
+        stop(e)
+    }
+    if opts.wants_repl()
+    {
+        let code = repl_start(sess);
+        let _ = io::stdout().flush();
+        process::exit(code)
+    }
+    if let Err(e) = sess.close()
+    {
+        stop(e)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::fs;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    // an output buffer that the test keeps reading after the session took it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared
+    {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+        {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    // runs the REPL on `lines`, returning the exit status, the output and the prompts shown
+    fn script(lines: &[&str]) -> (i32, String, Vec<String>)
+    {
+        let out = Shared::default();
+        let sess = Session::new(vec![], Box::new(out.clone()));
+        let mut lines = lines.iter();
+        let mut prompts = Vec::new();
+        let code = repl(sess, |prompt, _| {
+            prompts.push(prompt.to_string());
+            Ok(lines.next().map(|line| line.to_string() + "\n"))
+        });
+        let text = String::from_utf8(out.0.borrow().clone()).unwrap();
+        (code, text, prompts)
+    }
+
+    // opens a file and writes to it without closing it. The lambda makes a cycle between the global scope
+    // and itself, so the port is never dropped, and flushed, by itself
+    fn unclosed_write(name: &str) -> (PathBuf, Vec<String>)
+    {
+        let path = env::temp_dir().join(format!("rlisp-main-{}-{}", process::id(), name));
+        let exprs = vec![
+            "(set f (lambda (x) x))".to_string(),
+            format!("(set p (open-output-file {:?}))", path.display().to_string()),
+            "(display \"hi\" p)".to_string(),
+        ];
+        (path, exprs)
+    }
+
+    #[test]
+    fn knows_the_commands()
+    {
+        assert!(is_command(":load file.lisp\n"));
+        assert!(is_command(":q"));
+        assert!(!is_command(":before\n"));
+        assert!(!is_command("(list :after)"));
+        assert!(!is_command(":loader"));
+    }
+
+    #[test]
+    fn closes_files_after_the_args()
+    {
+        let (path, exprs) = unclosed_write("args");
+        let mut sess = Session::new(vec![], Box::new(io::sink()));
+        assert!(run_args(&mut sess, &exprs, None).is_ok());
+        assert!(sess.close().is_ok());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hi");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn closes_files_after_the_repl()
+    {
+        let (path, exprs) = unclosed_write("repl");
+        let mut sess = Session::new(vec![], Box::new(io::sink()));
+        assert!(run_args(&mut sess, &exprs, None).is_ok());
+        assert_eq!(repl(sess, |_, _| Ok(None)), 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hi");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn continues_incomplete_forms()
+    {
+        let (code, out, prompts) = script(&["(let f (lambda (x)", "  (* x 2)))", "(f 21)"]);
+        assert_eq!(code, 0);
+        assert!(out.ends_with("42\n"), "{}", out);
+        assert_eq!(prompts, [PROMPT, PROMPT_CONT, PROMPT, PROMPT]);
+
+        let (_, out, prompts) = script(&["(string-length \"a", "b\")"]);
+        assert_eq!(out, "3\n");
+        assert_eq!(prompts, [PROMPT, PROMPT_CONT, PROMPT]);
+    }
+
+    #[test]
+    fn reports_errors_and_goes_on()
+    {
+        let (code, out, _) = script(&["(car 1 2 3", ")", "(+ 1 2) (car)", "1 )"]);
+        assert_eq!(code, 0);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", out);
+        assert!(lines[0].starts_with("Error: "));
+        assert_eq!(lines[1], "3");
+        assert!(lines[2].starts_with("Error: "));
+        assert!(lines[3].starts_with("Error: "));
+    }
+
+    #[test]
+    fn interrupt_drops_the_partial_form()
+    {
+        let out = Shared::default();
+        let sess = Session::new(vec![], Box::new(out.clone()));
+        let mut reads = 0;
+        let code = repl(sess, |prompt, _| {
+            reads += 1;
+            match reads {
+                1 => Ok(Some("(+ 1\n".to_string())),
+                2 => { assert_eq!(prompt, PROMPT_CONT); Err(io::Error::new(io::ErrorKind::Interrupted, "^C")) },
+                3 => { assert_eq!(prompt, PROMPT); Ok(Some("5\n".to_string())) },
+                _ => Ok(None),
+            }
+        });
+        assert_eq!(code, 0);
+        assert_eq!(out.0.borrow().as_slice(), b"5\n");
+    }
+
+    #[test]
+    fn runs_the_commands()
+    {
+        let (code, out, _) = script(&[
+            ":help",
+            ":type 1",
+            ":t \"s\"",
+            ":time (+ 1 2)",
+            ":doc car",
+            "(let twice (lambda (x) \"doubles x\" (* x 2)))",
+            ":doc twice",
+            "(let answer 42)",
+            ":doc answer",
+            ":doc nothing",
+            ":env",
+            ":type",
+            ":h",
+            ":?",
+        ]);
+        assert_eq!(code, 0);
+        let mut lines = out.lines();
+        let mut next = || lines.next().unwrap().to_string();
+        for line in HELP.lines()
+        {
+            assert_eq!(next(), line);
+        }
+        assert_eq!(next(), "Number");
+        assert_eq!(next(), "String");
+        assert_eq!(next(), "3");
+        assert!(next().ends_with(" eval steps"));
+        assert_eq!(next(), "car: builtin function");
+        next();
+        assert_eq!(next(), "(twice x)");
+        assert_eq!(next(), "  doubles x");
+        next();
+        assert_eq!(next(), "answer: Number value");
+        assert_eq!(next(), "Error: Unbound variable: nothing");
+        assert!(out.lines().any(|l| l == "answer = 42"));
+        assert!(out.lines().any(|l| l == "Error: Unknown command :type, try :help"));
+        assert_eq!(out.matches(HELP).count(), 3);
+    }
+
+    #[test]
+    fn loads_reloads_and_resets()
+    {
+        let path = env::temp_dir().join(format!("rlisp-main-{}-load.lisp", process::id()));
+        let load = format!(":load {}", path.display());
+        fs::write(&path, "(let loaded 1)").unwrap();
+
+        let out = Shared::default();
+        let sess = Session::new(vec![], Box::new(out.clone()));
+        let mut lines = vec![":reload", &load[..], "loaded", "(let other 5)", "!rewrite", ":r", "loaded", ":reset",
+                             "other", "loaded", ":reload", "loaded", ":quit", "never read"].into_iter();
+        let code = repl(sess, |_, _| {
+            Ok(match lines.next() {
+                Some("!rewrite") => { fs::write(&path, "(let loaded 2)").unwrap(); Some("\n".to_string()) },
+                line => line.map(|l| l.to_string() + "\n"),
+            })
+        });
+        fs::remove_file(&path).unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(lines.next(), Some("never read"));
+        let text = String::from_utf8(out.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, ["Error: No file loaded yet", "1", "5", "2",
+                           "Error: Unbound variable: other", "Error: Unbound variable: loaded", "2"]);
+    }
+
+    #[test]
+    fn exit_ends_the_repl_with_its_status()
+    {
+        assert_eq!(script(&["(exit 3)", "(exit 4)"]).0, 3);
+        assert_eq!(script(&[":q", "(exit 4)"]).0, 0);
+        assert_eq!(script(&[":time (exit 5)"]).0, 5);
+        assert_eq!(script(&["(car)"]).0, 0);
+    }
+
+    fn args(args: &[&str]) -> Result<Option<Options>, String>
+    {
+        parse_args(Some("rlisp").into_iter().chain(args.iter().cloned()).map(String::from))
+    }
+
+    fn strings(strs: &[&str]) -> Vec<String>
+    {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_the_arguments()
+    {
+        let opts = args(&[]).unwrap().unwrap();
+        assert_eq!(opts, Options{ exprs: vec![], interactive: false, script: None, cmd_line: strings(&["rlisp"]) });
+        assert!(opts.wants_repl());
+
+        let opts = args(&["-e", "(f)", "-e", "2", "run.lisp", "-e", "x"]).unwrap().unwrap();
+        assert_eq!(opts.exprs, strings(&["(f)", "2"]));
+        assert_eq!(opts.script, Some("run.lisp".to_string()));
+        assert_eq!(opts.cmd_line, strings(&["run.lisp", "-e", "x"]));
+        assert!(!opts.wants_repl());
+
+        let opts = args(&["-e", "1"]).unwrap().unwrap();
+        assert!(!opts.wants_repl());
+        assert!(args(&["-i", "-e", "1"]).unwrap().unwrap().wants_repl());
+        assert!(args(&["-i", "run.lisp"]).unwrap().unwrap().wants_repl());
+
+        let opts = args(&["--", "-i", "a"]).unwrap().unwrap();
+        assert!(!opts.interactive);
+        assert_eq!(opts.script, Some("-i".to_string()));
+        assert_eq!(opts.cmd_line, strings(&["-i", "a"]));
+        assert_eq!(args(&["--"]).unwrap().unwrap().script, None);
+
+        assert_eq!(args(&["-h", "-x"]), Ok(None));
+        assert_eq!(args(&["--help"]), Ok(None));
+        assert_eq!(args(&["-x"]), Err("unknown option -x".to_string()));
+        assert_eq!(args(&["-e"]), Err("option -e requires an argument".to_string()));
+        assert_eq!(args(&["-"]).unwrap().unwrap().script, Some("-".to_string()));
+    }
+
+    #[test]
+    fn exit_status_follows_the_args()
+    {
+        let run = |exprs: &[&str], script: Option<&str>| {
+            let mut sess = Session::new(vec![], Box::new(io::sink()));
+            let res = run_args(&mut sess, &strings(exprs), script.map(String::from).as_ref());
+            (res.err().map_or(0, status), sess)
+        };
+        assert_eq!(run(&["(+ 1 2)"], None).0, 0);
+        assert_eq!(run(&["(exit 7)", "(exit 8)"], None).0, 7);
+        assert_eq!(run(&["(exit)"], None).0, 0);
+        assert_eq!(run(&["(car)"], None).0, 1);
+        assert_eq!(run(&["(+ 1"], None).0, 1);
+        assert_eq!(run(&[], Some("/nonexistent/script.lisp")).0, 1);
+
+        // the expressions stop at the first error
+        let (code, sess) = run(&["(let a 1)", "(car)", "(let b 2)"], None);
+        assert_eq!(code, 1);
+        assert!(sess.env.borrow().get("a").is_some());
+        assert!(sess.env.borrow().get("b").is_none());
+    }
+
+    #[test]
+    fn scripts_see_their_arguments()
+    {
+        let opts = args(&["run.lisp", "x", "y"]).unwrap().unwrap();
+        let mut sess = Session::new(opts.cmd_line, Box::new(io::sink()));
+        let code = run_args(&mut sess, &strings(&["(exit (if (equal? (command-line) '(\"run.lisp\" \"x\" \"y\")) 0 9))"]), None)
+            .err().map_or(0, status);
+        assert_eq!(code, 0);
     }
 }
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::rc::Rc;
+use std::collections::VecDeque;
+use data::{Value, List, RuntimeError};
+use data::RuntimeError::*;
+use scope::{Scope, RcScope};
+
+pub struct Module
+{
+    pub name: String,
+    env: RcScope,
+    exports: Vec<String>,
+}
+
+impl PartialEq for Module
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Module
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "module {} exports: {:?}", self.name, self.exports)
+    }
+}
+
+impl Module
+{
+    pub fn exports(&self) -> &[String]
+    {
+        &self.exports
+    }
+
+    // gets the value of an exported name
+    pub fn get(&self, name: &str) -> Result<Value, RuntimeError>
+    {
+        if !self.exports.iter().any(|e| e == name)
+        {
+            return Err(NotExported(self.name.clone(), name.to_string()))
+        }
+        self.env.borrow().get(name).ok_or_else(|| UnkSymbol(Rc::new(name.to_string())))
+    }
+}
+
+// resolves a `module/name` reference
+pub fn get_qualified(env: &Scope, name: &str) -> Option<Result<Value, RuntimeError>>
+{
+    let sep = name.find('/')?;
+    let (module, member) = (&name[..sep], &name[sep + 1..]);
+    if module.is_empty() || member.is_empty() { return None }
+    match env.get(module) {
+        Some(Value::Module(m)) => Some(m.get(member)),
+        _ => None,
+    }
+}
+
+fn symbol_names(lst: List) -> Result<Vec<String>, RuntimeError>
+{
+    lst.iter().map(|val| match val {
+        Value::Symbol(s) => Ok((*s).clone()),
+        other => Err(InvalidArgType("Symbol", other.type_name())),
+    }).collect()
+}
+
+// a module and the (binding name, exported name) pairs to import from it
+type ImportSet = (Rc<Module>, Vec<(String, String)>);
+
+fn import_set(spec: Value, env: &RcScope) -> Result<ImportSet, RuntimeError>
+{
+    let mut args: VecDeque<Value> = match spec {
+        Value::Symbol(name) => return match env.borrow().get(&name) {
+            Some(Value::Module(m)) => {
+                let names = m.exports.iter().map(|n| (n.clone(), n.clone())).collect();
+                Ok((m, names))
+            },
+            Some(other) => Err(InvalidArgType("Module", other.type_name())),
+            None => Err(UnkSymbol(name)),
+        },
+        Value::List(lst) => lst.iter().collect(),
+        other => return Err(InvalidArgType("List", other.type_name())),
+    };
+
+    let kind = check_arg!(args, Symbol, 2, 0);
+    let (module, mut names) = import_set(check_arg!(args, 2, 1), env)?;
+    match &kind[..] {
+        "only" => {
+            let only = symbol_names(args.into_iter().collect())?;
+            if let Some(n) = only.iter().find(|n| !names.iter().any(|(b, _)| b == *n))
+            {
+                return Err(NotExported(module.name.clone(), n.clone()))
+            }
+            names.retain(|(b, _)| only.contains(b));
+        },
+        "except" => {
+            let except = symbol_names(args.into_iter().collect())?;
+            names.retain(|(b, _)| !except.contains(b));
+        },
+        "prefix" => {
+            let prefix = check_arg!(args, Symbol, 2, 1);
+            for (b, _) in &mut names
+            {
+                b.insert_str(0, &prefix);
+            }
+        },
+        "rename" => for pair in args
+        {
+            let pair = symbol_names(map_value!(pair, List, |l| l)?)?;
+            if pair.len() != 2 { return Err(InvalidArgNum(2, pair.len() as u32)) }
+            match names.iter_mut().find(|(b, _)| *b == pair[0]) {
+                Some(entry) => entry.0 = pair[1].clone(),
+                None => return Err(NotExported(module.name.clone(), pair[0].clone())),
+            }
+        },
+        _ => return Err(InvalidImport((*kind).clone())),
+    }
+    Ok((module, names))
+}
+
+pub fn load_modules(env: &mut Scope)
+{
+    env.set_builtin("module", false, |mut args, env| {
+        let name = check_arg!(args, Symbol, 2, 0);
+        let exports = match args.front() {
+            Some(Value::List(List::Node(cons))) if matches!(*cons.car.borrow(), Value::Symbol(ref s) if **s == "export") =>
+                Some(symbol_names(map_value!(cons.cdr.borrow().clone(), List, |l| l)?)?),
+            _ => None,
+        };
+        if exports.is_some() { args.pop_front(); }
+        let exports = exports.unwrap_or_default();
+
+        let local = Scope::module(Scope::root(&env)).wrap();
+        for val in args
+        {
+            val.eval(local.clone())?;
+        }
+        if let Some(n) = exports.iter().find(|n| local.borrow().get(n).is_none())
+        {
+            return Err(UnkSymbol(Rc::new(n.clone())))
+        }
+
+        let module = Value::Module(Rc::new(Module{ name: (*name).clone(), env: local, exports }));
+        env.borrow_mut().decl(&name, module.clone());
+        Ok(module)
+    });
+
+    env.set_builtin("import", false, |args, env| {
+        for spec in args
+        {
+            let (module, names) = import_set(spec, &env)?;
+            for (binding, name) in names
+            {
+                let val = module.get(&name)?;
+                env.borrow_mut().decl(&binding, val);
+            }
+        }
+        Ok(Value::Nil)
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use scope::run_in as run;
+
+    #[test]
+    fn module_bindings_stay_in_the_module()
+    {
+        let env = Scope::global().wrap();
+        env.borrow_mut().load_stdlib();
+        run("(let counter 0)", &env).unwrap();
+        run("(module m (export get) (set hidden 5) (set counter 1) (let get (lambda () hidden)))", &env).unwrap();
+
+        // a new name set in the body is private, an existing global one is updated
+        assert!(matches!(run("hidden", &env), Err(UnkSymbol(_))));
+        assert_eq!(run("counter", &env).unwrap(), Value::Number(1.0));
+        assert_eq!(run("(m/get)", &env).unwrap(), Value::Number(5.0));
+
+        // and so is a name set by a function of the module
+        run("(module n (export init) (let init (lambda () (set state 2))))", &env).unwrap();
+        run("(n/init)", &env).unwrap();
+        assert!(matches!(run("state", &env), Err(UnkSymbol(_))));
+    }
+}
@@ -0,0 +1,544 @@
+use std::fmt;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::hash::{Hash, Hasher};
+use std::collections::{HashMap, VecDeque};
+use data::{Value, List, Function, RuntimeError};
+use data::RuntimeError::*;
+use builtins::builtin_value;
+use lambda::Lambda;
+use record::{Record, RecordType, type_label};
+use scope::{Scope, RcScope};
+
+// what a method parameter accepts
+#[derive(Debug)]
+enum Specializer
+{
+    Class(Rc<RecordType>),  // records of the class or its subclasses
+    Type(String),           // values with this `typeof` name
+    Any,
+}
+
+impl PartialEq for Specializer
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        match (self, other) {
+            (Specializer::Class(a), Specializer::Class(b)) => Rc::ptr_eq(a, b),
+            (Specializer::Type(a), Specializer::Type(b)) => a == b,
+            (Specializer::Any, Specializer::Any) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Specializer
+{
+    // how close the match is, 0 being the most specific, or None if the value doesn't match
+    fn rank(&self, val: &Value) -> Option<usize>
+    {
+        match (self, val) {
+            (Specializer::Class(class), Value::Record(rec)) if Rc::ptr_eq(&rec.rtype, class) => Some(0),
+            (Specializer::Class(class), Value::Record(rec)) =>
+                rec.rtype.ancestors.iter().position(|t| Rc::ptr_eq(t, class)).map(|i| i + 1),
+            (Specializer::Class(_), _) => None,
+            // a type name is less specific than any class of a record
+            (Specializer::Type(name), Value::Record(rec)) if *name == rec.rtype.name || name == "Record" =>
+                Some(rec.rtype.ancestors.len() + 1),
+            (Specializer::Type(name), _) if *name == type_label(val) => Some(0),
+            (Specializer::Type(_), _) => None,
+            (Specializer::Any, _) => Some(usize::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Qualifier
+{
+    Primary,
+    Before,
+    After,
+}
+
+struct Method
+{
+    qualifier: Qualifier,
+    specs: Vec<Specializer>,
+    params: Vec<String>,
+    code: VecDeque<Value>,
+    env: RcScope,
+}
+
+// the type of an argument as far as dispatch is concerned
+enum TypeKey
+{
+    Record(Rc<RecordType>),
+    Builtin(&'static str),
+}
+
+impl PartialEq for TypeKey
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        match (self, other) {
+            (TypeKey::Record(a), TypeKey::Record(b)) => Rc::ptr_eq(a, b),
+            (TypeKey::Builtin(a), TypeKey::Builtin(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for TypeKey {}
+
+impl Hash for TypeKey
+{
+    fn hash<H: Hasher>(&self, state: &mut H)
+    {
+        match *self {
+            TypeKey::Record(ref t) => (Rc::as_ptr(t) as usize).hash(state),
+            TypeKey::Builtin(name) => name.hash(state),
+        }
+    }
+}
+
+fn type_key(val: &Value) -> TypeKey
+{
+    match *val {
+        Value::Record(ref rec) => TypeKey::Record(rec.rtype.clone()),
+        ref other => TypeKey::Builtin(other.type_name()),
+    }
+}
+
+// the applicable methods for some argument types, in the order they run
+struct Effective
+{
+    before: Vec<Rc<Method>>,
+    primary: Rc<Vec<Rc<Method>>>,
+    after: Vec<Rc<Method>>,
+}
+
+// a function made of methods, that runs the ones that match the types of all the arguments
+pub struct Generic
+{
+    pub name: String,
+    methods: RefCell<Vec<Rc<Method>>>,
+    cache: RefCell<HashMap<Vec<TypeKey>, Rc<Effective>>>,
+}
+
+impl PartialEq for Generic
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Generic
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "generic {} with {} methods", self.name, self.methods.borrow().len())
+    }
+}
+
+impl Generic
+{
+    fn new(name: String) -> Generic
+    {
+        Generic{ name, methods: RefCell::new(Vec::new()), cache: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn method_count(&self) -> usize
+    {
+        self.methods.borrow().len()
+    }
+
+    // adds a method, replacing the one with the same qualifier and specializers
+    fn add_method(&self, method: Method)
+    {
+        let mut methods = self.methods.borrow_mut();
+        methods.retain(|m| m.qualifier != method.qualifier || m.specs != method.specs);
+        methods.push(Rc::new(method));
+        self.cache.borrow_mut().clear();
+    }
+
+    fn effective(&self, args: &[Value]) -> Rc<Effective>
+    {
+        let key: Vec<TypeKey> = args.iter().map(type_key).collect();
+        if let Some(eff) = self.cache.borrow().get(&key)
+        {
+            return eff.clone()
+        }
+
+        // the ranks of each argument, compared in order, sort the methods from the most specific
+        let mut ranked: Vec<(Vec<usize>, Rc<Method>)> = self.methods.borrow().iter()
+            .filter(|m| m.specs.len() == args.len())
+            .filter_map(|m| {
+                let ranks = m.specs.iter().zip(args).map(|(s, a)| s.rank(a)).collect::<Option<Vec<_>>>()?;
+                Some((ranks, m.clone()))
+            })
+            .collect();
+        ranked.sort_by(|a, b| a.0.cmp(&b.0));
+        let of_kind = |q| ranked.iter().filter(|m| m.1.qualifier == q).map(|m| m.1.clone()).collect::<Vec<_>>();
+
+        let mut after = of_kind(Qualifier::After);
+        after.reverse();
+        let eff = Rc::new(Effective{ before: of_kind(Qualifier::Before), primary: Rc::new(of_kind(Qualifier::Primary)), after });
+        self.cache.borrow_mut().insert(key, eff.clone());
+        eff
+    }
+}
+
+fn run_method(method: &Method, env: RcScope, args: Vec<Value>, local: Scope) -> Result<Value, RuntimeError>
+{
+    let lambda = Lambda::new(method.params.clone(), method.code.clone(), local.wrap());
+    lambda.call(&args.into_iter().collect(), env, false)
+}
+
+// calls the primary method `i`, with `call-next-method` bound to the ones after it
+fn call_primary(name: Rc<String>, methods: Rc<Vec<Rc<Method>>>, i: usize, args: Vec<Value>, env: RcScope)
+    -> Result<Value, RuntimeError>
+{
+    let method = methods[i].clone();
+    let mut local = Scope::local(method.env.clone());
+    let has_next = i + 1 < methods.len();
+
+    let (n, ms, a) = (name.clone(), methods.clone(), args.clone());
+    // without arguments, the next method gets the same ones
+    local.decl("call-next-method", builtin_value("call-next-method", move |next_args, env| {
+        if !has_next { return Err(NoNextMethod((*n).clone())) }
+        let next_args = if next_args.is_empty() { a.clone() } else { next_args.into_iter().collect() };
+        call_primary(n.clone(), ms.clone(), i + 1, next_args, env)
+    }));
+    local.decl("next-method?", builtin_value("next-method?", move |_, _| Ok(Value::Bool(has_next))));
+
+    run_method(&method, env, args, local)
+}
+
+impl Function for Generic
+{
+    fn call(&self, args: &List, env: RcScope, do_eval: bool) -> Result<Value, RuntimeError>
+    {
+        let vals: Vec<Value> = if do_eval { args.eval(env.clone())?.into_iter().collect() } else { args.iter().collect() };
+        let eff = self.effective(&vals);
+        if eff.primary.is_empty()
+        {
+            return Err(NoApplicableMethod(self.name.clone(), vals.iter().map(type_label).collect()))
+        }
+
+        for method in &eff.before
+        {
+            run_method(method, env.clone(), vals.clone(), Scope::local(method.env.clone()))?;
+        }
+        let res = call_primary(Rc::new(self.name.clone()), eff.primary.clone(), 0, vals.clone(), env.clone())?;
+        for method in &eff.after
+        {
+            run_method(method, env.clone(), vals.clone(), Scope::local(method.env.clone()))?;
+        }
+        Ok(res)
+    }
+}
+
+fn class_arg(val: Value) -> Result<Rc<RecordType>, RuntimeError>
+{
+    match val {
+        Value::RecordType(t) => Ok(t),
+        other => Err(InvalidArgType("RecordType", other.type_name())),
+    }
+}
+
+fn symbol_name(val: &Value, form: &'static str, what: &str) -> Result<String, RuntimeError>
+{
+    match *val {
+        Value::Symbol(ref s) => Ok((**s).clone()),
+        ref other => Err(InvalidDefinition(form, format!("expected a symbol for the {}, but found {}", what, other))),
+    }
+}
+
+// the superclasses of a class with the given direct ones, depth first from left to right. A class
+// reached more than once goes in its last position, so it's after all its subclasses
+fn ancestors(supers: &[Rc<RecordType>]) -> Vec<Rc<RecordType>>
+{
+    let mut all: Vec<Rc<RecordType>> = Vec::new();
+    for class in supers
+    {
+        all.push(class.clone());
+        all.extend(class.ancestors.iter().cloned());
+    }
+    let mut res: Vec<Rc<RecordType>> = Vec::new();
+    for (i, class) in all.iter().enumerate()
+    {
+        if !all[i + 1..].iter().any(|c| Rc::ptr_eq(c, class))
+        {
+            res.push(class.clone());
+        }
+    }
+    res
+}
+
+// (defclass name (supers...) (slot | (slot default)...))
+fn defclass(mut args: VecDeque<Value>, env: RcScope) -> Result<Value, RuntimeError>
+{
+    let name = symbol_name(&check_arg!(args, 3, 0), "defclass", "class name")?;
+    let supers = match check_arg!(args, 3, 1) {
+        Value::List(lst) => lst.iter().map(|val| class_arg(val.eval(env.clone())?)).collect::<Result<Vec<_>, _>>()?,
+        other => return Err(InvalidDefinition("defclass", format!("expected a list of superclasses, but found {}", other))),
+    };
+    let slots = match check_arg!(args, 3, 2) {
+        Value::List(lst) => lst,
+        other => return Err(InvalidDefinition("defclass", format!("expected a list of slots, but found {}", other))),
+    };
+
+    // the inherited slots go first, the defaults of a subclass replace the ones of its superclasses
+    let mut fields: Vec<String> = Vec::new();
+    let mut defaults: Vec<Value> = Vec::new();
+    let mut add_field = |name: &str, default: Value| match fields.iter().position(|n| n == name) {
+        Some(i) => defaults[i] = default,
+        None => { fields.push(name.to_string()); defaults.push(default); },
+    };
+    for class in supers.iter().rev()
+    {
+        for (field, default) in class.fields.iter().zip(&class.defaults)
+        {
+            add_field(field, default.clone());
+        }
+    }
+    for slot in slots.iter()
+    {
+        match slot {
+            Value::Symbol(ref s) => add_field(s, Value::Nil),
+            Value::List(List::Node(ref cons)) => {
+                let mut spec: VecDeque<Value> = List::Node(cons.clone()).iter().collect();
+                let field = symbol_name(&check_arg!(spec, 2, 0), "defclass", "slot name")?;
+                let default = match spec.pop_front() {
+                    Some(expr) => expr.eval(env.clone())?,
+                    None => Value::Nil,
+                };
+                add_field(&field, default);
+            },
+            other => return Err(InvalidDefinition("defclass", format!("invalid slot {}", other))),
+        }
+    }
+
+    let class = Rc::new(RecordType{ name: name.clone(), fields, defaults, ancestors: ancestors(&supers) });
+    let val = Value::RecordType(class);
+    env.borrow_mut().decl(&name, val.clone());
+    Ok(val)
+}
+
+// the generic function bound to `name`, that is made if there's none
+fn generic_named(name: &str, env: &RcScope) -> Rc<Generic>
+{
+    if let Some(Value::Generic(gf)) = env.borrow().get(name)
+    {
+        return gf
+    }
+    let gf = Rc::new(Generic::new(name.to_string()));
+    env.borrow_mut().decl(name, Value::Generic(gf.clone()));
+    gf
+}
+
+// (defmethod name [:before | :after] (param | (param type)...) body...)
+fn defmethod(mut args: VecDeque<Value>, env: RcScope) -> Result<Value, RuntimeError>
+{
+    let name = symbol_name(&check_arg!(args, 2, 0), "defmethod", "method name")?;
+    let qualifier = match args.front() {
+        Some(Value::Symbol(s)) if &s[..] == ":before" => Qualifier::Before,
+        Some(Value::Symbol(s)) if &s[..] == ":after" => Qualifier::After,
+        Some(Value::Symbol(s)) => return Err(InvalidDefinition("defmethod", format!("unknown qualifier {}", s))),
+        _ => Qualifier::Primary,
+    };
+    if qualifier != Qualifier::Primary { args.pop_front(); }
+
+    let params = match check_arg!(args, 2, 1) {
+        Value::List(lst) => lst,
+        other => return Err(InvalidDefinition("defmethod", format!("expected a parameter list, but found {}", other))),
+    };
+    let mut names = Vec::new();
+    let mut specs = Vec::new();
+    for param in params.iter()
+    {
+        match param {
+            Value::Symbol(ref s) => { names.push((**s).clone()); specs.push(Specializer::Any) },
+            Value::List(ref lst) => {
+                let pair: Vec<Value> = lst.iter().collect();
+                if pair.len() != 2
+                {
+                    return Err(InvalidDefinition("defmethod", format!("invalid parameter {}", lst)))
+                }
+                names.push(symbol_name(&pair[0], "defmethod", "parameter")?);
+                let type_name = symbol_name(&pair[1], "defmethod", "parameter type")?;
+                // a name bound to a class is that class, any other is a `typeof` name
+                specs.push(match env.borrow().get(&type_name) {
+                    Some(Value::RecordType(class)) => Specializer::Class(class),
+                    _ => Specializer::Type(type_name),
+                });
+            },
+            other => return Err(InvalidDefinition("defmethod", format!("invalid parameter {}", other))),
+        }
+    }
+
+    let gf = generic_named(&name, &env);
+    gf.add_method(Method{ qualifier, specs, params: names, code: args, env });
+    Ok(Value::Generic(gf))
+}
+
+fn slot_index(rec: &Record, slot: &str) -> Result<usize, RuntimeError>
+{
+    rec.rtype.field_index(slot).ok_or_else(|| NoSuchSlot(rec.rtype.name.clone(), slot.to_string()))
+}
+
+pub fn load_objects(env: &mut Scope)
+{
+    env.set_builtin("defclass", false, defclass);
+
+    env.set_builtin("defmethod", false, defmethod);
+
+    // (defgeneric name [params]) makes an empty generic function, or returns the one already bound to the
+    // name with its methods. The parameters are only documentation
+    env.set_builtin("defgeneric", false, |mut args, env| {
+        let name = symbol_name(&check_arg!(args, 1, 0), "defgeneric", "name")?;
+        Ok(Value::Generic(generic_named(&name, &env)))
+    });
+
+    // (make-instance class 'slot value...)
+    env.set_builtin("make-instance", true, |mut args, _| {
+        let class = class_arg(check_arg!(args, 1, 0))?;
+        let rec = Record::new(class);
+        while let Some(slot) = args.pop_front()
+        {
+            let slot = map_value!(slot, Symbol, |s| s)?;
+            let i = slot_index(&rec, &slot)?;
+            let val = args.pop_front().ok_or_else(|| MissingSlotValue(rec.rtype.name.clone(), (*slot).clone()))?;
+            rec.fields.borrow_mut()[i] = val;
+        }
+        Ok(Value::Record(Rc::new(rec)))
+    });
+
+    env.set_builtin("slot-ref", true, |mut args, _| {
+        let rec = check_arg!(args, Record, 2, 0);
+        let slot = check_arg!(args, Symbol, 2, 1);
+        let val = rec.fields.borrow()[slot_index(&rec, &slot)?].clone();
+        Ok(val)
+    });
+
+    env.set_builtin("slot-set!", true, |mut args, _| {
+        let rec = check_arg!(args, Record, 3, 0);
+        let slot = check_arg!(args, Symbol, 3, 1);
+        let i = slot_index(&rec, &slot)?;
+        rec.fields.borrow_mut()[i] = check_arg!(args, 3, 2);
+        Ok(Value::Nil)
+    });
+
+    // true if a value is a record of the class or one of its subclasses
+    env.set_builtin("is-a?", true, |mut args, _| {
+        let val = check_arg!(args, 2, 0);
+        let class = class_arg(check_arg!(args, 2, 1))?;
+        Ok(Value::Bool(matches!(val, Value::Record(ref rec) if rec.rtype.is_a(&class))))
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use scope;
+
+    const SHAPES: &str = "
+        (defclass shape () ((color 'black)))
+        (defclass circle (shape) (r))
+        (defclass ring (circle) (inner))
+        (let trace '())
+        (let log (lambda (x) (set trace (cons x trace))))";
+
+    fn run(code: &str) -> Result<Value, RuntimeError>
+    {
+        scope::run(&format!("{}\n{}", SHAPES, code))
+    }
+
+    fn check(code: &str, expected: &str)
+    {
+        assert_eq!(run(code).unwrap().to_string(), expected, "{}", code);
+    }
+
+    #[test]
+    fn dispatches_to_the_most_specific()
+    {
+        let methods = "
+            (defmethod name ((s shape)) 'shape)
+            (defmethod name ((c circle)) 'circle)
+            (defmethod name ((n Number)) 'number)
+            (defmethod name (x) 'anything)";
+        check(&format!("{} (name (make-instance ring))", methods), "circle");
+        check(&format!("{} (name (make-instance shape))", methods), "shape");
+        check(&format!("{} (name 3)", methods), "number");
+        check(&format!("{} (name \"s\")", methods), "anything");
+
+        // the arguments are compared from left to right
+        let pairs = "
+            (defmethod meet ((a circle) (b shape)) 'circle-shape)
+            (defmethod meet ((a shape) (b ring)) 'shape-ring)";
+        check(&format!("{} (meet (make-instance ring) (make-instance ring))", pairs), "circle-shape");
+        check(&format!("{} (meet (make-instance shape) (make-instance ring))", pairs), "shape-ring");
+        assert!(matches!(run(&format!("{} (meet 1 2)", pairs)), Err(NoApplicableMethod(..))));
+    }
+
+    #[test]
+    fn calls_the_next_methods()
+    {
+        let methods = "
+            (defmethod describe ((s shape)) (list 'shape (next-method?)))
+            (defmethod describe ((c circle)) (cons 'circle (call-next-method)))
+            (defmethod describe ((r ring)) (cons 'ring (call-next-method)))";
+        check(&format!("{} (describe (make-instance ring))", methods), "(ring circle shape #f)");
+        check(&format!("{} (describe (make-instance circle))", methods), "(circle shape #f)");
+
+        // the next method can be given other arguments
+        let scale = "
+            (defmethod scale ((n Number)) (* n 10))
+            (defmethod scale (x) x)
+            (defmethod scale ((n Number)) (+ 1 (call-next-method (* n 2))))";
+        check(&format!("{} (scale 5)", scale), "11");
+        let last = "(defmethod only ((s shape)) (call-next-method)) (only (make-instance shape))";
+        assert!(matches!(run(last), Err(NoNextMethod(_))));
+    }
+
+    #[test]
+    fn runs_before_and_after_methods_around_the_primary()
+    {
+        let methods = "
+            (defmethod draw ((s shape)) (log 'shape) 'drawn)
+            (defmethod draw ((c circle)) (log 'circle) (call-next-method))
+            (defmethod draw :before ((s shape)) (log 'before-shape))
+            (defmethod draw :before ((c circle)) (log 'before-circle))
+            (defmethod draw :after ((s shape)) (log 'after-shape))
+            (defmethod draw :after ((c circle)) (log 'after-circle))";
+        // the trace is newest first: the :before methods run from the most specific, the :after ones from the least
+        check(&format!("{} (list (draw (make-instance ring)) trace)", methods),
+            "(drawn (after-circle after-shape shape circle before-shape before-circle))");
+        // the results of the :before and :after methods are dropped
+        check(&format!("{} (draw (make-instance shape))", methods), "drawn");
+        // there must be a primary method
+        let only_before = "(defmethod paint :before ((s shape)) 1) (paint (make-instance shape))";
+        assert!(matches!(run(only_before), Err(NoApplicableMethod(..))));
+    }
+
+    #[test]
+    fn defgeneric_keeps_the_methods()
+    {
+        check("(defmethod area ((c circle)) 3) (defgeneric area (s)) (area (make-instance circle))", "3");
+        let code = "(defgeneric perimeter (s)) (defmethod perimeter ((c circle)) 6) (perimeter (make-instance circle))";
+        check(code, "6");
+    }
+
+    #[test]
+    fn names_the_slot_without_a_value()
+    {
+        check("(slot-ref (make-instance circle 'r 2) 'color)", "black");
+        match run("(make-instance circle 'r 2 'color)") {
+            Err(e @ MissingSlotValue(..)) => assert_eq!(e.to_string(), "Slot color of circle is missing its value"),
+            other => panic!("{:?}", other.map_err(|e| e.to_string())),
+        }
+        assert!(matches!(run("(make-instance circle 'size 2)"), Err(NoSuchSlot(..))));
+    }
+}
@@ -1,43 +1,143 @@
+use std::fmt;
 use std::rc::Rc;
 use std::mem;
+use std::cell::RefCell;
 use data::{Token, Value, List, ParseError};
-use lexer::Tokenizer;
+use lexer::{Tokenizer, Span, line_col};
+use hashtable::{HashTable, HashKind};
+use persistent::PMap;
+
+// the value of a number, char, symbol or string token
+pub fn atom_value(tok: Token) -> Value
+{
+    match tok {
+        Token::Number(val) => Value::Number(val),
+        Token::Char(val) => Value::Char(val),
+        Token::Ident(val) => match &val[..] {
+            "nil" => Value::Nil,
+            "#t" | "#true" => Value::Bool(true),
+            "#f" | "#false" => Value::Bool(false),
+            _ => Value::Symbol(Rc::new(val)),
+        },
+        Token::String(val) => Value::String(Rc::new(val)),
+        _ => unreachable!(),
+    }
+}
+
+// a hash table from its (key . value) pairs
+pub fn hashtable_value(kind: HashKind, vals: Vec<Value>) -> Result<Value, ParseError>
+{
+    let mut table = HashTable::new(kind);
+    for val in vals
+    {
+        match val {
+            Value::List(List::Node(cons)) => table.insert(cons.car.borrow().clone(), cons.cdr.borrow().clone()),
+            _ => return Err(ParseError::InvalidHashEntry),
+        }
+    }
+    Ok(table.wrap())
+}
+
+// a map from its alternating keys and values
+pub fn pmap_value(vals: Vec<Value>) -> Result<Value, ParseError>
+{
+    if !vals.len().is_multiple_of(2) { return Err(ParseError::OddMapLiteral) }
+    let mut map = PMap::new();
+    let mut iter = vals.into_iter();
+    while let (Some(key), Some(val)) = (iter.next(), iter.next())
+    {
+        map = map.insert(key, val);
+    }
+    Ok(Value::PMap(map))
+}
+
+// a parse error with where it happened, and a hint on how to fix it
+#[derive(Debug)]
+pub struct Diagnostic
+{
+    pub error: ParseError,
+    pub span: Span,
+    pub line: usize,
+    pub col: usize,
+    pub hint: Option<String>,
+}
+
+impl fmt::Display for Diagnostic
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "line {}, column {}: {}", self.line, self.col, self.error)?;
+        match self.hint {
+            Some(ref hint) => write!(f, " ({})", hint),
+            None => Ok(()),
+        }
+    }
+}
+
+fn is_open(tok: &Token) -> bool
+{
+    matches!(*tok, Token::Lparen | Token::VectorStart | Token::HashTableStart(_) | Token::Lbracket | Token::Lbrace)
+}
 
 pub struct Parser<'a>
 {
+    text: &'a str,
     cur_tok: Token,
+    cur_span: Span,
+    last_span: Span,    // the span of the last consumed token
+    opens: Vec<(&'static str, Span)>,   // the delimiters of the sequences being parsed
     input: Tokenizer<'a>,
 }
 
 impl<'a> Parser<'a>
 {
-    pub fn new(text: &str) -> Parser
+    pub fn new(text: &'a str) -> Parser<'a>
     {
         let mut tokens = Tokenizer::new(text);
-        Parser{ cur_tok: tokens.next_token(), input: tokens }
+        let (cur_tok, cur_span) = tokens.next_spanned();
+        Parser{ text, cur_tok, cur_span, last_span: cur_span, opens: Vec::new(), input: tokens }
     }
 
     // consumes the current token and pulls a new one
     fn next_token(&mut self) -> Token
     {
-        mem::replace(&mut self.cur_tok, self.input.next_token())
+        let (tok, span) = self.input.next_spanned();
+        self.last_span = mem::replace(&mut self.cur_span, span);
+        mem::replace(&mut self.cur_tok, tok)
+    }
+
+    // parses a sequence after its opening delimiter
+    fn nested<F>(&mut self, open: &'static str, parse: F) -> Result<Value, ParseError>
+        where F: FnOnce(&mut Parser<'a>) -> Result<Value, ParseError>
+    {
+        self.opens.push((open, self.last_span));
+        let val = parse(self)?;
+        self.opens.pop();
+        Ok(val)
     }
 
     // parses one expression
     pub fn parse_value(&mut self) -> Result<Value, ParseError>
     {
         match self.next_token() {
-            Token::Lparen => self.parse_list(),
+            Token::Lparen => self.nested("(", Parser::parse_list),
+            Token::VectorStart => self.nested("#(", Parser::parse_vector),
+            Token::HashTableStart(kind) => self.nested("#hash(", |p| p.parse_hashtable(kind)),
+            Token::Lbracket => self.nested("[", Parser::parse_pvector),
+            Token::Lbrace => self.nested("{", Parser::parse_pmap),
             Token::Rparen => Err(ParseError::UnexpectedRparen),
+            Token::Rbracket => Err(ParseError::UnexpectedDelim(']')),
+            Token::Rbrace => Err(ParseError::UnexpectedDelim('}')),
+            Token::Dot => Err(ParseError::UnexpectedDot),
             Token::Quote => match self.parse_value() {
                 Ok(val) => Ok(val.quote()),
                 Err(ParseError::EndOfStream) => Err(ParseError::NoQuoteArg),
                 Err(e) => Err(e),
             },
-            Token::Number(val) => Ok(Value::Number(val)),
-            Token::Ident(val) => Ok(Value::Symbol(Rc::new(val))),
-            Token::String(val) => Ok(Value::String(Rc::new(val))),
+            tok @ Token::Number(_) | tok @ Token::Char(_) | tok @ Token::Ident(_) | tok @ Token::String(_) =>
+                Ok(atom_value(tok)),
             Token::Error(e) => Err(e),
+            Token::Comment(_) | Token::Newline => self.parse_value(),
             Token::End => Err(ParseError::EndOfStream),
         }
     }
@@ -46,16 +146,77 @@ impl<'a> Parser<'a>
     fn parse_list(&mut self) -> Result<Value, ParseError>
     {
         let mut list = Vec::new();
+        let mut tail = Value::List(List::End);
         while self.cur_tok != Token::Rparen
         {
-            if self.cur_tok == Token::End
-            {
-                return Err(ParseError::UnclosedList);
+            match self.cur_tok {
+                Token::End => return Err(ParseError::UnclosedList),
+                Token::Dot => {
+                    // a dot must have one element at each side, and end the list
+                    self.next_token();
+                    if list.is_empty() { return Err(ParseError::UnexpectedDot) }
+                    tail = match self.parse_value() {
+                        Err(ParseError::EndOfStream) => return Err(ParseError::UnclosedList),
+                        other => other?,
+                    };
+                    match self.cur_tok {
+                        Token::Rparen => break,
+                        Token::End => return Err(ParseError::UnclosedList),
+                        _ => return Err(ParseError::UnexpectedDot),
+                    }
+                },
+                _ => list.push(self.parse_value()?),
             }
-            list.push(try!(self.parse_value()));
         }
         self.next_token();  // consume the ')'
-        Ok(Value::List(List::from_de_iter(list.into_iter())))
+        Ok(list.into_iter().rev().fold(tail, |cdr, car| Value::List(List::pair(car, cdr))))
+    }
+
+    // parses the contents of a vector
+    fn parse_vector(&mut self) -> Result<Value, ParseError>
+    {
+        let mut vec = Vec::new();
+        while self.cur_tok != Token::Rparen
+        {
+            match self.cur_tok {
+                Token::End => return Err(ParseError::UnclosedList),
+                Token::Dot => return Err(ParseError::UnexpectedDot),
+                _ => vec.push(self.parse_value()?),
+            }
+        }
+        self.next_token();  // consume the ')'
+        Ok(Value::Vector(Rc::new(RefCell::new(vec))))
+    }
+
+    fn parse_hashtable(&mut self, kind: HashKind) -> Result<Value, ParseError>
+    {
+        self.parse_seq(Token::Rparen).and_then(|vals| hashtable_value(kind, vals))
+    }
+
+    // parses the elements of a sequence until the `close` token
+    fn parse_seq(&mut self, close: Token) -> Result<Vec<Value>, ParseError>
+    {
+        let mut vals = Vec::new();
+        while self.cur_tok != close
+        {
+            match self.cur_tok {
+                Token::End => return Err(ParseError::UnclosedList),
+                Token::Dot => return Err(ParseError::UnexpectedDot),
+                _ => vals.push(self.parse_value()?),
+            }
+        }
+        self.next_token();  // consume the closing token
+        Ok(vals)
+    }
+
+    fn parse_pvector(&mut self) -> Result<Value, ParseError>
+    {
+        self.parse_seq(Token::Rbracket).map(|vals| Value::PVector(vals.into_iter().collect()))
+    }
+
+    fn parse_pmap(&mut self) -> Result<Value, ParseError>
+    {
+        self.parse_seq(Token::Rbrace).and_then(pmap_value)
     }
 
     // parses the entire chunk
@@ -72,4 +233,203 @@ impl<'a> Parser<'a>
         }
         Ok(out)
     }
+
+    // restarts parsing at a byte offset of the text
+    fn seek(&mut self, offset: usize)
+    {
+        self.input = Tokenizer::at(self.text, offset);
+        let (tok, span) = self.input.next_spanned();
+        self.cur_tok = tok;
+        self.cur_span = span;
+        self.opens.clear();
+    }
+
+    // the offset of the next opening delimiter at the start of a line, where a top-level form likely begins
+    fn next_form_start(&self, from: usize) -> Option<usize>
+    {
+        let mut tokens = Tokenizer::at(self.text, from);
+        loop
+        {
+            match tokens.next_spanned() {
+                (Token::End, _) => return None,
+                (ref tok, span) if is_open(tok) && (span.start == 0 || self.text[..span.start].ends_with('\n')) =>
+                    return Some(span.start),
+                _ => {},
+            }
+        }
+    }
+
+    fn diagnostic(&self, error: ParseError, resume: Option<usize>) -> Diagnostic
+    {
+        let open_at = |&(open, span): &(&str, Span)| {
+            format!("'{}' opened at line {}", open, line_col(self.text, span.start).0)
+        };
+        let close_of = |open: &str| match open {
+            "[" => ']',
+            "{" => '}',
+            _ => ')',
+        };
+        let (span, hint) = match error {
+            ParseError::UnclosedList => {
+                let at = resume.unwrap_or(self.text.len());
+                (Span{ start: at, end: at }, self.opens.last().map(|o| format!("unclosed {}", open_at(o))))
+            },
+            ParseError::UnclosedString =>
+                (self.last_span, Some(format!("unclosed '\"' opened at line {}", line_col(self.text, self.last_span.start).0))),
+            ParseError::UnexpectedRparen | ParseError::UnexpectedDelim(_) => (self.last_span, match self.opens.last() {
+                Some(o) => Some(format!("expected '{}' to close the {}", close_of(o.0), open_at(o))),
+                None => Some("there's no open delimiter to close".to_string()),
+            }),
+            ParseError::UnexpectedDot => (self.last_span, Some("a dot goes before the last element of a list".to_string())),
+            ParseError::OddMapLiteral => (self.last_span, Some("a key is missing its value".to_string())),
+            ParseError::InvalidHashEntry => (self.last_span, Some("write the entries as (key . value)".to_string())),
+            ParseError::InvalidEscape(..) =>
+                (self.last_span, Some("the escapes are \\n \\t \\r \\0 \\\\ \\\" \\xHH; and \\u{HHHH}".to_string())),
+            _ => (self.last_span, None),
+        };
+        let (line, col) = line_col(self.text, span.start);
+        Diagnostic{ error, span, line, col, hint }
+    }
+
+    // parses the entire chunk, skipping over the forms with errors.
+    // Returns the forms that could be read, and a diagnostic for each error
+    pub fn parse_recover(mut self) -> (Vec<Value>, Vec<Diagnostic>)
+    {
+        let mut out = Vec::new();
+        let mut diags = Vec::new();
+        loop
+        {
+            let start = self.cur_span.start;
+            match self.parse_value() {
+                Ok(val) => out.push(val),
+                Err(ParseError::EndOfStream) => break,
+                Err(e) => {
+                    // a form that goes over the start of the next ones was likely left unclosed, so the error
+                    // is looked for again in them
+                    let outer = self.opens.first().map(|&(_, span)| span.start + 1);
+                    if let Some(at) = outer.and_then(|from| self.next_form_start(from)).filter(|&at| at < self.last_span.start)
+                    {
+                        self.opens.truncate(1);
+                        diags.push(self.diagnostic(ParseError::UnclosedList, Some(at)));
+                        self.seek(at);
+                        continue
+                    }
+                    // resynchronize at the next line that starts with a form, inside an unclosed one if needed
+                    let from = match e {
+                        ParseError::UnclosedList => start + 1,
+                        _ => self.last_span.end.max(start + 1),
+                    };
+                    let resume = self.next_form_start(from);
+                    diags.push(self.diagnostic(e, resume));
+                    match resume {
+                        Some(offset) => self.seek(offset),
+                        None => break,
+                    }
+                },
+            }
+        }
+        (out, diags)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn recover(text: &str) -> (Vec<String>, Vec<Diagnostic>)
+    {
+        let (vals, diags) = Parser::new(text).parse_recover();
+        (vals.iter().map(|v| v.to_string()).collect(), diags)
+    }
+
+    fn hint(diag: &Diagnostic) -> &str
+    {
+        diag.hint.as_ref().map_or("", |h| &h[..])
+    }
+
+    #[test]
+    fn recovers_from_unclosed_lists()
+    {
+        let (vals, diags) = recover("(define a (+ 1 2)\n(define b 3)\n(define c 4)\n");
+        assert_eq!(vals, ["(define b 3)", "(define c 4)"]);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].error, ParseError::UnclosedList);
+        assert_eq!((diags[0].line, diags[0].col), (2, 1));
+        assert_eq!(hint(&diags[0]), "unclosed '(' opened at line 1");
+
+        let (vals, diags) = recover("(a\n  (b\n(c)");
+        assert_eq!(vals, ["(c)"]);
+        assert_eq!(hint(&diags[0]), "unclosed '(' opened at line 1");
+    }
+
+    #[test]
+    fn recovers_from_stray_closers()
+    {
+        let (vals, diags) = recover("(a)\n)\n(b)\n");
+        assert_eq!(vals, ["(a)", "(b)"]);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].error, ParseError::UnexpectedRparen);
+        assert_eq!((diags[0].line, diags[0].col), (2, 1));
+        assert_eq!(hint(&diags[0]), "there's no open delimiter to close");
+
+        let (vals, diags) = recover("(a [1 2)\n(b)\n");
+        assert_eq!(vals, ["(b)"]);
+        assert_eq!((diags[0].line, diags[0].col), (1, 8));
+        assert_eq!(hint(&diags[0]), "expected ']' to close the '[' opened at line 1");
+    }
+
+    #[test]
+    fn recovers_from_bad_escapes()
+    {
+        let (vals, diags) = recover("(a \"x\\q\")\n(b \"\\u{zz}\" \"ok\")\n(c)");
+        assert_eq!(vals, ["(c)"]);
+        assert_eq!(diags.len(), 2);
+        assert_eq!(diags[0].error, ParseError::InvalidEscape("\\q".to_string(), 1, 6));
+        assert_eq!((diags[0].line, diags[0].col), (1, 4));
+        assert!(hint(&diags[0]).starts_with("the escapes are"));
+        assert_eq!(diags[1].error, ParseError::InvalidEscape("\\u".to_string(), 2, 5));
+    }
+
+    #[test]
+    fn recovery_makes_progress()
+    {
+        // every text of up to 5 of these chars ends, with at most one diagnostic per char
+        let alphabet = ['(', ')', '[', '"', '\\', 'q', ' ', '\n', 'a'];
+        let mut texts = vec![String::new()];
+        for _ in 0..5
+        {
+            texts = texts.iter().flat_map(|t| alphabet.iter().map(move |c| format!("{}{}", t, c))).collect();
+            for text in &texts
+            {
+                let (_, diags) = recover(text);
+                assert!(diags.len() <= text.len(), "{:?}", text);
+                assert!(diags.iter().all(|d| d.span.end <= text.len()), "{:?}", text);
+            }
+        }
+    }
+
+    fn read(text: &str) -> Result<String, ParseError>
+    {
+        Parser::new(text).parse().map(|vals| vals.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(" "))
+    }
+
+    #[test]
+    fn reads_dotted_pairs()
+    {
+        assert_eq!(read("(a . b)"), Ok("(a . b)".to_string()));
+        assert_eq!(read("(1 2 . 3)"), Ok("(1 2 . 3)".to_string()));
+        assert_eq!(read("((a . 1) (b . (2 3)))"), Ok("((a . 1) (b 2 3))".to_string()));
+        assert_eq!(read("(1 . (2 . (3 . ())))"), Ok("(1 2 3)".to_string()));
+        assert_eq!(read("(a .b)"), Ok("(a .b)".to_string()));
+
+        // a dot needs one element before it and exactly one after, inside a list
+        for text in &["(. a)", "(a . b c)", "(a . b . c)", "[1 . 2]", "{a . 1}"]
+        {
+            assert_eq!(read(text), Err(ParseError::UnexpectedDot), "{}", text);
+        }
+        assert_eq!(read("(a . )"), Err(ParseError::UnexpectedRparen));
+        assert_eq!(read("(a . "), Err(ParseError::UnclosedList));
+        assert_eq!(read("(a . b"), Err(ParseError::UnclosedList));
+    }
 }
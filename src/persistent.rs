@@ -0,0 +1,647 @@
+use std::rc::Rc;
+use data::{Value, List, Function};
+use data::RuntimeError::*;
+use hashtable::{equal_hash, equal_keys};
+use equality::is_equal;
+use builtins::{check_bound, check_index};
+use scope::Scope;
+
+const BITS: u32 = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: u64 = WIDTH as u64 - 1;
+
+// the expected type of the builtins that take either persistent collection
+const COLLECTION: &str = "PMap or PVector";
+
+// a node of the vector trie, the leaves are at level 0
+#[derive(Debug)]
+enum VecNode
+{
+    Branch(Vec<Rc<VecNode>>),
+    Leaf(Vec<Value>),
+}
+
+// an immutable vector stored as a 32-way trie, updates copy only the path to the changed leaf
+#[derive(Debug, Clone)]
+pub struct PVector
+{
+    len: usize,
+    shift: u32,
+    root: Rc<VecNode>,
+}
+
+impl PartialEq for PVector
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        self.len == other.len && (Rc::ptr_eq(&self.root, &other.root) || self.iter().zip(other.iter()).all(|(a, b)| is_equal(a, b)))
+    }
+}
+
+impl Default for PVector
+{
+    fn default() -> Self
+    {
+        PVector::new()
+    }
+}
+
+impl PVector
+{
+    pub fn new() -> PVector
+    {
+        PVector{ len: 0, shift: 0, root: Rc::new(VecNode::Leaf(Vec::new())) }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&Value>
+    {
+        if idx >= self.len { return None }
+        let mut node = &self.root;
+        let mut level = self.shift;
+        loop
+        {
+            match **node {
+                VecNode::Branch(ref children) => {
+                    node = &children[(idx >> level) & (WIDTH - 1)];
+                    level -= BITS;
+                },
+                VecNode::Leaf(ref vals) => return vals.get(idx & (WIDTH - 1)),
+            }
+        }
+    }
+
+    // a copy with the element at `idx` replaced, `idx` must be in range
+    pub fn set(&self, idx: usize, val: Value) -> PVector
+    {
+        fn set_in(node: &VecNode, level: u32, idx: usize, val: Value) -> VecNode
+        {
+            match *node {
+                VecNode::Branch(ref children) => {
+                    let mut children = children.clone();
+                    let i = (idx >> level) & (WIDTH - 1);
+                    children[i] = Rc::new(set_in(&children[i], level - BITS, idx, val));
+                    VecNode::Branch(children)
+                },
+                VecNode::Leaf(ref vals) => {
+                    let mut vals = vals.clone();
+                    vals[idx & (WIDTH - 1)] = val;
+                    VecNode::Leaf(vals)
+                },
+            }
+        }
+
+        assert!(idx < self.len);
+        PVector{ len: self.len, shift: self.shift, root: Rc::new(set_in(&self.root, self.shift, idx, val)) }
+    }
+
+    // a copy with `val` appended
+    pub fn push(&self, val: Value) -> PVector
+    {
+        fn new_path(level: u32, val: Value) -> VecNode
+        {
+            if level == 0 { VecNode::Leaf(vec![val]) } else { VecNode::Branch(vec![Rc::new(new_path(level - BITS, val))]) }
+        }
+
+        fn push_in(node: &VecNode, level: u32, idx: usize, val: Value) -> VecNode
+        {
+            match *node {
+                VecNode::Branch(ref children) => {
+                    let mut children = children.clone();
+                    let i = (idx >> level) & (WIDTH - 1);
+                    if i < children.len()
+                    {
+                        children[i] = Rc::new(push_in(&children[i], level - BITS, idx, val));
+                    }
+                    else
+                    {
+                        children.push(Rc::new(new_path(level - BITS, val)));
+                    }
+                    VecNode::Branch(children)
+                },
+                VecNode::Leaf(ref vals) => {
+                    let mut vals = vals.clone();
+                    vals.push(val);
+                    VecNode::Leaf(vals)
+                },
+            }
+        }
+
+        if self.len == WIDTH << self.shift
+        {
+            // the trie is full, grow a level
+            let root = VecNode::Branch(vec![self.root.clone(), Rc::new(new_path(self.shift, val))]);
+            PVector{ len: self.len + 1, shift: self.shift + BITS, root: Rc::new(root) }
+        }
+        else
+        {
+            PVector{ len: self.len + 1, shift: self.shift, root: Rc::new(push_in(&self.root, self.shift, self.len, val)) }
+        }
+    }
+
+    // identifies the trie, for `eq` comparisons
+    pub fn root_ptr(&self) -> *const ()
+    {
+        Rc::as_ptr(&self.root) as *const ()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Value>
+    {
+        (0..self.len).filter_map(move |i| self.get(i))
+    }
+}
+
+impl ::std::iter::FromIterator<Value> for PVector
+{
+    fn from_iter<T>(iterator: T) -> Self
+        where T: IntoIterator<Item=Value>
+    {
+        iterator.into_iter().fold(PVector::new(), |vec, val| vec.push(val))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum MapEntry
+{
+    Leaf(u64, Value, Value),
+    Node(Rc<MapNode>),
+}
+
+// a node of the hash trie, indexed by 5 bits of the key hash per level
+#[derive(Debug)]
+enum MapNode
+{
+    Bitmap(u32, Vec<MapEntry>),
+    Collision(u64, Vec<(Value, Value)>),    // keys with the same full hash
+}
+
+impl MapNode
+{
+    fn get(&self, shift: u32, hash: u64, key: &Value) -> Option<&Value>
+    {
+        match *self {
+            MapNode::Bitmap(bitmap, ref entries) => {
+                let bit = 1 << ((hash >> shift) & MASK);
+                if bitmap & bit == 0 { return None }
+                match entries[(bitmap & (bit - 1)).count_ones() as usize] {
+                    MapEntry::Leaf(_, ref k, ref v) => if equal_keys(k, key) { Some(v) } else { None },
+                    MapEntry::Node(ref node) => node.get(shift + BITS, hash, key),
+                }
+            },
+            MapNode::Collision(_, ref pairs) => pairs.iter().find(|p| equal_keys(&p.0, key)).map(|p| &p.1),
+        }
+    }
+
+    // a node holding two leaves with different keys
+    fn merge(shift: u32, a: MapEntry, hash_b: u64, key_b: Value, val_b: Value) -> MapNode
+    {
+        let hash_a = match a {
+            MapEntry::Leaf(h, _, _) => h,
+            MapEntry::Node(_) => unreachable!(),
+        };
+        if hash_a == hash_b
+        {
+            return match a {
+                MapEntry::Leaf(_, k, v) => MapNode::Collision(hash_a, vec![(k, v), (key_b, val_b)]),
+                MapEntry::Node(_) => unreachable!(),
+            }
+        }
+        let (ia, ib) = ((hash_a >> shift) & MASK, (hash_b >> shift) & MASK);
+        if ia == ib
+        {
+            let node = MapNode::merge(shift + BITS, a, hash_b, key_b, val_b);
+            MapNode::Bitmap(1 << ia, vec![MapEntry::Node(Rc::new(node))])
+        }
+        else
+        {
+            let b = MapEntry::Leaf(hash_b, key_b, val_b);
+            let entries = if ia < ib { vec![a, b] } else { vec![b, a] };
+            MapNode::Bitmap((1 << ia) | (1 << ib), entries)
+        }
+    }
+
+    // returns the new node, and true if the key wasn't there before
+    fn insert(&self, shift: u32, hash: u64, key: Value, val: Value) -> (MapNode, bool)
+    {
+        match *self {
+            MapNode::Bitmap(bitmap, ref entries) => {
+                let bit = 1 << ((hash >> shift) & MASK);
+                let idx = (bitmap & (bit - 1)).count_ones() as usize;
+                let mut entries = entries.clone();
+                if bitmap & bit == 0
+                {
+                    entries.insert(idx, MapEntry::Leaf(hash, key, val));
+                    return (MapNode::Bitmap(bitmap | bit, entries), true)
+                }
+                let (entry, added) = match entries[idx] {
+                    MapEntry::Leaf(h, ref k, _) if h == hash && equal_keys(k, &key) => (MapEntry::Leaf(hash, key, val), false),
+                    ref leaf @ MapEntry::Leaf(..) => {
+                        (MapEntry::Node(Rc::new(MapNode::merge(shift + BITS, leaf.clone(), hash, key, val))), true)
+                    },
+                    MapEntry::Node(ref node) => {
+                        let (node, added) = node.insert(shift + BITS, hash, key, val);
+                        (MapEntry::Node(Rc::new(node)), added)
+                    },
+                };
+                entries[idx] = entry;
+                (MapNode::Bitmap(bitmap, entries), added)
+            },
+            MapNode::Collision(h, ref pairs) if h == hash => {
+                let mut pairs = pairs.clone();
+                match pairs.iter().position(|p| equal_keys(&p.0, &key)) {
+                    Some(i) => { pairs[i].1 = val; (MapNode::Collision(h, pairs), false) },
+                    None => { pairs.push((key, val)); (MapNode::Collision(h, pairs), true) },
+                }
+            },
+            MapNode::Collision(h, ref pairs) => {
+                // a different hash ends here, push the collision node down a level
+                let coll = MapEntry::Node(Rc::new(MapNode::Collision(h, pairs.clone())));
+                let node = MapNode::Bitmap(1 << ((h >> shift) & MASK), vec![coll]);
+                node.insert(shift, hash, key, val)
+            },
+        }
+    }
+
+    // returns the new node, or None if the key wasn't found
+    fn remove(&self, shift: u32, hash: u64, key: &Value) -> Option<MapNode>
+    {
+        match *self {
+            MapNode::Bitmap(bitmap, ref entries) => {
+                let bit = 1 << ((hash >> shift) & MASK);
+                if bitmap & bit == 0 { return None }
+                let idx = (bitmap & (bit - 1)).count_ones() as usize;
+                let mut entries = entries.clone();
+                match entries[idx] {
+                    MapEntry::Leaf(_, ref k, _) if equal_keys(k, key) => {},
+                    MapEntry::Leaf(..) => return None,
+                    MapEntry::Node(ref node) => {
+                        if let Some(entry) = node.remove(shift + BITS, hash, key)?.into_entry()
+                        {
+                            entries[idx] = entry;
+                            return Some(MapNode::Bitmap(bitmap, entries))
+                        }
+                    },
+                }
+                entries.remove(idx);
+                Some(MapNode::Bitmap(bitmap & !bit, entries))
+            },
+            MapNode::Collision(h, ref pairs) => {
+                let i = pairs.iter().position(|p| equal_keys(&p.0, key))?;
+                let mut pairs = pairs.clone();
+                pairs.remove(i);
+                Some(MapNode::Collision(h, pairs))
+            },
+        }
+    }
+
+    // the entry standing for this node in its parent after a removal, None if it's empty. A node left with a
+    // single leaf is replaced by the leaf, so lookups don't go through chains of nodes or one-key collisions
+    fn into_entry(self) -> Option<MapEntry>
+    {
+        match self {
+            MapNode::Bitmap(_, ref entries) if entries.is_empty() => None,
+            MapNode::Bitmap(_, ref entries) if entries.len() == 1 && matches!(entries[0], MapEntry::Leaf(..)) => {
+                Some(entries[0].clone())
+            },
+            MapNode::Collision(_, ref pairs) if pairs.is_empty() => None,
+            MapNode::Collision(h, mut pairs) if pairs.len() == 1 => {
+                let (k, v) = pairs.pop().unwrap();
+                Some(MapEntry::Leaf(h, k, v))
+            },
+            node => Some(MapEntry::Node(Rc::new(node))),
+        }
+    }
+
+    fn collect<'a>(&'a self, out: &mut Vec<(&'a Value, &'a Value)>)
+    {
+        match *self {
+            MapNode::Bitmap(_, ref entries) => for entry in entries
+            {
+                match *entry {
+                    MapEntry::Leaf(_, ref k, ref v) => out.push((k, v)),
+                    MapEntry::Node(ref node) => node.collect(out),
+                }
+            },
+            MapNode::Collision(_, ref pairs) => out.extend(pairs.iter().map(|p| (&p.0, &p.1))),
+        }
+    }
+}
+
+// an immutable hash map stored as a hash array mapped trie, keys compare like `equal` hash tables
+#[derive(Debug, Clone)]
+pub struct PMap
+{
+    len: usize,
+    root: Rc<MapNode>,
+}
+
+impl PartialEq for PMap
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        self.len == other.len && self.entries().iter().all(|&(k, v)| other.get(k).is_some_and(|w| is_equal(v, w)))
+    }
+}
+
+impl Default for PMap
+{
+    fn default() -> Self
+    {
+        PMap::new()
+    }
+}
+
+impl PMap
+{
+    pub fn new() -> PMap
+    {
+        PMap{ len: 0, root: Rc::new(MapNode::Bitmap(0, Vec::new())) }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value>
+    {
+        self.root.get(0, equal_hash(key), key)
+    }
+
+    // a copy with `key` set to `val`
+    pub fn insert(&self, key: Value, val: Value) -> PMap
+    {
+        let (root, added) = self.root.insert(0, equal_hash(&key), key, val);
+        PMap{ len: self.len + added as usize, root: Rc::new(root) }
+    }
+
+    // a copy without `key`
+    pub fn remove(&self, key: &Value) -> PMap
+    {
+        match self.root.remove(0, equal_hash(key), key) {
+            Some(root) => PMap{ len: self.len - 1, root: Rc::new(root) },
+            None => self.clone(),
+        }
+    }
+
+    // identifies the trie, for `eq` comparisons
+    pub fn root_ptr(&self) -> *const ()
+    {
+        Rc::as_ptr(&self.root) as *const ()
+    }
+
+    // the entries in hash order
+    pub fn entries(&self) -> Vec<(&Value, &Value)>
+    {
+        let mut out = Vec::with_capacity(self.len);
+        self.root.collect(&mut out);
+        out
+    }
+}
+
+pub fn load_persistent(env: &mut Scope)
+{
+    // named apart from the association list `assoc`
+    env.set_builtin("passoc", true, |mut args, _| {
+        let mut coll = check_arg!(args, 3, 0);
+        while !args.is_empty()
+        {
+            let key = check_arg!(args, 3, 1);
+            let val = check_arg!(args, 3, 2);
+            coll = match coll {
+                Value::PMap(map) => Value::PMap(map.insert(key, val)),
+                Value::PVector(vec) => {
+                    let i = check_bound(map_value!(key, Number, |n| n)?, vec.len())?;
+                    Value::PVector(if i == vec.len() { vec.push(val) } else { vec.set(i, val) })
+                },
+                other => return Err(InvalidArgType(COLLECTION, other.type_name())),
+            };
+        }
+        Ok(coll)
+    });
+
+    env.set_builtin("dissoc", true, |mut args, _| {
+        let map = check_arg!(args, PMap, 2, 0);
+        Ok(Value::PMap(args.iter().fold(map, |map, key| map.remove(key))))
+    });
+
+    env.set_builtin("conj", true, |mut args, _| {
+        let coll = check_arg!(args, 2, 0);
+        match coll {
+            Value::PVector(vec) => Ok(Value::PVector(args.into_iter().fold(vec, |vec, val| vec.push(val)))),
+            Value::PMap(mut map) => {
+                for entry in args
+                {
+                    // entries are (key . value) pairs or [key value] vectors
+                    map = match entry {
+                        Value::List(List::Node(ref cons)) => map.insert(cons.car.borrow().clone(), cons.cdr.borrow().clone()),
+                        Value::PVector(ref pair) if pair.len() == 2 => map.insert(pair.get(0).unwrap().clone(), pair.get(1).unwrap().clone()),
+                        other => return Err(InvalidArgType("Pair", other.type_name())),
+                    };
+                }
+                Ok(Value::PMap(map))
+            },
+            other => Err(InvalidArgType(COLLECTION, other.type_name())),
+        }
+    });
+
+    env.set_builtin("get", true, |mut args, _| {
+        let coll = check_arg!(args, 2, 0);
+        let key = check_arg!(args, 2, 1);
+        let found = match coll {
+            Value::PMap(ref map) => map.get(&key).cloned(),
+            // an index past the end gives the default, like a missing key
+            Value::PVector(ref vec) => match check_index(map_value!(key, Number, |n| n)?, vec.len()) {
+                Ok(i) => vec.get(i).cloned(),
+                Err(IndexOutOfRange(..)) => None,
+                Err(e) => return Err(e),
+            },
+            other => return Err(InvalidArgType(COLLECTION, other.type_name())),
+        };
+        Ok(found.or_else(|| args.pop_front()).unwrap_or(Value::Nil))
+    });
+
+    env.set_builtin("update", true, |mut args, env| {
+        let coll = check_arg!(args, 3, 0);
+        let key = check_arg!(args, 3, 1);
+        let func = check_function!(args, 3, 2);
+        match coll {
+            Value::PMap(map) => {
+                let old = map.get(&key).cloned().unwrap_or(Value::Nil);
+                let new = func.call(&old.wrap(), env, false)?;
+                Ok(Value::PMap(map.insert(key, new)))
+            },
+            Value::PVector(vec) => {
+                let i = check_bound(map_value!(key, Number, |n| n)?, vec.len())?;
+                let old = vec.get(i).cloned().unwrap_or(Value::Nil);
+                let new = func.call(&old.wrap(), env, false)?;
+                Ok(Value::PVector(if i == vec.len() { vec.push(new) } else { vec.set(i, new) }))
+            },
+            other => Err(InvalidArgType(COLLECTION, other.type_name())),
+        }
+    });
+
+    env.set_builtin("count", true, |mut args, _| {
+        let len = match check_arg!(args, 1, 0) {
+            Value::PMap(map) => map.len(),
+            Value::PVector(vec) => vec.len(),
+            other => return Err(InvalidArgType(COLLECTION, other.type_name())),
+        };
+        Ok(Value::Number(len as f64))
+    });
+
+    env.set_builtin("pvector->list", true, |mut args, _| {
+        let vec = check_arg!(args, PVector, 1, 0);
+        Ok(Value::List(vec.iter().cloned().collect()))
+    });
+
+    env.set_builtin("pmap->alist", true, |mut args, _| {
+        let map = check_arg!(args, PMap, 1, 0);
+        let pairs = map.entries().into_iter().map(|(k, v)| Value::List(List::pair(k.clone(), v.clone()))).collect();
+        Ok(Value::List(pairs))
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use scope::run;
+
+    #[test]
+    fn names_both_collections()
+    {
+        for code in &["(get '(1) 0)", "(count 1)", "(conj \"a\" 1)", "(update 1 0 car)", "(passoc 1 0 0)"]
+        {
+            match run(code) {
+                Err(InvalidArgType(expected, _)) => assert_eq!(expected, COLLECTION, "{}", code),
+                other => panic!("{}: {:?}", code, other.map_err(|e| e.to_string())),
+            }
+        }
+    }
+
+    fn nums(range: ::std::ops::Range<usize>) -> PVector
+    {
+        range.map(|i| Value::Number(i as f64)).collect()
+    }
+
+    fn check(code: &str, expected: &str)
+    {
+        match run(code) {
+            Ok(val) => assert_eq!(val.to_string(), expected, "{}", code),
+            Err(e) => panic!("{}: {}", code, e),
+        }
+    }
+
+    #[test]
+    fn vectors_grow_past_one_leaf()
+    {
+        // one leaf, two levels, and the first three level trie after 1024 elements
+        for &len in &[31, 32, 33, 64, 65, 1024, 1025, 1100]
+        {
+            let vec = nums(0..len);
+            assert_eq!(vec.len(), len);
+            assert!((0..len).all(|i| vec.get(i) == Some(&Value::Number(i as f64))), "{}", len);
+            assert_eq!(vec.get(len), None);
+            let vec = vec.set(len - 1, Value::Nil);
+            assert_eq!(vec.get(len - 1), Some(&Value::Nil));
+            assert_eq!(vec.iter().count(), len);
+        }
+        let elems: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        check(&format!("(let v [{}]) (list (count v) (get v 39) (get (conj v 40) 40))", elems.join(" ")), "(40 39 40)");
+    }
+
+    #[test]
+    fn old_versions_stay_the_same()
+    {
+        let old = nums(0..1024);
+        let (pushed, set) = (old.push(Value::Nil), old.set(1000, Value::Nil));
+        assert_eq!(old, nums(0..1024));
+        assert_eq!((pushed.len(), set.len()), (1025, 1024));
+        assert_eq!(set.get(1000), Some(&Value::Nil));
+
+        check("(let a [1 2]) (let b (conj a 3)) (let c (passoc a 0 9)) (let d (update a 1 (lambda (x) (* x 10)))) \
+               (list a b c d)", "([1 2] [1 2 3] [9 2] [1 20])");
+        check("(let m {'a 1}) (let n (passoc m 'b 2)) (let o (dissoc n 'a)) (let p (update m 'a (lambda (x) (+ x 1)))) \
+               (list m (count n) (get n 'b) o p)", "({a 1} 2 2 {b 2} {a 2})");
+    }
+
+    #[test]
+    fn gets_with_whole_number_indexes()
+    {
+        check("(list (get [1 2] 1) (get [1 2] 2) (get [1 2] 5 'none) (get {'a 1} 'b 'none))", "(2 nil none none)");
+        assert!(matches!(run("(get [1 2] 0.5)"), Err(InvalidIndex(_))));
+        assert!(matches!(run("(get [1 2] -1)"), Err(InvalidIndex(_))));
+        assert!(matches!(run("(get [1 2] 'a)"), Err(InvalidArgType("Number", _))));
+    }
+
+    // keys that differ only past the elements that are hashed, so they all have the same hash
+    fn colliding(i: usize) -> Value
+    {
+        run(&format!("'(0 1 2 3 4 5 6 7 8 {})", i)).unwrap()
+    }
+
+    // true if no collision node is left with fewer than two keys
+    fn collisions_are_full(node: &MapNode) -> bool
+    {
+        match *node {
+            MapNode::Bitmap(_, ref entries) => entries.iter().all(|entry| match *entry {
+                MapEntry::Node(ref node) => collisions_are_full(node),
+                MapEntry::Leaf(..) => true,
+            }),
+            MapNode::Collision(_, ref pairs) => pairs.len() > 1,
+        }
+    }
+
+    #[test]
+    fn maps_keep_colliding_keys()
+    {
+        let mut map = PMap::new();
+        for i in 0..5
+        {
+            map = map.insert(colliding(i), Value::Number(i as f64));
+        }
+        // other keys land next to the collision node and push it down
+        for i in 0..500
+        {
+            map = map.insert(Value::Number(i as f64), Value::Nil);
+        }
+        assert_eq!(map.len(), 505);
+        assert_eq!(map.entries().len(), 505);
+        assert!((0..5).all(|i| map.get(&colliding(i)) == Some(&Value::Number(i as f64))));
+        assert_eq!(map.insert(colliding(2), Value::Nil).len(), 505);
+
+        let full = map.clone();
+        for i in 0..5
+        {
+            map = map.remove(&colliding(i));
+            assert_eq!(map.len(), 504 - i);
+            assert_eq!(map.get(&colliding(i)), None);
+            assert!((i + 1..5).all(|j| map.get(&colliding(j)) == Some(&Value::Number(j as f64))));
+            assert!(collisions_are_full(&map.root), "after removing {}", i);
+        }
+        assert_eq!(map.remove(&colliding(0)).len(), 500);
+        assert!((0..5).all(|i| full.get(&colliding(i)).is_some()));
+
+        for i in 0..500
+        {
+            map = map.remove(&Value::Number(i as f64));
+        }
+        assert!(map.is_empty());
+        assert!(matches!(*map.root, MapNode::Bitmap(0, ref entries) if entries.is_empty()));
+    }
+}
@@ -0,0 +1,483 @@
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+use data::{Value, List, Function, ParseError, RuntimeError};
+use data::RuntimeError::*;
+use reader::Reader;
+use scope::{Scope, RcScope};
+use vfs::{Vfs, OsFs};
+
+enum Stream
+{
+    Input(Reader<Box<dyn BufRead>>),
+    Output(Box<dyn Write>),
+    Buffer(String),     // an output port that keeps the text in memory
+    Closed,
+}
+
+// a stream of chars that data can be read from or written to
+pub struct Port
+{
+    pub name: String,
+    output: bool,
+    stream: RefCell<Stream>,
+}
+
+impl PartialEq for Port
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Port
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{} port {}", self.kind(), self.name)
+    }
+}
+
+impl Port
+{
+    pub fn input<S: Into<String>>(name: S, input: Box<dyn BufRead>) -> Port
+    {
+        Port{ name: name.into(), output: false, stream: RefCell::new(Stream::Input(Reader::new(input))) }
+    }
+
+    pub fn output<S: Into<String>>(name: S, output: Box<dyn Write>) -> Port
+    {
+        Port{ name: name.into(), output: true, stream: RefCell::new(Stream::Output(output)) }
+    }
+
+    // an input port that reads from a string
+    pub fn string<S: Into<String>>(name: S, text: String) -> Port
+    {
+        Port::input(name, Box::new(Cursor::new(text.into_bytes())))
+    }
+
+    // an output port that collects what's written to it
+    pub fn buffer<S: Into<String>>(name: S) -> Port
+    {
+        Port{ name: name.into(), output: true, stream: RefCell::new(Stream::Buffer(String::new())) }
+    }
+
+    pub fn is_output(&self) -> bool
+    {
+        self.output
+    }
+
+    pub fn kind(&self) -> &'static str
+    {
+        if self.output { "output" } else { "input" }
+    }
+
+    // the text written to a buffer port so far
+    pub fn contents(&self) -> Option<String>
+    {
+        match *self.stream.borrow() {
+            Stream::Buffer(ref text) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    fn io_error(&self, e: io::Error) -> RuntimeError
+    {
+        IoError(self.name.clone(), e.to_string())
+    }
+
+    fn with_reader<T, F>(&self, f: F) -> Result<T, RuntimeError>
+        where F: FnOnce(&mut Reader<Box<dyn BufRead>>) -> Result<T, RuntimeError>
+    {
+        match *self.stream.borrow_mut() {
+            Stream::Input(ref mut reader) => f(reader),
+            Stream::Closed => Err(PortClosed(self.name.clone())),
+            _ => Err(WrongPortKind(self.name.clone(), "an input port")),
+        }
+    }
+
+    // the next datum, or the eof object at the end
+    pub fn read(&self) -> Result<Value, RuntimeError>
+    {
+        self.with_reader(|reader| match reader.read() {
+            Ok(val) => Ok(val),
+            Err(ParseError::EndOfStream) => Ok(Value::Eof),
+            Err(ParseError::Io(e)) => Err(IoError(self.name.clone(), e)),
+            Err(e) => Err(Syntax(e)),
+        })
+    }
+
+    pub fn read_char(&self) -> Result<Value, RuntimeError>
+    {
+        self.with_reader(|reader| reader.read_char().map(|chr| chr.map_or(Value::Eof, Value::Char)).map_err(|e| self.io_error(e)))
+    }
+
+    pub fn peek_char(&self) -> Result<Value, RuntimeError>
+    {
+        self.with_reader(|reader| reader.peek_char().map(|chr| chr.map_or(Value::Eof, Value::Char)).map_err(|e| self.io_error(e)))
+    }
+
+    pub fn read_line(&self) -> Result<Value, RuntimeError>
+    {
+        self.with_reader(|reader| match reader.read_line() {
+            Ok(Some(line)) => Ok(Value::String(Rc::new(line))),
+            Ok(None) => Ok(Value::Eof),
+            Err(e) => Err(self.io_error(e)),
+        })
+    }
+
+    pub fn write_str(&self, s: &str) -> Result<(), RuntimeError>
+    {
+        match *self.stream.borrow_mut() {
+            Stream::Output(ref mut out) => out.write_all(s.as_bytes()).map_err(|e| self.io_error(e)),
+            Stream::Buffer(ref mut text) => { text.push_str(s); Ok(()) },
+            Stream::Closed => Err(PortClosed(self.name.clone())),
+            Stream::Input(_) => Err(WrongPortKind(self.name.clone(), "an output port")),
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), RuntimeError>
+    {
+        match *self.stream.borrow_mut() {
+            Stream::Output(ref mut out) => out.flush().map_err(|e| self.io_error(e)),
+            _ => Ok(()),
+        }
+    }
+
+    // flushes an output port and releases its stream, closing it again does nothing
+    pub fn close(&self) -> Result<(), RuntimeError>
+    {
+        match ::std::mem::replace(&mut *self.stream.borrow_mut(), Stream::Closed) {
+            Stream::Output(mut out) => out.flush().map_err(|e| self.io_error(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// the ports used when none is given, shared by all the scopes of an interpreter
+pub struct StdPorts
+{
+    pub input: Rc<Port>,
+    pub output: RefCell<Rc<Port>>,
+    pub error: RefCell<Rc<Port>>,
+    opened: RefCell<Vec<Weak<Port>>>,   // the file ports opened by scripts
+}
+
+impl StdPorts
+{
+    pub fn new() -> StdPorts
+    {
+        // a one byte buffer, so what isn't read yet is still there for the REPL
+        let stdin = Port::input("stdin", Box::new(BufReader::with_capacity(1, io::stdin())));
+        StdPorts{
+            input: Rc::new(stdin),
+            output: RefCell::new(Rc::new(Port::output("stdout", Box::new(io::stdout())))),
+            error: RefCell::new(Rc::new(Port::output("stderr", Box::new(io::stderr())))),
+            opened: RefCell::new(Vec::new()),
+        }
+    }
+
+    // keeps a port opened by a script, so it can be closed when the script exits
+    pub fn track(&self, port: &Rc<Port>)
+    {
+        let mut opened = self.opened.borrow_mut();
+        opened.retain(|p| p.strong_count() > 0);
+        opened.push(Rc::downgrade(port));
+    }
+
+    // closes the ports opened by scripts and flushes the current ones. All are tried, the first error is
+    // returned
+    pub fn close_all(&self) -> Result<(), RuntimeError>
+    {
+        let opened: Vec<_> = self.opened.borrow_mut().drain(..).filter_map(|p| p.upgrade()).collect();
+        let current = [self.output.borrow().clone(), self.error.borrow().clone()];
+        let mut first = Ok(());
+        for res in opened.iter().map(|p| p.close()).chain(current.iter().map(|p| p.flush()))
+        {
+            if first.is_ok() { first = res }
+        }
+        first
+    }
+}
+
+impl Default for StdPorts
+{
+    fn default() -> StdPorts
+    {
+        StdPorts::new()
+    }
+}
+
+pub fn current_output(env: &RcScope) -> Rc<Port>
+{
+    env.borrow().ports().output.borrow().clone()
+}
+
+pub fn current_error(env: &RcScope) -> Rc<Port>
+{
+    env.borrow().ports().error.borrow().clone()
+}
+
+// runs `f` with the current output and error ports going to memory, for embedders that check what a script
+// prints. Returns the result of `f`, and the text written to each port
+pub fn capture_output<T, F>(env: &RcScope, f: F) -> (T, String, String)
+    where F: FnOnce() -> T
+{
+    let (output, error) = (Rc::new(Port::buffer("output")), Rc::new(Port::buffer("error")));
+    let res = {
+        let _redirect = Redirect::new(env.borrow().ports(), output.clone(), error.clone());
+        f()
+    };
+    (res, output.contents().unwrap_or_default(), error.contents().unwrap_or_default())
+}
+
+// sends the current output and error ports elsewhere, and puts the previous ones back when dropped, also when
+// unwinding from a panic
+struct Redirect
+{
+    ports: Rc<StdPorts>,
+    output: Rc<Port>,
+    error: Rc<Port>,
+}
+
+impl Redirect
+{
+    fn new(ports: Rc<StdPorts>, output: Rc<Port>, error: Rc<Port>) -> Redirect
+    {
+        let output = ports.output.replace(output);
+        let error = ports.error.replace(error);
+        Redirect{ ports, output, error }
+    }
+}
+
+impl Drop for Redirect
+{
+    fn drop(&mut self)
+    {
+        *self.ports.output.borrow_mut() = self.output.clone();
+        *self.ports.error.borrow_mut() = self.error.clone();
+    }
+}
+
+// the optional port argument of an input builtin
+fn input_port(args: &mut VecDeque<Value>, env: &RcScope) -> Result<Rc<Port>, RuntimeError>
+{
+    match args.pop_front() {
+        Some(Value::Port(port)) => Ok(port),
+        Some(other) => Err(InvalidArgType("Port", other.type_name())),
+        None => Ok(env.borrow().ports().input.clone()),
+    }
+}
+
+// the optional port argument of an output builtin, the current output port if there's none
+pub fn output_port(args: &mut VecDeque<Value>, env: &RcScope) -> Result<Rc<Port>, RuntimeError>
+{
+    match args.pop_front() {
+        Some(Value::Port(port)) => Ok(port),
+        Some(other) => Err(InvalidArgType("Port", other.type_name())),
+        None => Ok(current_output(env)),
+    }
+}
+
+fn string_list(names: Vec<String>) -> Value
+{
+    Value::List(names.into_iter().map(|name| Value::String(Rc::new(name))).collect())
+}
+
+// the file builtins, that only access the files through `vfs`
+pub fn load_files(env: &mut Scope, vfs: Rc<dyn Vfs>)
+{
+    let fs = vfs.clone();
+    env.set_builtin("open-input-file", true, move |mut args, env| {
+        let path = check_arg!(args, String, 1, 0);
+        let input = fs.open_read(&path).map_err(|e| IoError((*path).clone(), e.to_string()))?;
+        let port = Rc::new(Port::input((*path).clone(), input));
+        env.borrow().ports().track(&port);
+        Ok(Value::Port(port))
+    });
+
+    let fs = vfs.clone();
+    env.set_builtin("open-output-file", true, move |mut args, env| {
+        let path = check_arg!(args, String, 1, 0);
+        let output = fs.open_write(&path).map_err(|e| IoError((*path).clone(), e.to_string()))?;
+        let port = Rc::new(Port::output((*path).clone(), output));
+        env.borrow().ports().track(&port);
+        Ok(Value::Port(port))
+    });
+
+    // (call-with-output-file path proc) calls proc with a port to the file, and closes it after
+    let fs = vfs.clone();
+    env.set_builtin("call-with-output-file", true, move |mut args, env| {
+        let path = check_arg!(args, String, 2, 0);
+        let func = check_function!(args, 2, 1);
+        let output = fs.open_write(&path).map_err(|e| IoError((*path).clone(), e.to_string()))?;
+        let port = Rc::new(Port::output((*path).clone(), output));
+        env.borrow().ports().track(&port);
+        // an error of `proc` is reported before one closing the file
+        let res = func.call(&Value::Port(port.clone()).wrap(), env, false);
+        let closed = port.close();
+        let val = res?;
+        closed?;
+        Ok(val)
+    });
+
+    let fs = vfs.clone();
+    env.set_builtin("file-exists?", true, move |mut args, _| {
+        let path = check_arg!(args, String, 1, 0);
+        fs.exists(&path).map(Value::Bool).map_err(|e| IoError((*path).clone(), e.to_string()))
+    });
+
+    let fs = vfs.clone();
+    env.set_builtin("delete-file", true, move |mut args, _| {
+        let path = check_arg!(args, String, 1, 0);
+        fs.delete(&path).map(|_| Value::Nil).map_err(|e| IoError((*path).clone(), e.to_string()))
+    });
+
+    env.set_builtin("directory-list", true, move |mut args, _| {
+        let path = check_arg!(args, String, 1, 0);
+        vfs.list_dir(&path).map(string_list).map_err(|e| IoError((*path).clone(), e.to_string()))
+    });
+}
+
+pub fn load_ports(env: &mut Scope)
+{
+    load_files(env, Rc::new(OsFs));
+
+    env.set_builtin("current-input-port", true, |_, env| Ok(Value::Port(env.borrow().ports().input.clone())));
+
+    env.set_builtin("current-output-port", true, |_, env| Ok(Value::Port(current_output(&env))));
+
+    env.set_builtin("current-error-port", true, |_, env| Ok(Value::Port(current_error(&env))));
+
+    // (read [port])
+    env.set_builtin("read", true, |mut args, env| input_port(&mut args, &env)?.read());
+
+    env.set_builtin("read-char", true, |mut args, env| input_port(&mut args, &env)?.read_char());
+
+    env.set_builtin("peek-char", true, |mut args, env| input_port(&mut args, &env)?.peek_char());
+
+    env.set_builtin("read-line", true, |mut args, env| input_port(&mut args, &env)?.read_line());
+
+    env.set_builtin("close-port", true, |mut args, _| {
+        check_arg!(args, Port, 1, 0).close()?;
+        Ok(Value::Nil)
+    });
+
+    // calls a function with the current output port going to a string, and returns the string
+    env.set_builtin("with-output-to-string", true, |mut args, env| {
+        let func = check_function!(args, 1, 0);
+        let ports = env.borrow().ports();
+        let port = Rc::new(Port::buffer("string"));
+        let error = ports.error.borrow().clone();
+        let redirect = Redirect::new(ports, port.clone(), error);
+        let res = func.call(&List::End, env, false);
+        drop(redirect);
+        res?;
+        Ok(Value::String(Rc::new(port.contents().unwrap_or_default())))
+    });
+
+    env.set_builtin("open-input-string", true, |mut args, _| {
+        let text = check_arg!(args, String, 1, 0);
+        Ok(Value::Port(Rc::new(Port::string("string", (*text).clone()))))
+    });
+
+    env.set_builtin("open-output-string", true, |_, _| Ok(Value::Port(Rc::new(Port::buffer("string")))));
+
+    // the text written so far to a port from `open-output-string`
+    env.set_builtin("get-output-string", true, |mut args, _| {
+        let port = check_arg!(args, Port, 1, 0);
+        match port.contents() {
+            Some(text) => Ok(Value::String(Rc::new(text))),
+            None => Err(WrongPortKind(port.name.clone(), "a string output port")),
+        }
+    });
+
+    env.set_builtin("input-port?", true, |mut args, _| {
+        Ok(Value::Bool(matches!(check_arg!(args, 1, 0), Value::Port(ref p) if !p.is_output())))
+    });
+
+    env.set_builtin("output-port?", true, |mut args, _| {
+        Ok(Value::Bool(matches!(check_arg!(args, 1, 0), Value::Port(ref p) if p.is_output())))
+    });
+
+    env.set_builtin("eof-object", true, |_, _| Ok(Value::Eof));
+
+    env.set_builtin("eof-object?", true, |mut args, _| {
+        Ok(Value::Bool(matches!(check_arg!(args, 1, 0), Value::Eof)))
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use scope::{run, run_in};
+
+    fn check(code: &str, expected: &str)
+    {
+        match run(code) {
+            Ok(val) => assert_eq!(val.to_string(), expected, "{}", code),
+            Err(e) => panic!("{}: {}", code, e),
+        }
+    }
+
+    // writes to /dev/full fail when they are flushed, that is when the port is closed
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reports_the_error_of_proc_first()
+    {
+        let failing = run("(call-with-output-file \"/dev/full\" (lambda (p) (display \"x\" p) (car 1)))");
+        assert!(matches!(failing, Err(InvalidArgType(..))));
+        let ok = run("(call-with-output-file \"/dev/full\" (lambda (p) (display \"x\" p)))");
+        assert!(matches!(ok, Err(IoError(..))));
+    }
+
+    #[test]
+    fn reads_from_strings()
+    {
+        check("(let p (open-input-string \"ab\ncd\")) \
+               (list (read-char p) (peek-char p) (read-char p) (read-line p) (read-line p) (read-line p) (read-char p))",
+              "(#\\a #\\b #\\b \"\" \"cd\" #<eof> #<eof>)");
+        check("(let p (open-input-string \"(1 2) x\")) (list (read p) (read p) (eof-object? (read p)))", "((1 2) x #t)");
+        check("(let p (open-input-string \"\")) (list (input-port? p) (output-port? p))", "(#t #f)");
+    }
+
+    #[test]
+    fn writes_to_strings()
+    {
+        check("(let p (open-output-string)) (write \"a\" p) (let first (get-output-string p)) (display \" b\" p) \
+               (list first (get-output-string p) (output-port? p))", "(\"\\\"a\\\"\" \"\\\"a\\\" b\" #t)");
+        check("(with-output-to-string (lambda () (display 1) (write \"x\")))", "\"1\\\"x\\\"\"");
+        assert!(matches!(run("(get-output-string (open-input-string \"a\"))"), Err(WrongPortKind(..))));
+    }
+
+    #[test]
+    fn captures_the_output_and_restores_the_ports()
+    {
+        let env = Scope::global().wrap();
+        env.borrow_mut().load_stdlib();
+        let ports = env.borrow().ports();
+        let (output, error) = (ports.output.borrow().clone(), ports.error.borrow().clone());
+        let restored = || Rc::ptr_eq(&ports.output.borrow(), &output) && Rc::ptr_eq(&ports.error.borrow(), &error);
+
+        let (res, out, err) = capture_output(&env, || run_in("(display 1) (write \"e\" (current-error-port)) 2", &env));
+        assert_eq!((res.unwrap(), &out[..], &err[..]), (Value::Number(2.0), "1", "\"e\""));
+        assert!(restored());
+
+        let (res, out, _) = capture_output(&env, || run_in("(display 1) (car)", &env));
+        assert!(res.is_err());
+        assert_eq!(out, "1");
+        assert!(restored());
+
+        assert!(run_in("(with-output-to-string (lambda () (car)))", &env).is_err());
+        assert!(restored());
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| capture_output(&env, || panic!("in the capture"))));
+        assert!(res.is_err());
+        assert!(restored());
+    }
+}
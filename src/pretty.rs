@@ -0,0 +1,340 @@
+use std::rc::Rc;
+use std::collections::{HashSet, VecDeque};
+use data::{Value, List, RuntimeError};
+use data::RuntimeError::*;
+use hashtable::HashKind;
+use scope::Scope;
+use port::current_output;
+
+pub const DEFAULT_WIDTH: usize = 80;
+
+// a layout document, the lines of a group are all broken or all kept flat
+pub enum Doc
+{
+    Text(String),
+    Line,                   // a space when flat, a newline and the indentation when broken
+    HardLine,               // always a newline, so the groups around it are broken
+    BreakParent,            // prints nothing, but the groups around it are broken
+    Suffix(String),         // printed at the end of the line, like a trailing comment
+    Nest(usize, Box<Doc>),  // indents the broken lines inside by some more columns
+    Align(Box<Doc>),        // indents the broken lines inside up to the current column
+    Concat(Vec<Doc>),
+    Group(Box<Doc>),
+}
+
+pub fn text<S: Into<String>>(s: S) -> Doc
+{
+    Doc::Text(s.into())
+}
+
+pub fn group(docs: Vec<Doc>) -> Doc
+{
+    Doc::Group(Box::new(Doc::Concat(docs)))
+}
+
+// the docs with a line between each
+pub fn lines(docs: Vec<Doc>) -> Vec<Doc>
+{
+    let mut res = Vec::with_capacity(docs.len() * 2);
+    for doc in docs
+    {
+        if !res.is_empty() { res.push(Doc::Line) }
+        res.push(doc);
+    }
+    res
+}
+
+// how the elements of a list are laid out when it doesn't fit in one line
+enum Style
+{
+    Data,           // aligned under the first element
+    Call,           // aligned under the first argument
+    Body(usize),    // a number of arguments next to the head, then the rest indented by 2
+}
+
+fn form_style(head: &str) -> Style
+{
+    match head {
+        // the condition of an `if` stays next to it, the branches go under it
+        "lambda" | "let" | "if" | "module" | "define-record-type" | "defgeneric" => Style::Body(1),
+        "defclass" | "defmethod" => Style::Body(2),
+        "begin" => Style::Body(0),
+        _ => Style::Call,
+    }
+}
+
+// builds the doc of a value, or None if it contains a cycle
+struct Builder
+{
+    path: HashSet<*const ()>,
+}
+
+impl Builder
+{
+    // builds the doc of a container, guarding against cycles through it
+    fn nested<F>(&mut self, ptr: *const (), f: F) -> Option<Doc>
+        where F: FnOnce(&mut Builder) -> Option<Doc>
+    {
+        if !self.path.insert(ptr) { return None }
+        let doc = f(self);
+        self.path.remove(&ptr);
+        doc
+    }
+
+    fn elems<'a, I>(&mut self, vals: I) -> Option<Vec<Doc>>
+        where I: IntoIterator<Item=&'a Value>
+    {
+        vals.into_iter().map(|val| self.build(val)).collect()
+    }
+
+    fn build(&mut self, val: &Value) -> Option<Doc>
+    {
+        match *val {
+            Value::List(List::Node(ref cons)) => {
+                let ptr = Rc::as_ptr(cons) as *const ();
+                self.nested(ptr, |b| b.build_list(val))
+            },
+            Value::Vector(ref vec) => self.nested(Rc::as_ptr(vec) as *const (), |b| {
+                let docs = b.elems(vec.borrow().iter())?;
+                Some(seq("#(", docs, ")"))
+            }),
+            Value::PVector(ref vec) => self.nested(vec.root_ptr(), |b| {
+                let docs = b.elems(vec.iter())?;
+                Some(seq("[", docs, "]"))
+            }),
+            Value::PMap(ref map) => self.nested(map.root_ptr(), |b| {
+                let docs = map.entries().into_iter()
+                    .map(|(k, v)| Some(group(vec![b.build(k)?, Doc::Line, b.build(v)?])))
+                    .collect::<Option<_>>()?;
+                Some(seq("{", docs, "}"))
+            }),
+            Value::HashTable(ref table) => self.nested(Rc::as_ptr(table) as *const (), |b| {
+                let table = table.borrow();
+                let docs = table.entries().iter()
+                    .map(|(k, v)| Some(group(vec![text("("), b.build(k)?, text(" ."), Doc::Line, b.build(v)?, text(")")])))
+                    .collect::<Option<_>>()?;
+                Some(seq(if table.kind() == HashKind::Eq { "#hasheq(" } else { "#hash(" }, docs, ")"))
+            }),
+            Value::Record(ref rec) => self.nested(Rc::as_ptr(rec) as *const (), |b| {
+                let fields = rec.fields.borrow();
+                let docs = rec.rtype.fields.iter().zip(fields.iter())
+                    .map(|(name, val)| Some(group(vec![text(format!("{}:", name)), Doc::Line, b.build(val)?])))
+                    .collect::<Option<Vec<_>>>()?;
+                if docs.is_empty() { return Some(text(format!("#<{}>", rec.rtype.name))) }
+                Some(seq(&format!("#<{} ", rec.rtype.name), docs, ">"))
+            }),
+            ref other => Some(text(other.to_string())),
+        }
+    }
+
+    fn build_list(&mut self, val: &Value) -> Option<Doc>
+    {
+        let lst = match *val {
+            Value::List(ref lst) => lst.clone(),
+            _ => unreachable!(),
+        };
+
+        // split the elements from the tail of a dotted list, stopping at cycles along the cdrs
+        let mut elems = Vec::new();
+        let mut tail = None;
+        let mut seen = HashSet::new();
+        let mut cur = lst;
+        while let List::Node(cons) = cur
+        {
+            if !seen.insert(Rc::as_ptr(&cons)) { return None }
+            elems.push(cons.car.borrow().clone());
+            cur = match *cons.cdr.borrow() {
+                Value::List(ref next) => next.clone(),
+                ref other => { tail = Some(other.clone()); List::End },
+            };
+        }
+
+        let mut docs = self.elems(elems.iter())?;
+        if let Some(ref tail) = tail
+        {
+            docs.push(group(vec![text("."), Doc::Line, self.build(tail)?]));
+        }
+
+        let head = match elems[0] {
+            Value::Symbol(ref name) => Some(&name[..]),
+            _ => None,
+        };
+        Some(list_doc(head, docs))
+    }
+}
+
+// the doc of a parenthesized list, laid out according to its head symbol
+pub fn list_doc(head: Option<&str>, docs: Vec<Doc>) -> Doc
+{
+    let style = match head {
+        Some(name) if docs.len() > 1 => form_style(name),
+        _ => Style::Data,
+    };
+    match style {
+        Style::Data => seq("(", docs, ")"),
+        Style::Call => {
+            let mut docs = docs.into_iter();
+            let head = docs.next().unwrap();
+            let args = Doc::Align(Box::new(Doc::Concat(lines(docs.collect()))));
+            group(vec![text("("), head, text(" "), args, text(")")])
+        },
+        Style::Body(n) => {
+            let mut docs = docs.into_iter();
+            let mut first = vec![text("("), docs.next().unwrap()];
+            for doc in docs.by_ref().take(n)
+            {
+                first.push(text(" "));
+                first.push(doc);
+            }
+            let mut body = Vec::new();
+            for doc in docs
+            {
+                body.push(Doc::Line);
+                body.push(doc);
+            }
+            first.push(Doc::Nest(2, Box::new(Doc::Concat(body))));
+            first.push(text(")"));
+            Doc::Align(Box::new(group(first)))
+        },
+    }
+}
+
+// a sequence of elements between delimiters, aligned after the opening one
+pub fn seq(open: &str, docs: Vec<Doc>, close: &str) -> Doc
+{
+    let inner = Doc::Align(Box::new(Doc::Concat(lines(docs))));
+    group(vec![text(open), inner, text(close)])
+}
+
+// (indent, flat, doc) items left to lay out, the last one goes first
+type Stack<'a> = Vec<(usize, bool, &'a Doc)>;
+
+// true if the text up to the next broken line fits in `width` columns
+fn fits(mut width: isize, doc: &Doc, rest: &Stack) -> bool
+{
+    let mut stack: Vec<(bool, &Doc)> = vec![(true, doc)];
+    let mut rest = rest.iter().rev();
+    while width >= 0
+    {
+        let (flat, doc) = match stack.pop() {
+            Some(item) => item,
+            None => match rest.next() {
+                Some(&(_, flat, doc)) => (flat, doc),
+                None => return true,
+            },
+        };
+        match *doc {
+            Doc::Text(ref s) => width -= s.chars().count() as isize,
+            Doc::Line if flat => width -= 1,
+            Doc::Line => return true,
+            Doc::HardLine | Doc::BreakParent if flat => return false,
+            Doc::HardLine => return true,
+            Doc::BreakParent | Doc::Suffix(_) => {},
+            Doc::Nest(_, ref doc) | Doc::Align(ref doc) | Doc::Group(ref doc) => stack.push((flat, doc)),
+            Doc::Concat(ref docs) => stack.extend(docs.iter().rev().map(|doc| (flat, doc))),
+        }
+    }
+    false
+}
+
+pub fn layout(doc: &Doc, width: usize) -> String
+{
+    let mut out = String::new();
+    let mut col = 0;
+    let mut suffix = String::new();
+    let mut stack: Stack = vec![(0, false, doc)];
+    while let Some((indent, flat, doc)) = stack.pop()
+    {
+        match *doc {
+            Doc::Text(ref s) => {
+                out.push_str(s);
+                col += s.chars().count();
+            },
+            Doc::Line if flat => {
+                out.push(' ');
+                col += 1;
+            },
+            Doc::Line | Doc::HardLine => {
+                // no trailing spaces on blank lines
+                let len = out.trim_end_matches(' ').len();
+                out.truncate(len);
+                out.push_str(&suffix);
+                suffix.clear();
+                out.push('\n');
+                out.extend((0..indent).map(|_| ' '));
+                col = indent;
+            },
+            Doc::BreakParent => {},
+            Doc::Suffix(ref s) => {
+                suffix.push(' ');
+                suffix.push_str(s);
+            },
+            Doc::Nest(n, ref doc) => stack.push((indent + n, flat, doc)),
+            Doc::Align(ref doc) => stack.push((col, flat, doc)),
+            Doc::Concat(ref docs) => stack.extend(docs.iter().rev().map(|doc| (indent, flat, doc))),
+            Doc::Group(ref doc) => {
+                let flat = flat || fits(width as isize - col as isize, doc, &stack);
+                stack.push((indent, flat, doc));
+            },
+        }
+    }
+    out.push_str(&suffix);
+    out
+}
+
+// the `write` form of a value, broken in lines and indented to fit in `width` columns where possible
+pub fn pretty(val: &Value, width: usize) -> String
+{
+    match (Builder{ path: HashSet::new() }).build(val) {
+        Some(doc) => layout(&doc, width),
+        None => val.to_string(),    // cycles are printed with labels in one line
+    }
+}
+
+fn check_width(mut args: VecDeque<Value>) -> Result<usize, RuntimeError>
+{
+    match args.pop_front() {
+        Some(Value::Number(n)) if n >= 1.0 && n.fract() == 0.0 => Ok(n as usize),
+        Some(Value::Number(n)) => Err(InvalidIndex(n)),
+        Some(other) => Err(InvalidArgType("Number", other.type_name())),
+        None => Ok(DEFAULT_WIDTH),
+    }
+}
+
+pub fn load_pretty(env: &mut Scope)
+{
+    // (pp value [width])
+    env.set_builtin("pp", true, |mut args, env| {
+        let val = check_arg!(args, 1, 0);
+        let text = pretty(&val, check_width(args)?) + "\n";
+        current_output(&env).write_str(&text)?;
+        Ok(Value::Nil)
+    });
+
+    env.set_builtin("pp->string", true, |mut args, _| {
+        let val = check_arg!(args, 1, 0);
+        Ok(Value::String(Rc::new(pretty(&val, check_width(args)?))))
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use parser::Parser;
+
+    fn pp(code: &str, width: usize) -> String
+    {
+        pretty(&Parser::new(code).parse().unwrap()[0], width)
+    }
+
+    #[test]
+    fn breaks_if_after_the_condition()
+    {
+        let code = "(if (> (vector-length items) 10) (display \"many\") (display \"few\"))";
+        assert_eq!(pp(code, 80), code);
+        assert_eq!(pp(code, 40), "(if (> (vector-length items) 10)\n  (display \"many\")\n  (display \"few\"))");
+        let nested = "(lambda (x) (if (null? x) nil (begin (display x) (newline))))";
+        assert_eq!(pp(nested, 30), "(lambda (x)\n  (if (null? x)\n    nil\n    (begin\n      (display x)\n      (newline))))");
+    }
+}
@@ -0,0 +1,306 @@
+use std::io::{self, BufRead};
+use std::str;
+use data::{Value, ParseError};
+use parser::Parser;
+
+// the length of the UTF-8 sequence that starts with a byte
+fn utf8_len(byte: u8) -> Option<usize>
+{
+    match byte {
+        0x00..=0x7f => Some(1),
+        0xc2..=0xdf => Some(2),
+        0xe0..=0xef => Some(3),
+        0xf0..=0xf4 => Some(4),
+        _ => None,
+    }
+}
+
+fn invalid_utf8() -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+}
+
+// the same separators as the tokenizer
+fn is_separator(chr: char) -> bool
+{
+    matches!(chr, ' ' | '(' | ')' | '[' | ']' | '{' | '}' | ';' | '\n' | '\t' | '\r')
+}
+
+// reads chars and data from a byte stream as they're needed, instead of the whole text up front
+pub struct Reader<R>
+{
+    input: R,
+    peeked: Option<char>,
+}
+
+impl<R: BufRead> Reader<R>
+{
+    pub fn new(input: R) -> Reader<R>
+    {
+        Reader{ input, peeked: None }
+    }
+
+    // decodes the next char, its bytes may be split between two buffer fills
+    fn decode(&mut self) -> io::Result<Option<char>>
+    {
+        let mut bytes = [0; 4];
+        let (mut len, mut need) = (0, 1);
+        while len < need
+        {
+            let buf = match self.input.fill_buf() {
+                Ok(buf) => buf,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if buf.is_empty()
+            {
+                return if len == 0 { Ok(None) } else { Err(invalid_utf8()) }
+            }
+            if len == 0
+            {
+                need = utf8_len(buf[0]).ok_or_else(invalid_utf8)?;
+            }
+            let n = (need - len).min(buf.len());
+            bytes[len..len + n].copy_from_slice(&buf[..n]);
+            self.input.consume(n);
+            len += n;
+        }
+        match str::from_utf8(&bytes[..len]) {
+            Ok(s) => Ok(s.chars().next()),
+            Err(_) => Err(invalid_utf8()),
+        }
+    }
+
+    pub fn peek_char(&mut self) -> io::Result<Option<char>>
+    {
+        if self.peeked.is_none()
+        {
+            self.peeked = self.decode()?;
+        }
+        Ok(self.peeked)
+    }
+
+    pub fn read_char(&mut self) -> io::Result<Option<char>>
+    {
+        match self.peeked.take() {
+            Some(chr) => Ok(Some(chr)),
+            None => self.decode(),
+        }
+    }
+
+    // reads the text up to the end of the line, without the line ending. None at the end of the stream
+    pub fn read_line(&mut self) -> io::Result<Option<String>>
+    {
+        let mut line = match self.read_char()? {
+            Some('\n') => return Ok(Some(String::new())),
+            Some(chr) => chr.to_string(),
+            None => return Ok(None),
+        };
+        while let Some(chr) = self.read_char()?
+        {
+            if chr == '\n' { break }
+            line.push(chr);
+        }
+        if line.ends_with('\r') { line.pop(); }
+        Ok(Some(line))
+    }
+
+    // skips whitespace and comments, returns true if there was any
+    fn skip_space(&mut self) -> io::Result<bool>
+    {
+        let mut skipped = false;
+        loop
+        {
+            match self.peek_char()? {
+                Some(' ') | Some('\t') | Some('\r') | Some('\n') => { self.read_char()?; },
+                Some(';') => while let Some(chr) = self.read_char()?
+                {
+                    if chr == '\n' { break }
+                },
+                _ => return Ok(skipped),
+            }
+            skipped = true;
+        }
+    }
+
+    // copies the chars up to the next separator
+    fn take_atom(&mut self, buf: &mut String) -> io::Result<()>
+    {
+        while let Some(chr) = self.peek_char()?
+        {
+            if is_separator(chr) { break }
+            buf.push(chr);
+            self.read_char()?;
+        }
+        Ok(())
+    }
+
+    // copies the rest of a string after the opening ", the tokenizer will find the errors in it
+    fn take_string(&mut self, buf: &mut String, escapes: bool) -> io::Result<()>
+    {
+        while let Some(chr) = self.read_char()?
+        {
+            buf.push(chr);
+            match chr {
+                '"' => break,
+                '\\' if escapes => if let Some(chr) = self.read_char()? { buf.push(chr) },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    // copies the elements of a sequence after the opening delimiter, up to the closing one
+    fn take_seq(&mut self, buf: &mut String) -> io::Result<()>
+    {
+        loop
+        {
+            if self.skip_space()? { buf.push(' ') }
+            match self.peek_char()? {
+                None => return Ok(()),
+                Some(chr @ ')') | Some(chr @ ']') | Some(chr @ '}') => {
+                    buf.push(chr);
+                    self.read_char()?;
+                    return Ok(())
+                },
+                _ => { self.take_datum(buf)?; },
+            }
+        }
+    }
+
+    // copies the text of the next datum without its comments, returns false at the end of the stream
+    fn take_datum(&mut self, buf: &mut String) -> io::Result<bool>
+    {
+        self.skip_space()?;
+        let chr = match self.read_char()? {
+            Some(chr) => chr,
+            None => return Ok(false),
+        };
+        let start = buf.len();
+        buf.push(chr);
+        match chr {
+            '\'' => { self.take_datum(buf)?; },
+            '(' | '[' | '{' => self.take_seq(buf)?,
+            '"' => self.take_string(buf, true)?,
+            ')' | ']' | '}' => {},
+            '#' => match self.peek_char()? {
+                Some('(') => {
+                    buf.push('(');
+                    self.read_char()?;
+                    self.take_seq(buf)?
+                },
+                Some('\\') => {
+                    // the first char of a literal is taken even if it's a separator
+                    buf.push('\\');
+                    self.read_char()?;
+                    if let Some(chr) = self.read_char()? { buf.push(chr) }
+                    self.take_atom(buf)?
+                },
+                Some('r') => {
+                    buf.push('r');
+                    self.read_char()?;
+                    if self.peek_char()? == Some('"')
+                    {
+                        buf.push('"');
+                        self.read_char()?;
+                        self.take_string(buf, false)?
+                    }
+                    else
+                    {
+                        self.take_atom(buf)?
+                    }
+                },
+                _ => {
+                    self.take_atom(buf)?;
+                    let prefix = &buf[start..] == "#hash" || &buf[start..] == "#hasheq";
+                    if prefix && self.peek_char()? == Some('(')
+                    {
+                        buf.push('(');
+                        self.read_char()?;
+                        self.take_seq(buf)?
+                    }
+                },
+            },
+            _ => self.take_atom(buf)?,
+        }
+        Ok(true)
+    }
+
+    // reads one datum, or fails with `EndOfStream` when there are no more
+    pub fn read(&mut self) -> Result<Value, ParseError>
+    {
+        let mut buf = String::new();
+        match self.take_datum(&mut buf) {
+            Ok(true) => Parser::new(&buf).parse_value(),
+            Ok(false) => Err(ParseError::EndOfStream),
+            Err(e) => Err(ParseError::Io(e.to_string())),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R>
+{
+    type Item = Result<Value, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        match self.read() {
+            Err(ParseError::EndOfStream) => None,
+            res => Some(res),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{BufReader, Read};
+
+    // a reader whose buffer holds a single byte, so every multi-byte char is split between fills
+    fn tiny(bytes: &[u8]) -> Reader<BufReader<&[u8]>>
+    {
+        Reader::new(BufReader::with_capacity(1, bytes))
+    }
+
+    #[test]
+    fn decodes_chars_split_between_fills()
+    {
+        let text = "aé€😀\nλ line\r\nend";
+        let mut reader = tiny(text.as_bytes());
+        assert_eq!(reader.peek_char().unwrap(), Some('a'));
+        let chars: Vec<_> = (0..5).map(|_| reader.read_char().unwrap().unwrap()).collect();
+        assert_eq!(chars, ['a', 'é', '€', '😀', '\n']);
+        assert_eq!(reader.read_line().unwrap(), Some("λ line".to_string()));
+        assert_eq!(reader.read_line().unwrap(), Some("end".to_string()));
+        assert_eq!(reader.read_line().unwrap(), None);
+    }
+
+    #[test]
+    fn reads_data_split_between_fills()
+    {
+        let text = "(λ \"naïve 😀\" #\\€ #\\λ) ; ça\n[é] {ü \"日本\"} 'ß #r\"𝄞\"";
+        let vals: Vec<_> = tiny(text.as_bytes()).collect::<Result<_, _>>().unwrap();
+        assert_eq!(format!("{:?}", vals), format!("{:?}", Parser::new(text).parse().unwrap()));
+    }
+
+    #[test]
+    fn takes_only_the_bytes_of_one_datum()
+    {
+        let mut input = BufReader::with_capacity(1, "(é) λ rest".as_bytes());
+        assert_eq!(Reader::new(&mut input).read().unwrap().to_string(), "(é)");
+        let mut rest = String::new();
+        input.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, " λ rest");
+    }
+
+    #[test]
+    fn rejects_cut_chars()
+    {
+        // the first two bytes of "€", then the end of the stream
+        let mut reader = tiny(&[b'a', 0xe2, 0x82]);
+        assert_eq!(reader.read_char().unwrap(), Some('a'));
+        assert_eq!(reader.read_char().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(tiny(&[b'"', 0xff, b'"']).read(), Err(ParseError::Io(_))));
+    }
+}
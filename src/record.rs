@@ -0,0 +1,191 @@
+use std::fmt;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use data::{Value, RuntimeError};
+use data::RuntimeError::*;
+use builtins::builtin_value;
+use equality::records_equal;
+use scope::Scope;
+
+// a type made by `define-record-type` or `defclass`, records of different types are never equal
+#[derive(Debug)]
+pub struct RecordType
+{
+    pub name: String,
+    pub fields: Vec<String>,
+    pub defaults: Vec<Value>,               // the initial value of each field
+    pub ancestors: Vec<Rc<RecordType>>,     // the superclasses, from the most specific one
+}
+
+pub struct Record
+{
+    pub rtype: Rc<RecordType>,
+    pub fields: RefCell<Vec<Value>>,
+}
+
+impl PartialEq for RecordType
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl RecordType
+{
+    // true if the records of this type are also of type `other`
+    pub fn is_a(&self, other: &Rc<RecordType>) -> bool
+    {
+        std::ptr::eq(self, &**other) || self.ancestors.iter().any(|t| Rc::ptr_eq(t, other))
+    }
+
+    pub fn field_index(&self, name: &str) -> Option<usize>
+    {
+        self.fields.iter().position(|n| n == name)
+    }
+}
+
+impl Record
+{
+    pub fn new(rtype: Rc<RecordType>) -> Record
+    {
+        let fields = RefCell::new(rtype.defaults.clone());
+        Record{ rtype, fields }
+    }
+}
+
+impl PartialEq for Record
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        records_equal(self, other)
+    }
+}
+
+// each field is written on its own, so a record that contains itself shows up as a label
+impl fmt::Debug for Record
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "Record(#<{}", self.rtype.name)?;
+        for (name, val) in self.rtype.fields.iter().zip(self.fields.borrow().iter())
+        {
+            write!(f, " {}: {}", name, val)?;
+        }
+        write!(f, ">)")
+    }
+}
+
+// the name `typeof` returns, which is the type name for records
+pub fn type_label(val: &Value) -> String
+{
+    match *val {
+        Value::Record(ref rec) => rec.rtype.name.clone(),
+        ref other => other.type_name().to_string(),
+    }
+}
+
+// the record argument of an accessor or modifier, which must be of type `rtype`
+fn check_record(args: &mut VecDeque<Value>, rtype: &Rc<RecordType>, num: u32) -> Result<Rc<Record>, RuntimeError>
+{
+    match args.pop_front() {
+        Some(Value::Record(ref rec)) if rec.rtype.is_a(rtype) => Ok(rec.clone()),
+        Some(other) => Err(WrongRecordType(rtype.name.clone(), type_label(&other))),
+        None => Err(InvalidArgNum(num, 0)),
+    }
+}
+
+fn symbol(val: &Value, what: &str) -> Result<String, RuntimeError>
+{
+    match *val {
+        Value::Symbol(ref s) => Ok((**s).clone()),
+        ref other => Err(InvalidDefinition("define-record-type", format!("expected a symbol for the {}, but found {}", what, other))),
+    }
+}
+
+fn symbol_list(val: Value, what: &str) -> Result<Vec<String>, RuntimeError>
+{
+    match val {
+        Value::List(ref lst) => lst.iter().map(|val| symbol(&val, what)).collect(),
+        other => Err(InvalidDefinition("define-record-type", format!("expected a list for the {}, but found {}", what, other))),
+    }
+}
+
+pub fn load_records(env: &mut Scope)
+{
+    // (define-record-type name (constructor field...) predicate (field accessor [modifier])...)
+    env.set_builtin("define-record-type", false, |mut args, env| {
+        let name = symbol(&check_arg!(args, 3, 0), "type name")?;
+        let ctor = symbol_list(check_arg!(args, 3, 1), "constructor")?;
+        let pred = symbol(&check_arg!(args, 3, 2), "predicate")?;
+        let specs = args.into_iter().map(|spec| symbol_list(spec, "field")).collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(spec) = specs.iter().find(|spec| spec.len() < 2 || spec.len() > 3)
+        {
+            return Err(InvalidDefinition("define-record-type", format!("field ({}) must have an accessor and an optional modifier", spec.join(" "))))
+        }
+        let fields: Vec<String> = specs.iter().map(|spec| spec[0].clone()).collect();
+        let (ctor_name, ctor_fields) = match ctor.split_first() {
+            Some((name, args)) => (name.clone(), args.to_vec()),
+            None => return Err(InvalidDefinition("define-record-type", "the constructor needs a name".to_string())),
+        };
+        // the constructor sets the fields it names, in its order, and the others start as nil
+        let slots = ctor_fields.iter().map(|f| fields.iter().position(|n| n == f)
+            .ok_or_else(|| InvalidDefinition("define-record-type", format!("the constructor sets {}, which isn't a field", f))))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // the conventional <name> is shown without the brackets
+        let type_name = match name.strip_prefix('<').and_then(|n| n.strip_suffix('>')) {
+            Some(inner) if !inner.is_empty() => inner.to_string(),
+            _ => name.clone(),
+        };
+        let defaults = vec![Value::Nil; fields.len()];
+        let rtype = Rc::new(RecordType{ name: type_name, fields, defaults, ancestors: Vec::new() });
+        let mut env = env.borrow_mut();
+
+        let rt = rtype.clone();
+        let num = slots.len() as u32;
+        env.decl(&ctor_name, builtin_value(ctor_name.clone(), move |args, _| {
+            if args.len() != slots.len() { return Err(InvalidArgNum(num, args.len() as u32)) }
+            let rec = Record::new(rt.clone());
+            for (&slot, val) in slots.iter().zip(args)
+            {
+                rec.fields.borrow_mut()[slot] = val;
+            }
+            Ok(Value::Record(Rc::new(rec)))
+        }));
+
+        let rt = rtype.clone();
+        env.decl(&pred, builtin_value(pred.clone(), move |mut args, _| {
+            Ok(Value::Bool(matches!(check_arg!(args, 1, 0), Value::Record(ref rec) if rec.rtype.is_a(&rt))))
+        }));
+
+        for (i, spec) in specs.iter().enumerate()
+        {
+            let rt = rtype.clone();
+            env.decl(&spec[1], builtin_value(spec[1].clone(), move |mut args, _| {
+                let rec = check_record(&mut args, &rt, 1)?;
+                let val = rec.fields.borrow()[i].clone();
+                Ok(val)
+            }));
+            if let Some(modifier) = spec.get(2)
+            {
+                let rt = rtype.clone();
+                env.decl(modifier, builtin_value(modifier.clone(), move |mut args, _| {
+                    let rec = check_record(&mut args, &rt, 2)?;
+                    rec.fields.borrow_mut()[i] = check_arg!(args, 2, 1);
+                    Ok(Value::Nil)
+                }));
+            }
+        }
+
+        let val = Value::RecordType(rtype);
+        env.decl(&name, val.clone());
+        Ok(val)
+    });
+
+    env.set_builtin("record?", true, |mut args, _| {
+        Ok(Value::Bool(matches!(check_arg!(args, 1, 0), Value::Record(_))))
+    });
+}
@@ -1,8 +1,19 @@
 use std::rc::Rc;
 use std::collections::{HashMap, VecDeque};
 use std::cell::RefCell;
-use data::{Value, RuntimeError};
+use data::{Value, List, RuntimeError};
 use builtins::{BuiltinFn, load_builtins};
+use equality::load_equality;
+use loader::{load_loader, load_sources};
+use module::load_modules;
+use hashtable::load_hashtable;
+use persistent::load_persistent;
+use strings::load_strings;
+use pretty::load_pretty;
+use port::{StdPorts, load_ports, load_files};
+use vfs::Vfs;
+use record::load_records;
+use object::load_objects;
 
 pub type RcScope = Rc<RefCell<Scope>>;
 
@@ -10,18 +21,42 @@ pub struct Scope
 {
     dict: HashMap<String, Value>,
     parent: Option<RcScope>,
+    ports: Rc<StdPorts>,
+    owns_new: bool,     // `set` of an unbound name declares it here instead of in the root scope
 }
 
 impl Scope
 {
     pub fn local(env: RcScope) -> Scope
     {
-        Scope{ dict: HashMap::new(), parent: Some(env) }
+        let ports = env.borrow().ports();
+        Scope{ dict: HashMap::new(), parent: Some(env), ports, owns_new: false }
+    }
+
+    // the scope of a module body, which keeps the names it sets instead of adding them to `env`
+    pub fn module(env: RcScope) -> Scope
+    {
+        Scope{ owns_new: true, ..Scope::local(env) }
     }
 
     pub fn global() -> Scope
     {
-        Scope{ dict: HashMap::new(), parent: None }
+        Scope{ dict: HashMap::new(), parent: None, ports: Rc::new(StdPorts::new()), owns_new: false }
+    }
+
+    // the default ports of the interpreter
+    pub fn ports(&self) -> Rc<StdPorts>
+    {
+        self.ports.clone()
+    }
+
+    // the outermost scope of the chain `env` belongs to
+    pub fn root(env: &RcScope) -> RcScope
+    {
+        match env.borrow().parent {
+            Some(ref p) => Scope::root(p),
+            None => env.clone(),
+        }
     }
 
     pub fn wrap(self) -> RcScope
@@ -37,6 +72,17 @@ impl Scope
         }
     }
 
+    // all the names visible from this scope
+    pub fn names(&self) -> Vec<String>
+    {
+        let mut names: Vec<_> = self.dict.keys().cloned().collect();
+        if let Some(ref p) = self.parent
+        {
+            names.extend(p.borrow().names());
+        }
+        names
+    }
+
     pub fn set(&mut self, key: &str, val: Value)
     {
         if let Some(entry) = self.dict.get_mut(key)
@@ -45,8 +91,8 @@ impl Scope
             return
         }
         match self.parent {
-            Some(ref p) => p.borrow_mut().set(key, val),
-            None => self.decl(key, val),
+            Some(ref p) if !self.owns_new || p.borrow().get(key).is_some() => p.borrow_mut().set(key, val),
+            _ => self.decl(key, val),
         }
     }
 
@@ -58,7 +104,22 @@ impl Scope
     pub fn set_builtin<F>(&mut self, key: &'static str, do_eval: bool, val: F)
         where F: Fn(VecDeque<Value>, RcScope) -> Result<Value, RuntimeError> + 'static
     {
-        self.set(key, Value::Builtin(Rc::new(BuiltinFn{ name: key, do_eval: do_eval, func: Box::new(val) })))
+        self.set(key, Value::Builtin(Rc::new(BuiltinFn{ name: key.into(), do_eval, func: Box::new(val) })))
+    }
+
+    // the arguments returned by `command-line`, starting with the script name
+    pub fn set_command_line(&mut self, args: Vec<String>)
+    {
+        let lst: List = args.into_iter().map(|arg| Value::String(Rc::new(arg))).collect();
+        self.set_builtin("command-line", true, move |_, _| Ok(Value::List(lst.clone())));
+    }
+
+    // makes the file builtins, `load` and `require` go through `vfs`, to restrict or redirect what
+    // scripts can access. The files loaded before are forgotten
+    pub fn set_vfs(&mut self, vfs: Rc<dyn Vfs>)
+    {
+        load_files(self, vfs.clone());
+        load_sources(self, vfs);
     }
 
     pub fn load_stdlib(&mut self)
@@ -67,5 +128,36 @@ impl Scope
         self.set("#t", Value::Bool(true));
         self.set("#f", Value::Bool(false));
         load_builtins(self);
+        load_equality(self);
+        load_loader(self);
+        load_modules(self);
+        load_hashtable(self);
+        load_persistent(self);
+        load_strings(self);
+        load_pretty(self);
+        load_ports(self);
+        load_records(self);
+        load_objects(self);
+    }
+}
+
+// evaluates the forms of `code` in `env` and returns the value of the last one, for the tests
+#[cfg(test)]
+pub fn run_in(code: &str, env: &RcScope) -> Result<Value, RuntimeError>
+{
+    let mut last = Value::Nil;
+    for val in ::parser::Parser::new(code).parse().unwrap()
+    {
+        last = val.eval(env.clone())?;
     }
+    Ok(last)
+}
+
+// evaluates `code` in a new global scope with the standard library
+#[cfg(test)]
+pub fn run(code: &str) -> Result<Value, RuntimeError>
+{
+    let env = Scope::global().wrap();
+    env.borrow_mut().load_stdlib();
+    run_in(code, &env)
 }
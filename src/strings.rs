@@ -0,0 +1,372 @@
+use std::rc::Rc;
+use data::{Value, Function, RuntimeError};
+use data::RuntimeError::*;
+use scope::{Scope, RcScope};
+use builtins::{check_index, check_range};
+use lexer::parse_number;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Strings are indexed by grapheme clusters, what a reader sees as one character, so an index can't split
+// "e" + U+0301 or a flag in half. A cluster of a single char is a Char, a longer one is a String.
+
+// the byte offset of the grapheme at `idx`, or the length when it's past the end
+fn byte_offset(s: &str, idx: usize) -> usize
+{
+    s.grapheme_indices(true).nth(idx).map_or(s.len(), |(i, _)| i)
+}
+
+fn grapheme_count(s: &str) -> usize
+{
+    s.graphemes(true).count()
+}
+
+// a grapheme as a Char, or as a String when it's made of several chars
+fn grapheme_value(g: &str) -> Value
+{
+    let mut chars = g.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Value::Char(c),
+        _ => new_string(g.to_string()),
+    }
+}
+
+fn new_string(s: String) -> Value
+{
+    Value::String(Rc::new(s))
+}
+
+fn check_radix(n: f64) -> Result<u32, RuntimeError>
+{
+    if n.fract() != 0.0 || !(2.0..=36.0).contains(&n) { return Err(InvalidRadix(n)) }
+    Ok(n as u32)
+}
+
+fn format_radix(n: f64, radix: u32) -> Result<String, RuntimeError>
+{
+    if n.fract() != 0.0 || !n.is_finite() { return Err(NotAnInteger(n)) }
+    let mut num = n.abs();
+    let mut digits = Vec::new();
+    loop
+    {
+        digits.push(::std::char::from_digit((num % radix as f64) as u32, radix).unwrap());
+        num = (num / radix as f64).floor();
+        if num == 0.0 { break }
+    }
+    if n < 0.0 { digits.push('-') }
+    Ok(digits.into_iter().rev().collect())
+}
+
+fn parse_radix(s: &str, radix: u32) -> Option<f64>
+{
+    if radix == 10 { return parse_number(s) }
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    if digits.is_empty() { return None }
+    let num = digits.chars().try_fold(0.0, |acc, c| c.to_digit(radix).map(|d| acc * radix as f64 + d as f64))?;
+    Some(if neg { -num } else { num })
+}
+
+// a char or a string to search for
+enum Pattern
+{
+    Char(char),
+    Str(Rc<String>),
+}
+
+impl Pattern
+{
+    fn from_value(val: Value) -> Result<Pattern, RuntimeError>
+    {
+        match val {
+            Value::Char(c) => Ok(Pattern::Char(c)),
+            Value::String(s) => Ok(Pattern::Str(s)),
+            other => Err(InvalidArgType("String", other.type_name())),
+        }
+    }
+
+    fn split<'a>(&self, s: &'a str) -> Vec<&'a str>
+    {
+        match *self {
+            Pattern::Char(c) => s.split(c).collect(),
+            // an empty separator splits into graphemes
+            Pattern::Str(ref sep) if sep.is_empty() => s.graphemes(true).collect(),
+            Pattern::Str(ref sep) => s.split(&***sep).collect(),
+        }
+    }
+
+    // the grapheme index of the first match that doesn't start or end inside a grapheme
+    fn find(&self, s: &str) -> Option<usize>
+    {
+        let bounds: Vec<_> = s.grapheme_indices(true).map(|(i, _)| i).chain(Some(s.len())).collect();
+        let mut found: Box<dyn Iterator<Item=(usize, &str)>> = match *self {
+            Pattern::Char(c) => Box::new(s.match_indices(c)),
+            Pattern::Str(ref sub) => Box::new(s.match_indices(&***sub)),
+        };
+        found.find_map(|(b, m)| match (bounds.binary_search(&b), bounds.binary_search(&(b + m.len()))) {
+            (Ok(i), Ok(_)) => Some(i),
+            _ => None,
+        })
+    }
+}
+
+pub fn load_strings(env: &mut Scope)
+{
+    env.set_builtin("char->integer", true, |mut args, _| {
+        let chr = check_arg!(args, Char, 1, 0);
+        Ok(Value::Number(chr as u32 as f64))
+    });
+
+    env.set_builtin("integer->char", true, |mut args, _| {
+        let n = check_arg!(args, Number, 1, 0);
+        if n < 0.0 || n.fract() != 0.0 || n > u32::MAX as f64 { return Err(InvalidCharCode(n)) }
+        ::std::char::from_u32(n as u32).map(Value::Char).ok_or(InvalidCharCode(n))
+    });
+
+    env.set_builtin("char-upcase", true, |mut args, _| {
+        let chr = check_arg!(args, Char, 1, 0);
+        // keep the char when the upper case is more than one char, like 'ß'
+        let mut up = chr.to_uppercase();
+        Ok(Value::Char(if up.len() == 1 { up.next().unwrap() } else { chr }))
+    });
+
+    env.set_builtin("char-downcase", true, |mut args, _| {
+        let chr = check_arg!(args, Char, 1, 0);
+        let mut down = chr.to_lowercase();
+        Ok(Value::Char(if down.len() == 1 { down.next().unwrap() } else { chr }))
+    });
+
+    env.set_builtin("char-alphabetic?", true, |mut args, _| {
+        let chr = check_arg!(args, Char, 1, 0);
+        Ok(Value::Bool(chr.is_alphabetic()))
+    });
+
+    env.set_builtin("char-numeric?", true, |mut args, _| {
+        let chr = check_arg!(args, Char, 1, 0);
+        Ok(Value::Bool(chr.is_numeric()))
+    });
+
+    env.set_builtin("char-whitespace?", true, |mut args, _| {
+        let chr = check_arg!(args, Char, 1, 0);
+        Ok(Value::Bool(chr.is_whitespace()))
+    });
+
+    // indexes count graphemes, not chars or bytes
+    env.set_builtin("string-ref", true, |mut args, _| {
+        let s = check_arg!(args, String, 2, 0);
+        let i = check_index(check_arg!(args, Number, 2, 1), grapheme_count(&s))?;
+        Ok(grapheme_value(s.graphemes(true).nth(i).unwrap()))
+    });
+
+    env.set_builtin("string-length", true, |mut args, _| {
+        let s = check_arg!(args, String, 1, 0);
+        Ok(Value::Number(grapheme_count(&s) as f64))
+    });
+
+    // (substring s start [end])
+    env.set_builtin("substring", true, |mut args, _| {
+        let s = check_arg!(args, String, 2, 0);
+        let len = grapheme_count(&s);
+        let start = check_arg!(args, Number, 2, 1);
+        let end = match args.pop_front() {
+            Some(val) => map_value!(val, Number, |n| n)?,
+            None => len as f64,
+        };
+        let (start, end) = check_range(start, end, len)?;
+        Ok(new_string(s[byte_offset(&s, start)..byte_offset(&s, end)].to_string()))
+    });
+
+    env.set_builtin("string-append", true, |args, _| {
+        let mut res = String::new();
+        for val in args
+        {
+            res.push_str(&map_value!(val, String, |s| s)?);
+        }
+        Ok(new_string(res))
+    });
+
+    // splits on whitespace, or on every occurrence of a separator char or string
+    env.set_builtin("string-split", true, |mut args, _| {
+        let s = check_arg!(args, String, 1, 0);
+        let parts = match args.pop_front() {
+            Some(sep) => Pattern::from_value(sep)?.split(&s),
+            None => s.split_whitespace().collect(),
+        };
+        Ok(Value::List(parts.into_iter().map(|p| new_string(p.to_string())).collect()))
+    });
+
+    env.set_builtin("string-join", true, |mut args, _| {
+        let lst = check_arg!(args, List, 1, 0);
+        let sep = match args.pop_front() {
+            Some(val) => map_value!(val, String, |s| s)?,
+            None => Rc::new(String::new()),
+        };
+        if !lst.is_proper() { return Err(ImproperList) }
+        let parts = lst.iter().map(|val| map_value!(val, String, |s: Rc<String>| (*s).clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(new_string(parts.join(&sep)))
+    });
+
+    // the index of the first grapheme that is equal to a char or satisfies a predicate, or #f
+    env.set_builtin("string-index", true, |mut args, env| {
+        let s = check_arg!(args, String, 2, 0);
+        let idx = match check_arg!(args, 2, 1) {
+            Value::Char(c) => s.graphemes(true).position(|g| grapheme_value(g) == Value::Char(c)),
+            Value::Builtin(f) => find_char(&s, &*f, env)?,
+            Value::Lambda(f) => find_char(&s, &*f, env)?,
+            Value::Generic(f) => find_char(&s, &*f, env)?,
+            other => return Err(InvalidArgType("Function", other.type_name())),
+        };
+        Ok(idx.map_or(Value::Bool(false), |i| Value::Number(i as f64)))
+    });
+
+    fn find_char(s: &str, pred: &dyn Function, env: RcScope) -> Result<Option<usize>, RuntimeError>
+    {
+        for (i, g) in s.graphemes(true).enumerate()
+        {
+            let res = pred.call(&grapheme_value(g).wrap(), env.clone(), false)?;
+            if !matches!(res, Value::Nil | Value::Bool(false)) { return Ok(Some(i)) }
+        }
+        Ok(None)
+    }
+
+    // the index where a char or substring first appears, or #f
+    env.set_builtin("string-contains", true, |mut args, _| {
+        let s = check_arg!(args, String, 2, 0);
+        let pat = Pattern::from_value(check_arg!(args, 2, 1))?;
+        Ok(pat.find(&s).map_or(Value::Bool(false), |i| Value::Number(i as f64)))
+    });
+
+    // replaces every occurrence of a char or substring
+    env.set_builtin("string-replace", true, |mut args, _| {
+        let s = check_arg!(args, String, 3, 0);
+        let from = Pattern::from_value(check_arg!(args, 3, 1))?;
+        let to = check_arg!(args, String, 3, 2);
+        Ok(new_string(match from {
+            Pattern::Char(c) => s.replace(c, &to),
+            Pattern::Str(ref sub) if sub.is_empty() => (*s).clone(),
+            Pattern::Str(ref sub) => s.replace(&***sub, &to),
+        }))
+    });
+
+    env.set_builtin("string-trim", true, |mut args, _| {
+        let s = check_arg!(args, String, 1, 0);
+        Ok(new_string(s.trim().to_string()))
+    });
+
+    // the case mappings are the full Unicode ones, so the length can change ("ß" -> "SS")
+    env.set_builtin("string-upcase", true, |mut args, _| {
+        let s = check_arg!(args, String, 1, 0);
+        Ok(new_string(s.to_uppercase()))
+    });
+
+    env.set_builtin("string-downcase", true, |mut args, _| {
+        let s = check_arg!(args, String, 1, 0);
+        Ok(new_string(s.to_lowercase()))
+    });
+
+    // (string->number s [radix]), returns #f if the string isn't a number as the reader would read it
+    env.set_builtin("string->number", true, |mut args, _| {
+        let s = check_arg!(args, String, 1, 0);
+        let radix = match args.pop_front() {
+            Some(val) => check_radix(map_value!(val, Number, |n| n)?)?,
+            None => 10,
+        };
+        Ok(parse_radix(s.trim(), radix).map_or(Value::Bool(false), Value::Number))
+    });
+
+    // (number->string n [radix]), other radixes than 10 need an integer
+    env.set_builtin("number->string", true, |mut args, _| {
+        let n = check_arg!(args, Number, 1, 0);
+        let radix = match args.pop_front() {
+            Some(val) => check_radix(map_value!(val, Number, |n| n)?)?,
+            None => 10,
+        };
+        Ok(new_string(if radix == 10 { n.to_string() } else { format_radix(n, radix)? }))
+    });
+
+    env.set_builtin("string->symbol", true, |mut args, _| {
+        let s = check_arg!(args, String, 1, 0);
+        Ok(Value::Symbol(s))
+    });
+
+    env.set_builtin("symbol->string", true, |mut args, _| {
+        let s = check_arg!(args, Symbol, 1, 0);
+        Ok(Value::String(s))
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use scope::run;
+    use parser::Parser;
+    use data::ParseError;
+
+    fn text(val: Value) -> String
+    {
+        match val {
+            Value::String(s) => (*s).clone(),
+            other => panic!("not a string: {}", other),
+        }
+    }
+
+    #[test]
+    fn indexes_graphemes()
+    {
+        // "cafe" with a combining accent, then a flag made of two regional indicators
+        let s = "\"cafe\u{301}\u{1f1fa}\u{1f1f8}!\"";
+        assert_eq!(run(&format!("(string-length {})", s)).unwrap(), Value::Number(6.0));
+        assert_eq!(text(run(&format!("(substring {} 3 5)", s)).unwrap()), "e\u{301}\u{1f1fa}\u{1f1f8}");
+        assert_eq!(text(run(&format!("(string-ref {} 3)", s)).unwrap()), "e\u{301}");
+        assert_eq!(run(&format!("(string-ref {} 5)", s)).unwrap(), Value::Char('!'));
+        assert_eq!(run(&format!("(string-index {} #\\!)", s)).unwrap(), Value::Number(5.0));
+        assert_eq!(run(&format!("(string-contains {} \"!\")", s)).unwrap(), Value::Number(5.0));
+        // a match that ends inside a grapheme isn't one
+        assert_eq!(run(&format!("(string-contains {} #\\e)", s)).unwrap(), Value::Bool(false));
+        let parts = run(&format!("(string-join (string-split {} \"\") \" \")", s)).map(text).unwrap();
+        assert_eq!(parts, "c a f e\u{301} \u{1f1fa}\u{1f1f8} !");
+    }
+
+    #[test]
+    fn reads_only_number_syntax()
+    {
+        for good in &["12", "-1.5", "+3", ".5", "1.", "1e3", "2.5E-2", "+inf.0", "-inf.0", " 7 "]
+        {
+            assert!(matches!(run(&format!("(string->number \"{}\")", good)).unwrap(), Value::Number(_)), "{}", good);
+        }
+        for bad in &["nan", "inf", "-inf", "infinity", "+Infinity", "NaN", "", ".", "-", "1e", "1e+", "0x10", "1_000"]
+        {
+            assert_eq!(run(&format!("(string->number \"{}\")", bad)).unwrap(), Value::Bool(false), "{}", bad);
+        }
+        assert_eq!(run("(string->number \"ff\" 16)").unwrap(), Value::Number(255.0));
+    }
+
+    #[test]
+    fn names_chars()
+    {
+        for &(name, chr) in &[("space", ' '), ("newline", '\n'), ("tab", '\t'), ("nul", '\0'), ("x41", 'A'), ("a", 'a'), ("(", '(')]
+        {
+            assert_eq!(run(&format!("#\\{}", name)).unwrap(), Value::Char(chr), "{}", name);
+        }
+        assert_eq!(run("(list #\\space #\\newline #\\x41 #\\( #\\delete)").unwrap().to_string(),
+                   "(#\\space #\\newline #\\A #\\( #\\delete)");
+        assert_eq!(text(run("(with-output-to-string (lambda () (display (list #\\space #\\a #\\newline))))").unwrap()),
+                   "(  a \n)");
+        assert_eq!(run("(char->integer #\\space)").unwrap(), Value::Number(32.0));
+        assert!(matches!(Parser::new("#\\spaces").parse(), Err(ParseError::InvalidChar(_))));
+    }
+
+    #[test]
+    fn compares_chars()
+    {
+        assert_eq!(run("(list (< #\\a #\\b) (> #\\a #\\b) (<= #\\a #\\a) (>= #\\A #\\a) (< #\\Z #\\a))").unwrap().to_string(),
+                   "(#t #f #t #f #t)");
+        assert_eq!(run("(list (eq? #\\a #\\a) (eqv? #\\a #\\b) (equal? #\\x41 #\\A) (eq? #\\a \"a\"))").unwrap().to_string(),
+                   "(#t #f #t #f)");
+        assert!(matches!(run("(< #\\a 1)"), Err(InvalidComp("Char", "Number"))));
+        assert!(matches!(run("(< #\\a \"b\")"), Err(InvalidComp("Char", "String"))));
+    }
+}
@@ -1,6 +1,17 @@
 use std::rc::Rc;
+use std::cell::Cell;
 use data::{Value, List, RuntimeError};
 use scope::RcScope;
+use module::get_qualified;
+use persistent::PMap;
+
+thread_local!(static EVAL_STEPS: Cell<u64> = const { Cell::new(0) });
+
+// number of evaluation steps done so far on this thread
+pub fn eval_steps() -> u64
+{
+    EVAL_STEPS.with(|n| n.get())
+}
 
 impl Value
 {
@@ -11,9 +22,19 @@ impl Value
             Value::Bool(_) => "Bool",
             Value::Number(_) => "Number",
             Value::Symbol(_) => "Symbol",
+            Value::Char(_) => "Char",
             Value::String(_) => "String",
-            Value::Builtin(_) | Value::Lambda(_) => "Function",
+            Value::Builtin(_) | Value::Lambda(_) | Value::Generic(_) => "Function",
+            Value::Module(_) => "Module",
             Value::List(_) => "List",
+            Value::Vector(_) => "Vector",
+            Value::HashTable(_) => "HashTable",
+            Value::PVector(_) => "PVector",
+            Value::PMap(_) => "PMap",
+            Value::Port(_) => "Port",
+            Value::Eof => "Eof",
+            Value::Record(_) => "Record",
+            Value::RecordType(_) => "RecordType",
         }
     }
 
@@ -29,9 +50,25 @@ impl Value
 
     pub fn eval(&self, env: RcScope) -> Result<Value, RuntimeError>
     {
+        EVAL_STEPS.with(|n| n.set(n.get() + 1));
         match *self {
-            Value::Symbol(ref name) => env.borrow().get(name).ok_or_else(|| RuntimeError::UnkSymbol(name.clone())),
+            Value::Symbol(ref name) => {
+                let env = env.borrow();
+                env.get(name).map(Ok).or_else(|| get_qualified(&env, name))
+                    .unwrap_or_else(|| Err(RuntimeError::UnkSymbol(name.clone())))
+            },
             Value::List(ref lst) => lst.call(env),
+            // persistent collection literals evaluate their elements
+            Value::PVector(ref vec) => vec.iter().map(|val| val.eval(env.clone())).collect::<Result<_, _>>()
+                .map(Value::PVector),
+            Value::PMap(ref map) => {
+                let mut res = PMap::new();
+                for (key, val) in map.entries()
+                {
+                    res = res.insert(key.eval(env.clone())?, val.eval(env.clone())?);
+                }
+                Ok(Value::PMap(res))
+            },
             _ => Ok(self.clone()),
         }
     }
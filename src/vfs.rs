@@ -0,0 +1,195 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::fs;
+use std::path::{Path, PathBuf, Component};
+
+// the file system seen by the file and port builtins, so an embedder can restrict or redirect it
+pub trait Vfs
+{
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn BufRead>>;
+    fn open_write(&self, path: &str) -> io::Result<Box<dyn Write>>;
+    fn exists(&self, path: &str) -> io::Result<bool>;
+    fn is_file(&self, path: &str) -> io::Result<bool>;
+    // a name that is the same for all the paths to a file, used to tell if a file was already loaded
+    fn canonicalize(&self, path: &str) -> io::Result<PathBuf>;
+    fn delete(&self, path: &str) -> io::Result<()>;
+    // the names of the entries of a directory, sorted
+    fn list_dir(&self, path: &str) -> io::Result<Vec<String>>;
+}
+
+fn os_open_read(path: &Path) -> io::Result<Box<dyn BufRead>>
+{
+    Ok(Box::new(BufReader::new(fs::File::open(path)?)))
+}
+
+fn os_open_write(path: &Path) -> io::Result<Box<dyn Write>>
+{
+    Ok(Box::new(BufWriter::new(fs::File::create(path)?)))
+}
+
+fn os_list_dir(path: &Path) -> io::Result<Vec<String>>
+{
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path)?
+    {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(names)
+}
+
+// the real file system, relative paths start at the working directory
+pub struct OsFs;
+
+impl Vfs for OsFs
+{
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn BufRead>>
+    {
+        os_open_read(Path::new(path))
+    }
+
+    fn open_write(&self, path: &str) -> io::Result<Box<dyn Write>>
+    {
+        os_open_write(Path::new(path))
+    }
+
+    fn exists(&self, path: &str) -> io::Result<bool>
+    {
+        Ok(Path::new(path).exists())
+    }
+
+    fn is_file(&self, path: &str) -> io::Result<bool>
+    {
+        Ok(Path::new(path).is_file())
+    }
+
+    fn canonicalize(&self, path: &str) -> io::Result<PathBuf>
+    {
+        fs::canonicalize(path)
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()>
+    {
+        fs::remove_file(path)
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<String>>
+    {
+        os_list_dir(Path::new(path))
+    }
+}
+
+fn denied() -> io::Error
+{
+    io::Error::new(io::ErrorKind::PermissionDenied, "file access is disabled")
+}
+
+// no file access at all
+pub struct NoFs;
+
+impl Vfs for NoFs
+{
+    fn open_read(&self, _: &str) -> io::Result<Box<dyn BufRead>>
+    {
+        Err(denied())
+    }
+
+    fn open_write(&self, _: &str) -> io::Result<Box<dyn Write>>
+    {
+        Err(denied())
+    }
+
+    fn exists(&self, _: &str) -> io::Result<bool>
+    {
+        Err(denied())
+    }
+
+    fn is_file(&self, _: &str) -> io::Result<bool>
+    {
+        Err(denied())
+    }
+
+    fn canonicalize(&self, _: &str) -> io::Result<PathBuf>
+    {
+        Err(denied())
+    }
+
+    fn delete(&self, _: &str) -> io::Result<()>
+    {
+        Err(denied())
+    }
+
+    fn list_dir(&self, _: &str) -> io::Result<Vec<String>>
+    {
+        Err(denied())
+    }
+}
+
+// the files under a directory, which is the root of every path. Paths can't go above it with `..`,
+// but the symlinks inside it are followed
+pub struct RootedFs
+{
+    root: PathBuf,
+}
+
+impl RootedFs
+{
+    pub fn new<P: Into<PathBuf>>(root: P) -> RootedFs
+    {
+        RootedFs{ root: root.into() }
+    }
+
+    fn resolve(&self, path: &str) -> io::Result<PathBuf>
+    {
+        let mut parts = Vec::new();
+        for comp in Path::new(path).components()
+        {
+            match comp {
+                Component::Normal(part) => parts.push(part),
+                Component::ParentDir => if parts.pop().is_none()
+                {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, "path is outside the root"))
+                },
+                Component::CurDir | Component::RootDir | Component::Prefix(_) => {},
+            }
+        }
+        Ok(parts.into_iter().fold(self.root.clone(), |acc, part| acc.join(part)))
+    }
+}
+
+impl Vfs for RootedFs
+{
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn BufRead>>
+    {
+        os_open_read(&self.resolve(path)?)
+    }
+
+    fn open_write(&self, path: &str) -> io::Result<Box<dyn Write>>
+    {
+        os_open_write(&self.resolve(path)?)
+    }
+
+    fn exists(&self, path: &str) -> io::Result<bool>
+    {
+        Ok(self.resolve(path)?.exists())
+    }
+
+    fn is_file(&self, path: &str) -> io::Result<bool>
+    {
+        Ok(self.resolve(path)?.is_file())
+    }
+
+    fn canonicalize(&self, path: &str) -> io::Result<PathBuf>
+    {
+        fs::canonicalize(self.resolve(path)?)
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()>
+    {
+        fs::remove_file(self.resolve(path)?)
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<String>>
+    {
+        os_list_dir(&self.resolve(path)?)
+    }
+}